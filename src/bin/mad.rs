@@ -1,6 +1,18 @@
 use discord_bots::{discord, error};
 
 use clap::Parser;
+use futures::{
+    pin_mut,
+    future::{
+        Future,
+        FutureExt,
+    },
+};
+use notify::{
+    RecommendedWatcher,
+    RecursiveMode,
+    Watcher,
+};
 use regex::bytes::{
     Regex,
     RegexBuilder,
@@ -14,10 +26,29 @@ use std::{
         self,
         Read,
     },
-    path::PathBuf,
+    path::{
+        Path,
+        PathBuf,
+    },
     rc::Rc,
+    sync::mpsc::{
+        channel,
+        Receiver,
+    },
     time::SystemTime,
 };
+use tokio::signal::unix::{
+    signal,
+    SignalKind,
+};
+
+// Reactions tend to come in bursts, so being rate limited is expected rather
+// than something to give up on
+const MAX_RETRIES: u32 = 3;
+// How many times to try reconnecting after an error before giving up
+const RECONNECT_ATTEMPTS: u32 = 10;
+// Discord won't let a message have more than this many different reactions
+const MAX_REACTIONS: usize = 20;
 
 #[derive(Parser)]
 struct BotOptions {
@@ -25,6 +56,19 @@ struct BotOptions {
     token: String,
     #[clap(short='m', long="mention-file")]
     mention_file: PathBuf,
+    // Log what would be reacted with instead of reacting, for trying out
+    // changes to the mention file without bothering anyone
+    #[clap(short='n', long="dry-run")]
+    dry_run: bool,
+    // When someone else reacts to a message with one of the emoji from the
+    // mention file, react with it as well. Needs the GUILD_MESSAGE_REACTIONS
+    // intent to hear about reactions
+    #[clap(short='p', long="pile-on")]
+    pile_on: bool,
+    // The gateway intents to connect with, as a comma separated list of
+    // their names
+    #[clap(long="intents", default_value="GUILD_MESSAGES,DIRECT_MESSAGES,GUILD_MESSAGE_REACTIONS")]
+    intents: discord::Intents,
 }
 
 struct Mentions {
@@ -72,50 +116,196 @@ impl Mentions {
     }
     // If the file has changed since we last checked it, try to overwrite our
     // current mappings with the new ones
+    fn refresh(&mut self) {
+        let modified = fs::metadata(&self.mentions_file).ok()
+            .and_then(|md| md.modified().ok());
+        if matches!(modified, Some(modified) if self.last_modified < modified) {
+            self.reload();
+        }
+    }
+    // Try to overwrite our current mappings with whatever is in the file now
     //
     // Ignore any errors, better to have mappings than to try to use a broken
     // file
-    fn refresh(&mut self) {
-        let result = fs::metadata(&self.mentions_file).ok()
-            .and_then(|md| md.modified().ok())
-            .and_then(|modified| {
-                if self.last_modified < modified {
-                    Self::new(self.mentions_file.clone()).ok()
-                } else {
-                    None
-                }
-            });
-        if let Some(val) = result {
+    fn reload(&mut self) {
+        if let Ok(val) = Self::new(self.mentions_file.clone()) {
             *self = val;
         }
     }
-    // Find the first emoji with a match in the specified emoji file
-    fn first_match(&self, bytes: &[u8]) -> Option<Rc<str>> {
-        self.regex_map.iter().find(|r| r.0.is_match(bytes)).map(|r| Rc::clone(&r.1))
+    // Find every mapping with a match, in the order they're in the file. The
+    // same emoji shows up once for each of its regexes that matches
+    fn all_matches<'a>(&'a self, bytes: &'a [u8]) -> impl Iterator<Item=&'a (Regex, Rc<str>)> + 'a {
+        self.regex_map.iter().filter(move |r| r.0.is_match(bytes))
+    }
+    // The emoji to react to a message with, each one once and in the order
+    // they're in the file, stopping at as many as a message can have
+    fn reactions<'a>(&'a self, bytes: &'a [u8]) -> Vec<&'a str> {
+        let mut emojis = Vec::<&str>::new();
+        for (_, emoji) in self.all_matches(bytes) {
+            if emojis.len() == MAX_REACTIONS {
+                break;
+            }
+            if !emojis.contains(&&**emoji) {
+                emojis.push(emoji);
+            }
+        }
+        emojis
     }
+    // Whether any mapping reacts with emoji
+    fn has_emoji(&self, emoji: &str) -> bool {
+        self.regex_map.iter().any(|r| &*r.1 == emoji)
+    }
+}
+
+// Watch for changes to the mention file, which get sent to the receiver. The
+// directory is watched rather than the file itself, as a lot of editors save
+// by replacing the file, which would leave us watching the old one
+fn watch(path: &Path) -> notify::Result<(RecommendedWatcher, Receiver<()>)> {
+    let (tx, rx) = channel();
+    let name = path.file_name().map(|name| name.to_owned());
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        // Reading the file ourselves shows up as an access, which mustn't
+        // set off another reload
+        if let Ok(event) = res {
+            if !event.kind.is_access() && event.paths.iter().any(|p| p.file_name() == name.as_deref()) {
+                let _ = tx.send(());
+            }
+        }
+    })?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    Ok((watcher, rx))
+}
+
+// Resolves once we've been asked to stop, either by Ctrl-C or by a SIGTERM
+// from e.g. systemd. The handlers are set up straight away, so nothing is
+// missed before this is first polled
+fn shutdown_signal() -> io::Result<impl Future<Output=()>> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    Ok(async move {
+        futures::select! {
+            _ = interrupt.recv().fuse() => (),
+            _ = terminate.recv().fuse() => (),
+        }
+    })
 }
 
 #[tokio::main]
 async fn main() -> Result<(), error::Error> {
     let options = BotOptions::from_args();
-    let intents = discord::Intents::GUILD_MESSAGES | discord::Intents::DIRECT_MESSAGES;
 
+    // The watcher has to be kept around for as long as we want to hear about
+    // changes. If watching isn't possible here, fall back to checking when
+    // the file was last modified on every message
+    let watcher = match watch(&options.mention_file) {
+        Ok(watcher) => Some(watcher),
+        Err(e) => {
+            eprintln!("Failed to watch mention file, polling it instead: {}", e);
+            None
+        }
+    };
     let mut mentions = Mentions::new(options.mention_file)?;
-    let mut discord = discord::Discord::connect_bot(&options.token, Some(intents)).await?;
+    let mut discord = discord::Discord::connect_bot(&options.token, Some(options.intents)).await?.with_auto_retry(MAX_RETRIES);
+
+    let shutdown = shutdown_signal()?.fuse();
+    pin_mut!(shutdown);
     loop {
-        match discord.next().await {
-            Ok(msg) => {
+        let event = futures::select_biased! {
+            () = shutdown => break,
+            event = discord.next_event().fuse() => event,
+        };
+        if event.is_ok() {
+            match watcher {
+                Some((_, ref changes)) => if changes.try_iter().count() > 0 {
+                    mentions.reload();
+                },
+                None => mentions.refresh(),
+            }
+        }
+        match event {
+            // Messages edited to add a trigger get reacted to as well
+            Ok(discord::Event::MessageCreate(msg)) | Ok(discord::Event::MessageUpdate(msg)) => {
                 let cid = msg.channel_id();
                 let mid = msg.message_id();
-                mentions.refresh();
-                if let Some(r) = mentions.first_match(msg.message().as_bytes()) {
-                    tokio::spawn(discord.add_reaction(cid, mid, &r));
+                let emojis = mentions.reactions(msg.message().as_bytes());
+                if options.dry_run {
+                    for emoji in emojis {
+                        eprintln!("{}/{} -> {}", cid, mid, emoji);
+                    }
+                    continue;
+                }
+                // Reactions on the same message share a rate limit, so
+                // they're added one after the other rather than all at once
+                let reactions = emojis.into_iter()
+                    .map(|emoji| discord.add_reaction(cid, mid, emoji))
+                    .collect::<Vec<_>>();
+                if !reactions.is_empty() {
+                    tokio::spawn(async move {
+                        for reaction in reactions {
+                            if let Err(e) = reaction.await {
+                                eprintln!("Failed to add reaction: {}", e);
+                            }
+                        }
+                    });
                 }
             }
+            // Our own reactions come back to us too, which mustn't set off
+            // another one
+            Ok(discord::Event::ReactionAdd(reaction)) if options.pile_on && reaction.user_id() != discord.user_id() => {
+                let emoji = match reaction.emoji() {
+                    Some(emoji) if mentions.has_emoji(&emoji) => emoji,
+                    _ => continue,
+                };
+                if options.dry_run {
+                    eprintln!("{}/{} -> {}", reaction.channel_id(), reaction.message_id(), emoji);
+                    continue;
+                }
+                let res = discord.add_reaction(reaction.channel_id(), reaction.message_id(), &emoji);
+                tokio::spawn(async move {
+                    if let Err(e) = res.await {
+                        eprintln!("Failed to add reaction: {}", e);
+                    }
+                });
+            }
+            Ok(_) => (),
+            // There's no point trying again if e.g. the token is wrong
+            Err(e @ error::Error::Gateway { .. }) => return Err(e),
             Err(e) => {
                 eprintln!("ERROR: {}", e);
-                discord = self::discord::Discord::connect_bot(&options.token, Some(intents)).await?;
+                discord.reconnect_with_backoff(RECONNECT_ATTEMPTS).await?;
             }
         }
     }
+    discord.close(1000, "shutting down").await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mentions(regex_map: &[(&str, &str)]) -> Mentions {
+        Mentions {
+            mentions_file: PathBuf::new(),
+            last_modified: SystemTime::UNIX_EPOCH,
+            regex_map: regex_map.iter().map(|(regex, emoji)| (Regex::new(regex).unwrap(), Rc::from(*emoji))).collect(),
+        }
+    }
+
+    #[test]
+    fn reactions_are_deduplicated() {
+        let mentions = mentions(&[("cat", "a"), ("dog", "b"), ("kitten", "a"), ("fish", "c")]);
+        assert_eq!(mentions.reactions(b"a cat and a kitten and a dog"), ["a", "b"]);
+        assert!(mentions.reactions(b"nothing").is_empty());
+    }
+
+    #[test]
+    fn reactions_are_capped() {
+        let emojis = (0..MAX_REACTIONS + 5).map(|i| i.to_string()).collect::<Vec<_>>();
+        let mentions = mentions(&emojis.iter().map(|emoji| ("x", &**emoji)).collect::<Vec<_>>());
+        assert_eq!(mentions.reactions(b"x"), emojis[..MAX_REACTIONS]);
+    }
 }
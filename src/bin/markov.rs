@@ -1,5 +1,5 @@
 #![recursion_limit="512"]
-#![feature(hash_set_entry, try_blocks)]
+#![feature(hash_set_entry)]
 
 use discord_bots::{discord, chain, error};
 
@@ -7,18 +7,54 @@ use bytes::Bytes;
 use clap::Parser;
 use futures::{
     pin_mut,
-    future::FutureExt,
+    future::{
+        self,
+        Future,
+        FutureExt,
+    },
+    stream::TryStreamExt,
 };
 use std::{
     collections::{
         hash_map::HashMap,
         hash_set::HashSet,
     },
+    fs::{
+        self,
+        File,
+    },
+    io::{
+        self,
+        BufReader,
+        Write,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
     str,
+    sync::Arc,
+};
+use tokio::{
+    signal::unix::{
+        signal,
+        SignalKind,
+    },
+    sync::{
+        mpsc::{unbounded_channel, UnboundedSender},
+        Semaphore,
+    },
+    task,
+    time::{
+        self,
+        Duration,
+        Instant,
+        MissedTickBehavior,
+    },
 };
-use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
 
-const MAX_MESSAGE_LENGTH: usize = 2000;
+// How many times to try reconnecting after an error before giving up
+const RECONNECT_ATTEMPTS: u32 = 10;
 
 #[derive(Parser)]
 struct BotOptions {
@@ -30,6 +66,93 @@ struct BotOptions {
     backlog_len: usize,
     #[clap(short='g', long="whole-guild-logs")]
     whole_guild_logs: bool,
+    // How many times each kind of message counts when fed to a chain. If a
+    // message is both a reply and has attachments, the attachment weight is
+    // used. A weight of 0 means that kind of message isn't learned from at all
+    #[clap(long="message-weight", default_value_t=1)]
+    message_weight: usize,
+    #[clap(long="reply-weight", default_value_t=1)]
+    reply_weight: usize,
+    #[clap(long="attachment-weight", default_value_t=1)]
+    attachment_weight: usize,
+    // Chain over whole words instead of characters, chain-len is then the
+    // number of words in each state
+    #[clap(short='w', long="words")]
+    words: bool,
+    // The most states each chain can have before it starts forgetting the
+    // ones it learnt longest ago, by default chains grow forever
+    #[clap(long="max-states")]
+    max_states: Option<usize>,
+    // Case fold messages before feeding them to chains, so e.g. "Hello" and
+    // "hello" are learnt as the same thing
+    #[clap(short='i', long="lowercase")]
+    lowercase: bool,
+    // How many states a chain needs before the bot replies from it, so it
+    // doesn't reply with nonsense when it has barely learnt anything
+    #[clap(long="min-states", default_value_t=0)]
+    min_states: usize,
+    // When a reply reaches a state the chain hasn't learnt anywhere to go
+    // from, or ends before min-length, carry on from the end of it instead
+    #[clap(long="backoff")]
+    backoff: bool,
+    // The shortest reply to send, in bytes. Replies which end sooner carry on
+    // with backoff if they can and start a new line if not
+    #[clap(long="min-length", default_value_t=0)]
+    min_length: usize,
+    // How many channels can have their backlogs fetched at once, the rest
+    // wait their turn so joining a big server doesn't flood Discord with
+    // history requests
+    #[clap(long="max-concurrent-backfills", default_value_t=4)]
+    max_concurrent_backfills: usize,
+    // Where to save the chains, which are loaded again from there when the
+    // bot starts. By default they're thrown away when the bot stops
+    #[clap(long="state-dir")]
+    state_dir: Option<PathBuf>,
+    // How many seconds between saving the chains to the state dir, on top of
+    // saving them when the bot stops, or 0 to only save them then
+    #[clap(long="save-interval", default_value_t=300)]
+    save_interval: u64,
+    // The gateway intents to connect with, as a comma separated list of
+    // their names
+    #[clap(long="intents", default_value="GUILD_MESSAGES,DIRECT_MESSAGES")]
+    intents: discord::Intents,
+}
+
+impl BotOptions {
+    fn new_chain(&self) -> chain::Chain {
+        let chain = if self.words {
+            chain::Chain::new_tokenized(self.chain_length)
+        } else {
+            chain::Chain::new(self.chain_length)
+        };
+        self.configure_chain(chain)
+    }
+    // Set up the parts of a chain that aren't saved with it
+    fn configure_chain(&self, chain: chain::Chain) -> chain::Chain {
+        let chain = match self.max_states {
+            Some(max_states) => chain.max_states(max_states),
+            None => chain,
+        };
+        let chain = if self.backoff {
+            chain.backoff()
+        } else {
+            chain
+        };
+        if self.lowercase {
+            chain.normalizer(chain::lowercase)
+        } else {
+            chain
+        }
+    }
+    fn feed_weight(&self, msg: &discord::Message) -> usize {
+        if msg.has_attachments() {
+            self.attachment_weight
+        } else if msg.is_reply() {
+            self.reply_weight
+        } else {
+            self.message_weight
+        }
+    }
 }
 
 struct BacklogMessage {
@@ -37,46 +160,185 @@ struct BacklogMessage {
     guild_id: Option<Bytes>
 }
 
-async fn get_old_messages(mut messages: discord::ChannelMessages, gid: Option<Bytes>, tx: UnboundedSender<BacklogMessage>) {
-    let res: Result<(), error::Error> = try {
-        while let Some(msg) = messages.next().await? {
-            let guild_id = msg.guild_id_buf().cloned().or_else(|| gid.clone());
-            tx.send(BacklogMessage { msg, guild_id }).map_err(|_| error::Error::SendChannelClosed)?;
-        }
+async fn get_old_messages(messages: discord::ChannelMessages, gid: Option<Bytes>, tx: UnboundedSender<BacklogMessage>, backfills: Arc<Semaphore>) {
+    // Pages aren't fetched until the stream is polled, so holding this for
+    // the whole backfill bounds how many channels are paging at once
+    let _permit = match backfills.acquire().await {
+        Ok(permit) => permit,
+        Err(_) => return,
     };
+    let res = messages.try_for_each(|msg| {
+        let guild_id = msg.guild_id_buf().cloned().or_else(|| gid.clone());
+        future::ready(tx.send(BacklogMessage { msg, guild_id }).map_err(|_| error::Error::SendChannelClosed))
+    }).await;
     if let Err(e) = res {
         eprintln!("Failed to get old message: {}", e);
     }
 }
 
+// The file in the state dir listing the channels we've encountered, one id
+// per line
+const CHANNELS_FILE: &str = "encountered-channels";
+
+// Serialize each chain into files, named after the channel or guild it was
+// learnt from
+fn snapshot_chains(kind: &str, chains: &HashMap<Bytes, chain::Chain>, files: &mut Vec<(String, Vec<u8>)>) -> io::Result<()> {
+    for (id, chain) in chains {
+        let mut contents = Vec::new();
+        chain.save(&mut contents)?;
+        files.push((format!("{}-{}.chain", kind, String::from_utf8_lossy(id)), contents));
+    }
+    Ok(())
+}
+
+// Write each file to a temporary file in dir first, synced to disk, and then
+// move it into place, so a crash part way through never leaves half a file
+// behind
+fn write_files(dir: &Path, files: Vec<(String, Vec<u8>)>) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    for (name, contents) in files {
+        let path = dir.join(&name);
+        let tmp = dir.join(format!("{}.tmp", name));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&contents)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+    }
+    Ok(())
+}
+
+// Save the chains, and the channels whose backlogs we've already fetched, to
+// dir. Everything is serialized up front and then written out on a blocking
+// thread, so a slow disk doesn't hold up the runtime
+async fn save_state(dir: &Path, channel_chains: &HashMap<Bytes, chain::Chain>, guild_chains: &HashMap<Bytes, chain::Chain>, encountered_channels: &HashSet<Bytes>) -> io::Result<()> {
+    let mut files = Vec::new();
+    snapshot_chains("channel", channel_chains, &mut files)?;
+    snapshot_chains("guild", guild_chains, &mut files)?;
+    let channels = encountered_channels.iter().map(|id| &id[..]).collect::<Vec<_>>().join(&b'\n');
+    files.push((String::from(CHANNELS_FILE), channels));
+
+    let dir = dir.to_owned();
+    task::spawn_blocking(move || write_files(&dir, files)).await
+        .map_err(io::Error::other)?
+}
+
+// Load the channels saved by save_state, or none if nothing has been saved
+fn load_channels(dir: &Path) -> HashSet<Bytes> {
+    match fs::read(dir.join(CHANNELS_FILE)) {
+        Ok(contents) => contents.split(|&b| b == b'\n')
+            .filter(|id| !id.is_empty())
+            .map(Bytes::copy_from_slice)
+            .collect(),
+        Err(e) => {
+            if e.kind() != io::ErrorKind::NotFound {
+                eprintln!("Failed to read {}: {}", dir.join(CHANNELS_FILE).display(), e);
+            }
+            HashSet::new()
+        }
+    }
+}
+
+// Load the chains saved by save_state, keyed by the id in their file names.
+// Anything that can't be loaded, or was saved with a different chain length
+// or kind than we're using now, is skipped rather than stopping the bot
+fn load_chains(dir: &Path, kind: &str, options: &BotOptions) -> HashMap<Bytes, chain::Chain> {
+    let mut chains = HashMap::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        // Nothing has been saved yet
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return chains,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", dir.display(), e);
+            return chains;
+        }
+    };
+    let prefix = format!("{}-", kind);
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let id = match path.file_name().and_then(|name| name.to_str()).and_then(|name| name.strip_prefix(&prefix)?.strip_suffix(".chain")) {
+            Some(id) => Bytes::from(id.to_owned()),
+            None => continue,
+        };
+        match File::open(&path).and_then(|file| chain::Chain::load(BufReader::new(file))) {
+            Ok(chain) if chain.chain_len() != options.chain_length || chain.is_tokenized() != options.words => {
+                eprintln!("Skipping {}, it was saved with different chain options", path.display());
+            }
+            Ok(chain) => {
+                chains.insert(id, options.configure_chain(chain));
+            }
+            Err(e) => eprintln!("Skipping {}: {}", path.display(), e),
+        }
+    }
+    chains
+}
+
+// Resolves once we've been asked to stop, either by Ctrl-C or by a SIGTERM
+// from e.g. systemd. The handlers are set up straight away, so nothing is
+// missed before this is first polled
+fn shutdown_signal() -> io::Result<impl Future<Output=()>> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    Ok(async move {
+        futures::select! {
+            _ = interrupt.recv().fuse() => (),
+            _ = terminate.recv().fuse() => (),
+        }
+    })
+}
 
 #[tokio::main]
 async fn main() -> Result<(), error::Error> {
     let options = BotOptions::from_args();
-    let intents =
-        discord::Intents::GUILD_MESSAGES | discord::Intents::DIRECT_MESSAGES;
 
-    let mut discord = discord::Discord::connect_bot(&options.token, Some(intents)).await?;
-    let mut rng = rand::thread_rng();
+    // Loaded guild chains have already learnt the backlogs of the channels
+    // we'd encountered, so only new channels have their backlogs fetched
+    let (mut channel_chains, mut guild_chains, mut encountered_channels) = match &options.state_dir {
+        Some(dir) => (load_chains(dir, "channel", &options), load_chains(dir, "guild", &options), load_channels(dir)),
+        None => (HashMap::new(), HashMap::new(), HashSet::new()),
+    };
 
-    // These all use Bytes as a key, which is a known false positive for this
-    // lint
-    #[allow(clippy::mutable_key_type)]
-    let mut channel_chains = HashMap::new();
-    #[allow(clippy::mutable_key_type)]
-    let mut guild_chains = HashMap::new();
-    #[allow(clippy::mutable_key_type)]
-    let mut encountered_channels = HashSet::new();
+    // Backlog messages are worked through between events, so heartbeats are
+    // sent in the background in case that takes a while
+    let connect_options = discord::ConnectOptions::new().background_heartbeat(true);
+    let mut discord = discord::Discord::connect_bot_with_options(&options.token, Some(options.intents), connect_options).await?;
+    let mut rng = rand::thread_rng();
 
     let (tx, mut rx) = unbounded_channel::<BacklogMessage>();
+    let backfills = Arc::new(Semaphore::new(options.max_concurrent_backfills.max(1)));
+
+    let shutdown = shutdown_signal()?.fuse();
+    pin_mut!(shutdown);
+
+    let mut save_timer = match (&options.state_dir, options.save_interval) {
+        (Some(_), secs) if secs > 0 => {
+            let period = Duration::from_secs(secs);
+            let mut timer = time::interval_at(Instant::now() + period, period);
+            timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            Some(timer)
+        }
+        _ => None,
+    };
 
-    loop {
+    'events: loop {
         let res = {
             let next = discord.next().fuse();
             pin_mut!(next);
             loop {
                 // Favour incoming messages over backlog messages
                 futures::select_biased! {
+                    () = shutdown => break 'events,
+                    _ = async {
+                        match &mut save_timer {
+                            Some(timer) => timer.tick().await,
+                            None => future::pending().await,
+                        }
+                    }.fuse() => if let Some(dir) = &options.state_dir {
+                        // Keep going if this fails, there's still a chance
+                        // the next save works
+                        if let Err(e) = save_state(dir, &channel_chains, &guild_chains, &encountered_channels).await {
+                            eprintln!("Failed to save chains: {}", e);
+                        }
+                    },
                     // We've received a real message, continue
                     msg_res = next => break msg_res,
                     // We've got a backlog message, just feed it to the chain
@@ -85,13 +347,13 @@ async fn main() -> Result<(), error::Error> {
                     backlog = rx.recv().fuse() => if let Some(backlog) = backlog {
                         let chain = if let (Some(guild_id_buf), true) = (backlog.guild_id, options.whole_guild_logs) {
                             guild_chains.entry(guild_id_buf)
-                                .or_insert_with(|| chain::Chain::new(options.chain_length))
+                                .or_insert_with(|| options.new_chain())
                         } else {
                             channel_chains.entry(backlog.msg.channel_id_buf().clone())
-                                .or_insert_with(|| chain::Chain::new(options.chain_length))
+                                .or_insert_with(|| options.new_chain())
                         };
                         if !backlog.msg.is_me() && !backlog.msg.message().is_empty() && !backlog.msg.mentioned() {
-                            chain.feed(backlog.msg.message_buf().clone());
+                            chain.feed_weighted(backlog.msg.message_buf().clone(), options.feed_weight(&backlog.msg));
                         }
                     } else {
                         return Err(error::Error::SendChannelClosed)
@@ -103,45 +365,37 @@ async fn main() -> Result<(), error::Error> {
             Ok(msg) => {
                 let chain = if let (Some(guild_id_buf), true) = (msg.guild_id_buf(), options.whole_guild_logs) {
                     encountered_channels.get_or_insert_with(msg.channel_id_buf(), |buf| {
-                        let old_messages = discord.channel_messages(msg.channel_id(), options.backlog_len, None);
-                        tokio::spawn(get_old_messages(old_messages, Some(guild_id_buf.clone()), tx.clone()));
+                        let old_messages = discord.channel_messages(msg.channel_id(), options.backlog_len, discord::Direction::Before(None));
+                        tokio::spawn(get_old_messages(old_messages, Some(guild_id_buf.clone()), tx.clone(), backfills.clone()));
                         buf.clone()
                     });
 
                     guild_chains.entry(guild_id_buf.clone())
-                        .or_insert_with(|| chain::Chain::new(options.chain_length))
+                        .or_insert_with(|| options.new_chain())
                 } else {
                     channel_chains.entry(msg.channel_id_buf().clone())
                         .or_insert_with(|| {
-                            let old_messages = discord.channel_messages(msg.channel_id(), options.backlog_len, None);
-                            tokio::spawn(get_old_messages(old_messages, None, tx.clone()));
-                            chain::Chain::new(options.chain_length)
+                            let old_messages = discord.channel_messages(msg.channel_id(), options.backlog_len, discord::Direction::Before(None));
+                            tokio::spawn(get_old_messages(old_messages, None, tx.clone(), backfills.clone()));
+                            options.new_chain()
                         })
                 };
 
                 if !msg.is_me() && !msg.message().is_empty() {
                     if !msg.mentioned() {
-                        chain.feed(msg.message_buf().clone());
+                        chain.feed_weighted(msg.message_buf().clone(), options.feed_weight(&msg));
+                    } else if chain.len() < options.min_states {
+                        eprintln!("Not replying, the chain only has {} states", chain.len());
                     } else {
-                        let mut message = String::new();
-
-                        // The messages we receive should all be UTF-8
-                        // (otherwise the Deserialization will fail, the
-                        // underlying Discord models assume a str not just
-                        // bytes), so this should in theory never fail, but I
-                        // don't know enough about UTF-8 or unicode to guarantee
-                        // that so I just try 10 times to build a valid string
-                        // and if I still can't build a message after than, just
-                        // ignore the message
-                        for _ in 0..10 {
-                            let bytes = chain.generator(&mut rng).take(MAX_MESSAGE_LENGTH.saturating_sub(message.len())).collect::<Vec<_>>();
-                            if let Ok(s) = str::from_utf8(&bytes) {
-                                message.push_str(s);
-                                break;
-                            }
-                        }
+                        // Everything fed in is UTF-8, and chains only ever
+                        // generate whole characters or words, so this only
+                        // fails for a chain loaded from a corrupt file
+                        let bytes = chain.generator_bounded(&mut rng, options.min_length, discord::MAX_MESSAGE_LENGTH).collect::<Vec<_>>();
+                        let message = String::from_utf8(bytes).unwrap_or_default();
                         if !message.is_empty() {
-                            let msg = discord.send_message(msg.channel_id(), &message);
+                            // The message is made up of what other people
+                            // have said, so it shouldn't ping anyone they did
+                            let msg = discord.send_message_safe(msg.channel_id(), &message);
                             tokio::spawn(async move {
                                 let res = msg.await;
                                 if let Err(e) = res {
@@ -154,13 +408,24 @@ async fn main() -> Result<(), error::Error> {
                     }
                 }
             }
+            // There's no point trying again if e.g. the token is wrong
+            Err(e @ error::Error::Gateway { .. }) => return Err(e),
             Err(e) => {
                 eprintln!("ERROR: {}", e);
                 // Just try to reconnect if we can so that we keep all of the
                 // chains we have built rather than killing the process and
                 // starting from scratch again
-                discord = self::discord::Discord::connect_bot(&options.token, Some(intents)).await?;
+                discord.reconnect_with_backoff(RECONNECT_ATTEMPTS).await?;
             }
         }
     }
+
+    // Still close the connection properly if saving fails, since there's
+    // nothing more to be done about it now
+    if let Some(dir) = &options.state_dir {
+        if let Err(e) = save_state(dir, &channel_chains, &guild_chains, &encountered_channels).await {
+            eprintln!("Failed to save chains: {}", e);
+        }
+    }
+    discord.close(1000, "shutting down").await
 }
@@ -1,29 +1,98 @@
-use bytes::Bytes;
+use bytes::{
+    Buf,
+    BufMut,
+    Bytes,
+    BytesMut,
+};
 use rand::{
     distributions::Distribution,
     Rng
 };
 use std::{
     cmp,
-    collections::HashMap,
-    hash::Hash,
+    collections::{
+        BTreeMap,
+        HashMap,
+        HashSet,
+    },
+    convert::TryFrom,
+    hash::{
+        Hash,
+        Hasher,
+    },
+    io::{
+        self,
+        Read,
+        Write,
+    },
     iter,
 };
 
+// The values are kept in a BTreeMap rather than a HashMap so that iteration
+// order (and so sampling with a seeded Rng) is the same from run to run
 struct WeightedSet<T> {
-    values: HashMap<T, usize>,
+    values: BTreeMap<T, usize>,
     total_size: usize,
 }
-impl<T: Hash + Eq> WeightedSet<T> {
+impl<T: Ord> WeightedSet<T> {
     pub fn new() -> Self {
         Self {
-            values: HashMap::new(),
+            values: BTreeMap::new(),
             total_size: 0,
         }
     }
-    pub fn insert(&mut self, value: T) {
-        *self.values.entry(value).or_insert(0) += 1;
-        self.total_size += 1;
+    pub fn insert(&mut self, value: T, weight: usize) {
+        *self.values.entry(value).or_insert(0) += weight;
+        self.total_size += weight;
+    }
+    // Remove every one of `values` from the set, along with its weight
+    pub fn remove_all(&mut self, values: &HashSet<T>) where T: Hash {
+        let total_size = &mut self.total_size;
+        self.values.retain(|value, weight| {
+            let keep = !values.contains(value);
+            if !keep {
+                *total_size -= *weight;
+            }
+            keep
+        });
+    }
+    // Take weight off of a value, removing it once it has none left
+    pub fn remove(&mut self, value: &T, weight: usize) {
+        if let Some(current) = self.values.get_mut(value) {
+            let removed = cmp::min(*current, weight);
+            *current -= removed;
+            self.total_size -= removed;
+            if *current == 0 {
+                self.values.remove(value);
+            }
+        }
+    }
+    // Like `sample`, but only out of the values keep is true for, or None if
+    // there aren't any
+    pub fn sample_where<R: Rng + ?Sized, F: Fn(&T) -> bool>(&self, rng: &mut R, keep: F) -> Option<T> where T: Clone {
+        let total_size = self.values.iter().filter(|(value, _)| keep(value)).map(|(_, weight)| *weight).sum::<usize>();
+        if total_size == 0 {
+            return None;
+        }
+        let selected = rng.gen_range(1..=total_size);
+        self.values.iter()
+            .filter(|(value, _)| keep(value))
+            .scan(0, |accum, (value, weight)| {
+                *accum += *weight;
+                Some((*accum >= selected, value))
+            })
+            .find_map(|(is_next, value)| is_next.then(|| value.clone()))
+    }
+    // Multiply every weight by factor, rounding down and removing the values
+    // which end up with no weight at all
+    pub fn scale(&mut self, factor: f64) {
+        let total_size = &mut self.total_size;
+        *total_size = 0;
+        self.values.retain(|_, weight| {
+            *weight = (*weight as f64 * factor) as usize;
+            *total_size += *weight;
+            *weight > 0
+        });
     }
 }
 impl<T: Clone> Distribution<T> for WeightedSet<T> {
@@ -39,60 +108,565 @@ impl<T: Clone> Distribution<T> for WeightedSet<T> {
     }
 }
 
+// A state of the chain, which is None for the start of the input (when
+// it's a key) or the end of it (when it's a successor). States are hashed and
+// compared by their bytes alone, no matter which buffer they point into
+#[derive(Clone, Debug)]
+struct State(Option<Bytes>);
+impl PartialEq for State {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_deref() == other.0.as_deref()
+    }
+}
+impl Eq for State {}
+impl Hash for State {
+    fn hash<H: Hasher>(&self, hasher: &mut H) {
+        self.0.as_deref().hash(hasher)
+    }
+}
+impl PartialOrd for State {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for State {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        self.0.as_deref().cmp(&other.0.as_deref())
+    }
+}
+
+// Saved chains are laid out as:
+//
+//     magic, chain_len, tokenized, state count, (state, successor count, (successor, weight)*)*
+//
+// where tokenized is a byte which is 1 for a tokenized chain and 0 otherwise,
+// every number is a little endian u64 and every state is a byte which is 0
+// for None and 1 for Some, followed by the length and bytes for Some
+const MAGIC: &[u8; 8] = b"MRKVCHN2";
+
+fn write_u64<W: Write>(w: &mut W, value: u64) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+fn read_usize<R: Read>(r: &mut R) -> io::Result<usize> {
+    usize::try_from(read_u64(r)?).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "length too large"))
+}
+fn write_state<W: Write>(w: &mut W, state: &Option<Bytes>) -> io::Result<()> {
+    match state {
+        Some(bytes) => {
+            w.write_all(&[1])?;
+            write_u64(w, bytes.len() as u64)?;
+            w.write_all(bytes)
+        }
+        None => w.write_all(&[0]),
+    }
+}
+fn read_state<R: Read>(r: &mut R) -> io::Result<Option<Bytes>> {
+    let mut tag = [0];
+    r.read_exact(&mut tag)?;
+    match tag[0] {
+        0 => Ok(None),
+        1 => {
+            let len = read_u64(r)?;
+            // Read through take rather than allocating len bytes up front, so
+            // a corrupt length fails with an EOF rather than a huge allocation
+            let mut buf = Vec::new();
+            r.take(len).read_to_end(&mut buf)?;
+            if buf.len() as u64 != len {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            Ok(Some(Bytes::from(buf)))
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid chain state")),
+    }
+}
+
+// Whether a byte starts a UTF-8 character rather than continuing one. Bytes
+// which aren't UTF-8 are each a character of their own, unless they look
+// like they continue the one before
+fn is_char_start(b: u8) -> bool {
+    b & 0xc0 != 0x80
+}
+
+// Windows of size characters, so a window never starts or ends in the middle
+// of a multi-byte character
+fn char_windows(bytes: &Bytes, size: usize) -> impl Iterator<Item=Bytes> + '_ {
+    let starts = (0..bytes.len()).filter(|&idx| idx == 0 || is_char_start(bytes[idx])).collect::<Vec<_>>();
+
+    // The idea here is to iterate between 0 and the last window's left
+    // position and then slice the bytes for the window size
+    //
+    // We need to special case for the bytes being smaller than the
+    // window size though - i.e. we need to iterate at least once, so
+    // make sure that the iterator range goes to at least 1. The last
+    // window just runs to the end of the bytes
+    (0..=starts.len().saturating_sub(size))
+        .map(move |idx| bytes.slice(starts.get(idx).copied().unwrap_or(0)..starts.get(idx + size).copied().unwrap_or(bytes.len())))
+}
+
+// Collapse every run of whitespace into a single space and trim it from the
+// ends, so that words are always separated by exactly one space. If the
+// bytes are already like that they're given back as they are
+fn normalize_whitespace(bytes: Bytes) -> Bytes {
+    let mut normalized = BytesMut::with_capacity(bytes.len());
+    for word in bytes.split(u8::is_ascii_whitespace).filter(|w| !w.is_empty()) {
+        if !normalized.is_empty() {
+            normalized.put_u8(b' ');
+        }
+        normalized.extend_from_slice(word);
+    }
+    if normalized[..] == bytes[..] {
+        bytes
+    } else {
+        normalized.freeze()
+    }
+}
+
+// Like char_windows but each window is size words long rather than size
+// characters. The bytes need to have gone through normalize_whitespace first
+fn word_windows(bytes: &Bytes, size: usize) -> impl Iterator<Item=Bytes> + '_ {
+    let spaces = bytes.iter().enumerate().filter(|(_, &b)| b == b' ').map(|(idx, _)| idx);
+    let starts = iter::once(0).chain(spaces.clone().map(|idx| idx + 1)).collect::<Vec<_>>();
+    let ends = spaces.chain(iter::once(bytes.len())).collect::<Vec<_>>();
+
+    // Same as char_windows, if there are fewer words than the window size
+    // we still want the one window with all of the words in it
+    (0..=starts.len().saturating_sub(size))
+        .map(move |idx| bytes.slice(starts[idx]..ends[cmp::min(ends.len(), idx + size) - 1]))
+}
+
+// Keeps track of the order states were last fed in, so the least recently
+// fed ones can be evicted once a chain has too many states. The starting
+// state (None) is never evicted so it isn't tracked
+struct Recency {
+    max_states: usize,
+    tick: u64,
+    order: BTreeMap<u64, Bytes>,
+    fed_at: HashMap<Bytes, u64>,
+}
+impl Recency {
+    fn new(max_states: usize) -> Self {
+        Self {
+            max_states,
+            tick: 0,
+            order: BTreeMap::new(),
+            fed_at: HashMap::new(),
+        }
+    }
+    fn touch(&mut self, state: &Bytes) {
+        self.tick += 1;
+        if let Some(old) = self.fed_at.insert(state.clone(), self.tick) {
+            self.order.remove(&old);
+        }
+        self.order.insert(self.tick, state.clone());
+    }
+    fn forget(&mut self, state: &Bytes) {
+        if let Some(old) = self.fed_at.remove(state) {
+            self.order.remove(&old);
+        }
+    }
+    fn pop_oldest(&mut self) -> Option<Bytes> {
+        let (_, state) = self.order.pop_first()?;
+        self.fed_at.remove(&state);
+        Some(state)
+    }
+}
+
+// Case fold bytes for `Chain::normalizer`, so e.g. "Hello" and "hello" end
+// up as the same states. Bytes which aren't UTF-8 only have their ASCII
+// letters folded
+pub fn lowercase(bytes: &[u8]) -> Bytes {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => Bytes::from(s.to_lowercase()),
+        Err(_) => Bytes::from(bytes.to_ascii_lowercase()),
+    }
+}
+
+type Normalizer = Box<dyn Fn(&[u8]) -> Bytes + Send + Sync>;
+
+// The last character of a state, or the last word for a tokenized chain
+fn last_unit(state: &Bytes, tokenized: bool) -> Bytes {
+    let start = if tokenized {
+        state.iter().rposition(|&c| c == b' ').map_or(0, |idx| idx + 1)
+    } else {
+        state.iter().rposition(|&c| is_char_start(c)).unwrap_or(0)
+    };
+    state.slice(start..)
+}
+
+// Every suffix of a state which is at least one character (or word) shorter
+// than it, longest first
+fn suffixes(state: &Bytes, tokenized: bool) -> impl Iterator<Item=Bytes> + '_ {
+    (1..state.len())
+        .filter(move |&idx| if tokenized { state[idx - 1] == b' ' } else { is_char_start(state[idx]) })
+        .map(move |idx| state.slice(idx..))
+}
+
+// Count a transition against every shorter suffix of the state it's from,
+// keeping just the character (or word) it adds rather than the whole next state
+fn index_backoff(backoff: &mut HashMap<Bytes, WeightedSet<Option<Bytes>>>, tokenized: bool, state: &Bytes, next: &Option<Bytes>, weight: usize) {
+    let unit = next.as_ref().map(|next| last_unit(next, tokenized));
+    for suffix in suffixes(state, tokenized) {
+        backoff.entry(suffix).or_insert_with(WeightedSet::new).insert(unit.clone(), weight);
+    }
+}
+// Take a transition which has been forgotten back out of the suffixes
+fn unindex_backoff(backoff: &mut HashMap<Bytes, WeightedSet<Option<Bytes>>>, tokenized: bool, state: &Bytes, next: &Option<Bytes>, weight: usize) {
+    let unit = next.as_ref().map(|next| last_unit(next, tokenized));
+    for suffix in suffixes(state, tokenized) {
+        if let Some(units) = backoff.get_mut(&suffix) {
+            units.remove(&unit, weight);
+            if units.values.is_empty() {
+                backoff.remove(&suffix);
+            }
+        }
+    }
+}
+
 pub struct Chain {
-    values: HashMap<Option<Bytes>, WeightedSet<Option<Bytes>>>,
-    chain_len: usize
+    values: HashMap<State, WeightedSet<State>>,
+    chain_len: usize,
+    tokenized: bool,
+    recency: Option<Recency>,
+    normalizer: Option<Normalizer>,
+    // What follows each suffix of the states, for when generation reaches a
+    // state with nowhere to go. See `backoff`
+    backoff: Option<HashMap<Bytes, WeightedSet<Option<Bytes>>>>,
 }
 impl Chain {
+    // A chain over characters, where each state is the last len characters.
+    // States never split a UTF-8 character, so as long as the chain is fed
+    // UTF-8 it generates UTF-8
     pub fn new(len: usize) -> Self {
         Self {
             values: HashMap::new(),
-            chain_len: len
+            chain_len: len,
+            tokenized: false,
+            recency: None,
+            normalizer: None,
+            backoff: None,
+        }
+    }
+    // A chain which passes everything it's fed through normalizer first, see
+    // `normalizer`
+    pub fn new_with_normalizer<F: Fn(&[u8]) -> Bytes + Send + Sync + 'static>(len: usize, normalizer: F) -> Self {
+        Self::new(len).normalizer(normalizer)
+    }
+    // Pass everything fed to the chain through normalizer before it's split
+    // into states, e.g. `lowercase` to case fold it. Only what's fed after
+    // this is normalized, and the normalizer isn't saved with the chain so it
+    // has to be set again after loading one
+    pub fn normalizer<F: Fn(&[u8]) -> Bytes + Send + Sync + 'static>(mut self, normalizer: F) -> Self {
+        self.normalizer = Some(Box::new(normalizer));
+        self
+    }
+    // A chain which holds at most max_states states, see `max_states`
+    pub fn with_capacity(len: usize, max_states: usize) -> Self {
+        Self::new(len).max_states(max_states)
+    }
+    // Limit the chain to max_states states. Once it has more than that, the
+    // states which were fed the longest time ago are forgotten along with
+    // every transition into them. Any states the chain already has count as
+    // older than anything fed after this
+    pub fn max_states(mut self, max_states: usize) -> Self {
+        let mut recency = Recency::new(max_states);
+        let mut states = self.values.keys().filter_map(|state| state.0.as_ref()).collect::<Vec<_>>();
+        states.sort_unstable();
+        for state in states {
+            recency.touch(state);
+        }
+        self.recency = Some(recency);
+        self.evict();
+        self
+    }
+    // When generation reaches a state the chain has no transitions out of,
+    // carry on from the longest suffix of it which has been seen followed by
+    // something, rather than stopping there. `generator_bounded` does the
+    // same when it reaches the end of the input before min_len, before
+    // falling back to starting again on a new line. The suffixes are kept
+    // alongside the states so this uses a lot more memory, and they aren't
+    // saved with the chain so this has to be called again after loading one
+    pub fn backoff(mut self) -> Self {
+        self.backoff = Some(HashMap::new());
+        self.rebuild_backoff();
+        self
+    }
+    fn rebuild_backoff(&mut self) {
+        let Self { values, backoff, tokenized, .. } = self;
+        if let Some(backoff) = backoff {
+            backoff.clear();
+            for (state, successors) in values.iter() {
+                if let Some(state) = &state.0 {
+                    for (next, weight) in &successors.values {
+                        index_backoff(backoff, *tokenized, state, &next.0, *weight);
+                    }
+                }
+            }
+        }
+    }
+    // A chain over whole words rather than bytes, where each state is the
+    // last len words. Input is split on whitespace and generated words are
+    // joined with single spaces, so the output never has words cut in half
+    pub fn new_tokenized(len: usize) -> Self {
+        Self {
+            tokenized: true,
+            ..Self::new(len)
         }
     }
     pub fn feed<T: Into<Bytes>>(&mut self, feeder: T) {
-        fn byte_windows(bytes: &Bytes, size: usize) -> impl Iterator<Item=Bytes> + '_ {
-            // The idea here is to iterate between 0 and the last window's left
-            // position and then slice the bytes for the window size
-            //
-            // We need to special case for the bytes being smaller than the
-            // window size though - i.e. we need to iterate at least once, so
-            // make sure that the iterator range goes to at least 1
-            (0..=bytes.len().saturating_sub(size))
-                .into_iter()
-                // if the bytes are smaller than the window size, then doing
-                // bytes[idx..idx + size] will overflow the buffer, so we need
-                // to make sure that the slice we make is within bounds
-                .map(move |idx| bytes.slice(idx..cmp::min(bytes.len(), idx + size)))
-        }
-
-        fn inner(this: &mut Chain, bytes: Bytes) {
-            if !bytes.is_empty() {
+        self.feed_weighted(feeder, 1)
+    }
+    // Feed the chain, counting every transition in the fed bytes `weight`
+    // times. A weight of 0 leaves the chain untouched
+    pub fn feed_weighted<T: Into<Bytes>>(&mut self, feeder: T, weight: usize) {
+        fn inner(this: &mut Chain, bytes: Bytes, weight: usize) {
+            let bytes = match this.normalizer {
+                Some(ref normalizer) => normalizer(&bytes),
+                None => bytes,
+            };
+            let bytes = if this.tokenized { normalize_whitespace(bytes) } else { bytes };
+            if !bytes.is_empty() && weight > 0 {
                 // We want an iterator like so (for the string "abcde"):
                 //
                 // (None, "abc"), ("abc", "bcd"), ("bcd", "cde"), ("cde", None)
                 //
-                // To do this we start with an iterator over "abc", "bcd", "cde"
-                // which is the above byte windows iterator for the bytes
-                //
+                // To do this we start with the windows "abc", "bcd", "cde"
+                // which is the above char windows iterator for the bytes (or
+                // the word windows for a tokenized chain)
+                let windows = if this.tokenized {
+                    word_windows(&bytes, this.chain_len).collect::<Vec<_>>()
+                } else {
+                    char_windows(&bytes, this.chain_len).collect::<Vec<_>>()
+                };
+
                 // Then we create one iterator which will go through those values,
                 // and finish with None
-                let wind_a = byte_windows(&bytes, this.chain_len).map(Option::Some).chain(iter::once(None));
+                let wind_a = windows.iter().cloned().map(Some).chain(iter::once(None)).map(State);
                 // Then we create another iterator which will start with None, then
                 // go through the values
-                let wind_b = iter::once(None).chain(byte_windows(&bytes, this.chain_len).map(Option::Some));
+                let wind_b = iter::once(None).chain(windows.iter().cloned().map(Some)).map(State);
 
                 //Then we zip the two iterators together
                 for (prev, next) in wind_b.zip(wind_a) {
-                    this.values.entry(prev).or_insert_with(WeightedSet::new).insert(next);
+                    if let (Some(recency), Some(prev)) = (this.recency.as_mut(), prev.0.as_ref()) {
+                        recency.touch(prev);
+                    }
+                    if let (Some(backoff), Some(prev)) = (this.backoff.as_mut(), prev.0.as_ref()) {
+                        index_backoff(backoff, this.tokenized, prev, &next.0, weight);
+                    }
+                    this.values.entry(prev).or_insert_with(WeightedSet::new).insert(next, weight);
+                }
+                this.evict();
+            }
+        }
+
+        inner(self, feeder.into(), weight)
+    }
+    fn evict(&mut self) {
+        let Self { values, recency, backoff, tokenized, .. } = self;
+        let recency = match recency {
+            Some(recency) if values.len() > recency.max_states => recency,
+            _ => return,
+        };
+
+        // Evict down to 90% of the limit rather than to just under it, so
+        // the pass over every state below doesn't happen on every feed
+        let target = recency.max_states - recency.max_states / 10;
+        let mut evicted = HashSet::new();
+        while values.len() > target {
+            match recency.pop_oldest() {
+                Some(state) => {
+                    let state = State(Some(state));
+                    let successors = values.remove(&state);
+                    if let (Some(backoff), Some(successors)) = (backoff.as_mut(), successors) {
+                        for (next, weight) in &successors.values {
+                            unindex_backoff(backoff, *tokenized, state.0.as_ref().unwrap(), &next.0, *weight);
+                        }
+                    }
+                    evicted.insert(state);
                 }
+                None => break,
             }
         }
 
-        inner(self, feeder.into())
+        // Nothing can transition into the evicted states any more. States
+        // left with nowhere to go are removed as well, so we never sample an
+        // empty set, which just means generation stops a little earlier when
+        // it reaches a state that transitioned into them
+        values.retain(|state, successors| {
+            if let (Some(backoff), Some(state)) = (backoff.as_mut(), &state.0) {
+                for (next, weight) in successors.values.iter().filter(|(next, _)| evicted.contains(next)) {
+                    unindex_backoff(backoff, *tokenized, state, &next.0, *weight);
+                }
+            }
+            successors.remove_all(&evicted);
+            if successors.values.is_empty() {
+                if let Some(state) = &state.0 {
+                    recency.forget(state);
+                }
+                false
+            } else {
+                true
+            }
+        });
+    }
+    // Multiply the weight of every transition in the chain by factor, so what
+    // it was fed before counts for less than what it's fed after. Weights are
+    // rounded down, so transitions which were only seen a few times are
+    // forgotten altogether, along with any states left with nowhere to go.
+    // Calling this every so often lets a chain drift towards what it's been
+    // fed recently
+    //
+    // Panics if factor is negative or not finite
+    pub fn decay(&mut self, factor: f64) {
+        assert!(factor >= 0.0 && factor.is_finite(), "decay factor must be a non-negative number");
+
+        let Self { values, recency, .. } = self;
+        values.retain(|state, successors| {
+            successors.scale(factor);
+            if successors.values.is_empty() {
+                if let (Some(recency), Some(state)) = (recency.as_mut(), &state.0) {
+                    recency.forget(state);
+                }
+                false
+            } else {
+                true
+            }
+        });
+        self.rebuild_backoff();
     }
+    // Add everything other has been fed to this chain, as if this chain had
+    // been fed the same input. The merged states count as the most recently
+    // fed ones if this chain has a state limit
+    //
+    // Fails, leaving this chain untouched, if the chains have different
+    // lengths or if only one of them is tokenized, since their states
+    // wouldn't line up
+    pub fn merge(&mut self, other: &Chain) -> io::Result<()> {
+        if self.chain_len != other.chain_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "merged chains must have the same chain length"));
+        }
+        if self.tokenized != other.tokenized {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "merged chains must both be tokenized or both not be"));
+        }
+
+        // Go through other's states in order so eviction doesn't depend on
+        // the HashMap's order
+        let mut states = other.values.iter().collect::<Vec<_>>();
+        states.sort_unstable_by(|a, b| a.0.cmp(b.0));
+        for (state, successors) in states {
+            if let (Some(recency), Some(state)) = (self.recency.as_mut(), state.0.as_ref()) {
+                recency.touch(state);
+            }
+            let merged = self.values.entry(state.clone()).or_insert_with(WeightedSet::new);
+            for (successor, weight) in &successors.values {
+                merged.insert(successor.clone(), *weight);
+                if let (Some(backoff), Some(state)) = (self.backoff.as_mut(), state.0.as_ref()) {
+                    index_backoff(backoff, self.tokenized, state, &successor.0, *weight);
+                }
+            }
+        }
+        self.evict();
+        Ok(())
+    }
+    // The number of characters (or words for a tokenized chain) in each
+    // state of the chain. Chains with different lengths can't be mixed, so
+    // this should be checked against the length that's expected after
+    // loading a chain
+    pub fn chain_len(&self) -> usize {
+        self.chain_len
+    }
+    pub fn is_tokenized(&self) -> bool {
+        self.tokenized
+    }
+    // The number of distinct states the chain has, including the starting one
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+    // Whether the chain has learnt nothing at all, in which case generating
+    // from it gives nothing back
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+    // The sum of the weights of every transition in the chain, which is how
+    // many transitions it has been fed, less anything forgotten since
+    pub fn total_samples(&self) -> usize {
+        self.values.values().map(|successors| successors.total_size).sum()
+    }
+    // Saving and loading do lots of small reads and writes, so files should
+    // be wrapped in a BufWriter/BufReader
+    pub fn save<W: Write>(&self, mut w: W) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        write_u64(&mut w, self.chain_len as u64)?;
+        w.write_all(&[self.tokenized as u8])?;
+        write_u64(&mut w, self.values.len() as u64)?;
+        // Write the states in order so the same chain is always saved as the
+        // same bytes, rather than depending on the HashMap's order
+        let mut states = self.values.iter().collect::<Vec<_>>();
+        states.sort_unstable_by(|a, b| a.0.cmp(b.0));
+        for (state, successors) in states {
+            write_state(&mut w, &state.0)?;
+            write_u64(&mut w, successors.values.len() as u64)?;
+            for (successor, weight) in &successors.values {
+                write_state(&mut w, &successor.0)?;
+                write_u64(&mut w, *weight as u64)?;
+            }
+        }
+        w.flush()
+    }
+    // Load a chain written by `save`, with the chain length it was saved with
+    pub fn load<R: Read>(mut r: R) -> io::Result<Self> {
+        let mut magic = [0; MAGIC.len()];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a saved chain"));
+        }
+
+        let chain_len = read_usize(&mut r)?;
+        if chain_len == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "saved chain has a chain length of 0"));
+        }
+        let mut tokenized = [0];
+        r.read_exact(&mut tokenized)?;
+        let mut chain = match tokenized[0] {
+            0 => Self::new(chain_len),
+            1 => Self::new_tokenized(chain_len),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid chain kind")),
+        };
+        for _ in 0..read_u64(&mut r)? {
+            let state = State(read_state(&mut r)?);
+            // Sampling panics on a set with no weight, and `save` never
+            // writes one, so these can only come from a corrupt file
+            let count = read_u64(&mut r)?;
+            if count == 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "saved state has no successors"));
+            }
+            let mut successors = WeightedSet::new();
+            for _ in 0..count {
+                let successor = State(read_state(&mut r)?);
+                let weight = read_usize(&mut r)?;
+                if weight == 0 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "saved successor has a weight of 0"));
+                }
+                if successors.total_size.checked_add(weight).is_none() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "saved weights are too large"));
+                }
+                successors.insert(successor, weight);
+            }
+            chain.values.insert(state, successors);
+        }
+        Ok(chain)
+    }
+    // Generate bytes from the chain. The output only depends on what the
+    // chain has been fed and on rng, so a seeded rng like
+    // `StdRng::seed_from_u64(seed)` gives the same output every time for the
+    // same seed and the same input
     pub fn generator<'a, R: Rng + 'a>(&'a self, mut rng: R) -> impl Iterator<Item=u8> + 'a {
-        let mut random_segment = move |base| self.values.get(&base).and_then(|set| rng.sample(set));
+        let mut random_segment = move |base| self.next_state(&base, &mut rng);
 
         let mut segments = iter::successors(random_segment(None), move |b| random_segment(Some(b.clone())));
 
@@ -100,8 +674,551 @@ impl Chain {
         segments.next()
             .into_iter()
             .flatten()
-            // For every other segment, just get the last character
-            .chain(segments.map(|b| b[b.len() - 1]))
+            // For every other segment, just get what it adds on to the end
+            .chain(segments.flat_map(move |b| {
+                let (separator, tail) = self.segment_tail(&b);
+                separator.into_iter().chain(tail)
+            }))
+    }
+    // Like `generator`, but if the chain reaches the end of its input before
+    // min_len bytes have been generated it starts again from the beginning
+    // on a new line, and it never generates more than max_len bytes. This
+    // means the output ends where the input ended as long as it can, rather
+    // than being cut off. When it is cut off it's before the character (or
+    // word) that wouldn't fit, never part way through one
+    pub fn generator_bounded<'a, R: Rng + 'a>(&'a self, mut rng: R, min_len: usize, max_len: usize) -> impl Iterator<Item=u8> + 'a {
+        let mut len = 0;
+        // The bytes from the current segment still to be handed out
+        let mut pending = (None, Bytes::new());
+        // The current state, or None once generation has finished
+        let mut state = Some(None);
+
+        iter::from_fn(move || loop {
+            if len >= max_len {
+                return None;
+            }
+            if let Some(b) = pending.0.take().or_else(|| {
+                let b = pending.1.first().copied()?;
+                pending.1.advance(1);
+                Some(b)
+            }) {
+                len += 1;
+                return Some(b);
+            }
+
+            let current = state.take()?;
+            match self.next_state(&current, &mut rng) {
+                Some(next) => {
+                    pending = match current {
+                        Some(_) => self.segment_tail(&next),
+                        None => (None, next.clone()),
+                    };
+                    state = Some(Some(next));
+                }
+                // Carry on from a shorter context if one goes anywhere, and
+                // otherwise start again. If we're already at the start then
+                // the chain is empty, so starting again would loop forever
+                None if len < min_len && current.is_some() => {
+                    match current.as_ref().and_then(|current| self.back_off(current, &mut rng, false)) {
+                        Some(next) => {
+                            pending = self.segment_tail(&next);
+                            state = Some(Some(next));
+                        }
+                        None => {
+                            pending = (Some(b'\n'), Bytes::new());
+                            state = Some(None);
+                        }
+                    }
+                }
+                None => return None,
+            }
+            if len + pending.0.iter().count() + pending.1.len() > max_len {
+                return None;
+            }
+        })
+    }
+    // Pick the state to go to after state, or None if generation should stop
+    fn next_state<R: Rng + ?Sized>(&self, state: &Option<Bytes>, rng: &mut R) -> Option<Bytes> {
+        match self.values.get(&State(state.clone())) {
+            Some(successors) if !successors.values.is_empty() => successors.sample(rng).0,
+            _ => self.back_off(state.as_ref()?, rng, true),
+        }
+    }
+    // Pick what comes after a state from the longest suffix of it that has
+    // somewhere to go, and build the state that gives. Unless can_end is set,
+    // only suffixes that have gone on to something other than the end count
+    fn back_off<R: Rng + ?Sized>(&self, state: &Bytes, rng: &mut R, can_end: bool) -> Option<Bytes> {
+        let backoff = self.backoff.as_ref()?;
+        let unit = suffixes(state, self.tokenized)
+            .filter_map(|suffix| backoff.get(&suffix))
+            .find_map(|units| units.sample_where(rng, |unit| can_end || unit.is_some()))??;
+
+        // The next state is this one with the unit on the end, less its first
+        // unit if it's already as long as a state can be
+        let units = if self.tokenized {
+            state.iter().filter(|&&c| c == b' ').count() + 1
+        } else {
+            state.iter().filter(|&&c| is_char_start(c)).count()
+        };
+        let kept = if units < self.chain_len {
+            state.clone()
+        } else {
+            suffixes(state, self.tokenized).next().unwrap_or_default()
+        };
+        let mut next = BytesMut::with_capacity(kept.len() + unit.len() + 1);
+        next.extend_from_slice(&kept);
+        if self.tokenized && !kept.is_empty() {
+            next.put_u8(b' ');
+        }
+        next.extend_from_slice(&unit);
+        Some(next.freeze())
+    }
+    // The bytes a segment adds on to the end of the output, which is just the
+    // last character, or a space and the last word for a tokenized chain
+    fn segment_tail(&self, segment: &Bytes) -> (Option<u8>, Bytes) {
+        if segment.is_empty() {
+            // Feeding never creates empty states, but a loaded chain could
+            // have them, so just skip over them
+            (None, Bytes::new())
+        } else if self.tokenized {
+            (Some(b' '), last_unit(segment, true))
+        } else {
+            (None, last_unit(segment, false))
+        }
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{
+        rngs::StdRng,
+        SeedableRng,
+    };
+
+    fn windows(input: &'static [u8], size: usize) -> Vec<Bytes> {
+        char_windows(&Bytes::from_static(input), size).collect()
+    }
+
+    fn successors<'a>(chain: &'a Chain, state: Option<&'static [u8]>) -> Vec<(Option<&'a [u8]>, usize)> {
+        chain.values[&State(state.map(Bytes::from_static))].values.iter()
+            .map(|(value, weight)| (value.0.as_deref(), *weight))
+            .collect()
+    }
+
+    #[test]
+    fn char_windows_longer_than_window() {
+        assert_eq!(windows(b"abcde", 3), [&b"abc"[..], b"bcd", b"cde"]);
+    }
+
+    #[test]
+    fn char_windows_equal_to_window() {
+        assert_eq!(windows(b"abc", 3), [&b"abc"[..]]);
+    }
+
+    #[test]
+    fn char_windows_shorter_than_window() {
+        assert_eq!(windows(b"ab", 3), [&b"ab"[..]]);
+        assert_eq!(windows(b"a", 8), [&b"a"[..]]);
+    }
+
+    #[test]
+    fn char_windows_keep_characters_whole() {
+        assert_eq!(windows("aé€b".as_bytes(), 2), ["aé".as_bytes(), "é€".as_bytes(), "€b".as_bytes()]);
+        assert_eq!(windows("é€".as_bytes(), 3), ["é€".as_bytes()]);
+    }
+
+    #[test]
+    fn feed_reachable_states() {
+        let mut chain = Chain::new(3);
+        chain.feed("abcde");
+
+        let mut states = chain.values.keys().map(|k| k.0.as_deref()).collect::<Vec<_>>();
+        states.sort();
+        assert_eq!(states, [None, Some(&b"abc"[..]), Some(b"bcd"), Some(b"cde")]);
+
+        assert_eq!(successors(&chain, None), [(Some(&b"abc"[..]), 1)]);
+        assert_eq!(successors(&chain, Some(b"abc")), [(Some(&b"bcd"[..]), 1)]);
+        assert_eq!(successors(&chain, Some(b"bcd")), [(Some(&b"cde"[..]), 1)]);
+        assert_eq!(successors(&chain, Some(b"cde")), [(None, 1)]);
+    }
+
+    #[test]
+    fn feed_shorter_than_chain_len() {
+        let mut chain = Chain::new(8);
+        chain.feed("hi");
+
+        assert_eq!(successors(&chain, None), [(Some(&b"hi"[..]), 1)]);
+        assert_eq!(successors(&chain, Some(b"hi")), [(None, 1)]);
+        assert_eq!(chain.generator(StdRng::seed_from_u64(0)).collect::<Vec<_>>(), b"hi");
+    }
+
+    #[test]
+    fn feed_accumulates_weights() {
+        let mut chain = Chain::new(2);
+        chain.feed("ab");
+        chain.feed("ac");
+        chain.feed_weighted("ab", 2);
+        chain.feed_weighted("ad", 0);
+
+        assert_eq!(successors(&chain, None), [(Some(&b"ab"[..]), 3), (Some(b"ac"), 1)]);
+        assert_eq!(chain.values[&State(None)].total_size, 4);
+    }
+
+    #[test]
+    fn chain_size() {
+        let mut chain = Chain::new(3);
+        assert!(chain.is_empty());
+        assert_eq!((chain.len(), chain.total_samples()), (0, 0));
+
+        chain.feed("abcde");
+        chain.feed_weighted("abc", 2);
+        assert!(!chain.is_empty());
+        assert_eq!((chain.len(), chain.total_samples()), (4, 8));
+    }
+
+    #[test]
+    fn generate_single_path() {
+        let mut chain = Chain::new(3);
+        chain.feed("abcdefg");
+
+        let output = chain.generator(StdRng::seed_from_u64(1)).collect::<Vec<_>>();
+        assert_eq!(output, b"abcdefg");
+    }
+
+    #[test]
+    fn generate_seeded() {
+        let mut chain = Chain::new(2);
+        chain.feed("the cat sat on the mat");
+        chain.feed("the dog sat on the log");
+        chain.feed("a cat and a dog");
+
+        let output = chain.generator(StdRng::seed_from_u64(42)).collect::<Vec<_>>();
+        assert_eq!(String::from_utf8(output).unwrap(), "the mat on the cat sat");
+    }
+
+    #[test]
+    fn generate_same_seed_same_output() {
+        // Chains are built separately so each gets its own HashMap (with
+        // its own random hasher), which mustn't change what's generated
+        let build = || {
+            let mut chain = Chain::new(3);
+            for line in &["the quick brown fox", "the quick red fox jumps", "a brown dog jumps over the fox"] {
+                chain.feed(*line);
+            }
+            chain
+        };
+        let (a, b) = (build(), build());
+
+        for seed in 0..32 {
+            assert_eq!(
+                a.generator(StdRng::seed_from_u64(seed)).collect::<Vec<_>>(),
+                b.generator(StdRng::seed_from_u64(seed)).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn generate_bounded() {
+        let mut chain = Chain::new(2);
+        chain.feed("abc");
+
+        let generate = |min_len, max_len| chain.generator_bounded(StdRng::seed_from_u64(0), min_len, max_len).collect::<Vec<_>>();
+        assert_eq!(generate(0, 100), b"abc");
+        assert_eq!(generate(0, 2), b"ab");
+        assert_eq!(generate(4, 100), b"abc\nabc");
+        assert_eq!(generate(4, 6), b"abc\nab");
+
+        // Generating the same thing as generator when unbounded
+        let mut chain = Chain::new(2);
+        chain.feed("the cat sat on the mat");
+        chain.feed("the dog sat on the log");
+        assert_eq!(
+            chain.generator_bounded(StdRng::seed_from_u64(42), 0, usize::MAX).collect::<Vec<_>>(),
+            chain.generator(StdRng::seed_from_u64(42)).collect::<Vec<_>>()
+        );
+
+        assert_eq!(Chain::new(2).generator_bounded(StdRng::seed_from_u64(0), 10, 100).count(), 0);
+
+        // Output that doesn't fit is cut off before the character or word
+        // that would go over, so it's still UTF-8
+        let mut chain = Chain::new(2);
+        chain.feed("aé€");
+        assert_eq!(chain.generator_bounded(StdRng::seed_from_u64(0), 0, 5).collect::<Vec<_>>(), "aé".as_bytes());
+        let mut chain = Chain::new_tokenized(1);
+        chain.feed("one two three");
+        assert_eq!(chain.generator_bounded(StdRng::seed_from_u64(0), 0, 12).collect::<Vec<_>>(), b"one two");
+    }
+
+    #[test]
+    fn generate_boundary_inputs() {
+        let mut chain = Chain::new(3);
+        chain.feed("");
+        assert_eq!(chain.generator(StdRng::seed_from_u64(0)).count(), 0);
+
+        chain.feed("a");
+        assert_eq!(chain.generator(StdRng::seed_from_u64(0)).collect::<Vec<_>>(), b"a");
+
+        let mut tokenized = Chain::new_tokenized(2);
+        tokenized.feed(" \n ");
+        assert_eq!(tokenized.generator(StdRng::seed_from_u64(0)).count(), 0);
+    }
+
+    #[test]
+    fn generate_skips_empty_states() {
+        let mut chain = Chain::new(2);
+        chain.values.entry(State(None)).or_insert_with(WeightedSet::new).insert(State(Some(Bytes::from_static(b"ab"))), 1);
+        chain.values.entry(State(Some(Bytes::from_static(b"ab")))).or_insert_with(WeightedSet::new).insert(State(Some(Bytes::new())), 1);
+        chain.values.entry(State(Some(Bytes::new()))).or_insert_with(WeightedSet::new).insert(State(None), 1);
+
+        assert_eq!(chain.generator(StdRng::seed_from_u64(0)).collect::<Vec<_>>(), b"ab");
+    }
+
+    #[test]
+    fn generate_backs_off() {
+        fn insert(chain: &mut Chain, state: Option<&'static str>, next: Option<&'static str>) {
+            let to_bytes = |s: &'static str| Bytes::from_static(s.as_bytes());
+            let (state, next) = (state.map(to_bytes), next.map(to_bytes));
+            chain.values.entry(State(state)).or_insert_with(WeightedSet::new).insert(State(next), 1);
+        }
+
+        // "bcd" has nowhere to go, but "cd" has been followed by "y"
+        let mut chain = Chain::new(3);
+        insert(&mut chain, None, Some("abc"));
+        insert(&mut chain, Some("abc"), Some("bcd"));
+        insert(&mut chain, Some("xcd"), Some("cdy"));
+        insert(&mut chain, Some("cdy"), None);
+        assert_eq!(chain.generator(StdRng::seed_from_u64(0)).collect::<Vec<_>>(), b"abcd");
+        let chain = chain.backoff();
+        assert_eq!(chain.generator(StdRng::seed_from_u64(0)).collect::<Vec<_>>(), b"abcdy");
+        assert_eq!(chain.generator_bounded(StdRng::seed_from_u64(0), 0, 100).collect::<Vec<_>>(), b"abcdy");
+
+        // The input ends after "bcd", but "cd" has gone on to "y" elsewhere,
+        // which is better than starting again to reach min_len
+        let mut chain = Chain::new(3);
+        insert(&mut chain, None, Some("abc"));
+        insert(&mut chain, Some("abc"), Some("bcd"));
+        insert(&mut chain, Some("bcd"), None);
+        insert(&mut chain, Some("xcd"), Some("cdy"));
+        insert(&mut chain, Some("cdy"), None);
+        assert_eq!(chain.generator_bounded(StdRng::seed_from_u64(0), 5, 100).collect::<Vec<_>>(), b"abcd\nabcd");
+        let chain = chain.backoff();
+        assert_eq!(chain.generator_bounded(StdRng::seed_from_u64(0), 5, 100).collect::<Vec<_>>(), b"abcdy");
+
+        let mut chain = Chain::new_tokenized(2).backoff();
+        chain.feed("x c d");
+        insert(&mut chain, None, Some("a b"));
+        insert(&mut chain, Some("a b"), Some("b c"));
+        chain.values.remove(&State(None));
+        insert(&mut chain, None, Some("a b"));
+        assert_eq!(chain.generator(StdRng::seed_from_u64(0)).collect::<Vec<_>>(), b"a b c d");
+    }
+
+    #[test]
+    fn evicts_least_recently_fed() {
+        let mut chain = Chain::with_capacity(2, 4);
+        chain.feed("abc");
+        chain.feed("xyz");
+        chain.feed("bc");
+
+        // "ab" was fed the longest ago, so it's gone along with the
+        // transition to it from the start
+        let mut states = chain.values.keys().map(|k| k.0.as_deref()).collect::<Vec<_>>();
+        states.sort();
+        assert_eq!(states, [None, Some(&b"bc"[..]), Some(b"xy"), Some(b"yz")]);
+        assert_eq!(successors(&chain, None), [(Some(&b"bc"[..]), 1), (Some(b"xy"), 1)]);
+        assert_eq!(chain.values[&State(None)].total_size, 2);
+    }
+
+    #[test]
+    fn evicting_keeps_backoff_in_step() {
+        type Index = Vec<(Bytes, Vec<(Option<Bytes>, usize)>)>;
+        fn index(chain: &Chain) -> Index {
+            let mut index = chain.backoff.as_ref().unwrap().iter()
+                .map(|(suffix, units)| {
+                    let mut units = units.values.iter().map(|(unit, weight)| (unit.clone(), *weight)).collect::<Vec<_>>();
+                    units.sort();
+                    (suffix.clone(), units)
+                })
+                .collect::<Vec<_>>();
+            index.sort();
+            index
+        }
+        let mut chain = Chain::with_capacity(3, 3).backoff();
+        chain.feed("abcd");
+        chain.feed("xbcy");
+        chain.feed("bcz");
+        chain.feed("abcd");
+        let evicted = index(&chain);
+        chain.rebuild_backoff();
+        assert_eq!(evicted, index(&chain));
+    }
+
+    #[test]
+    fn decay_scales_weights() {
+        let mut chain = Chain::new(2);
+        chain.feed_weighted("ab", 4);
+        chain.feed("ac");
+
+        chain.decay(0.5);
+        // "ac" only had a weight of 1, so it's gone from the start as well as
+        // being gone as a state
+        assert_eq!(successors(&chain, None), [(Some(&b"ab"[..]), 2)]);
+        assert_eq!(chain.values[&State(None)].total_size, 2);
+        assert!(!chain.values.contains_key(&State(Some(Bytes::from_static(b"ac")))));
+
+        chain.decay(0.0);
+        assert!(chain.values.is_empty());
+        assert_eq!(chain.generator(StdRng::seed_from_u64(0)).count(), 0);
+    }
+
+    #[test]
+    fn merge_sums_weights() {
+        let mut a = Chain::new(2);
+        a.feed("abc");
+        let mut b = Chain::new(2);
+        b.feed("abd");
+        b.feed("abc");
+
+        a.merge(&b).unwrap();
+
+        let mut fed = Chain::new(2);
+        fed.feed("abc");
+        fed.feed("abd");
+        fed.feed("abc");
+        for state in [None, Some(&b"ab"[..]), Some(b"bc"), Some(b"bd")] {
+            assert_eq!(successors(&a, state), successors(&fed, state));
+        }
+        assert_eq!(a.values[&State(Some(Bytes::from_static(b"ab")))].total_size, 3);
+    }
+
+    #[test]
+    fn merge_mismatched_chains() {
+        let mut chain = Chain::new(2);
+        chain.feed("abc");
+        assert_eq!(chain.merge(&Chain::new(3)).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(chain.merge(&Chain::new_tokenized(2)).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(chain.len(), 3);
+    }
+
+    #[test]
+    fn load_keeps_chain_len() {
+        let mut saved = Vec::new();
+        Chain::new(5).save(&mut saved).unwrap();
+
+        assert_eq!(Chain::load(&saved[..]).unwrap().chain_len(), 5);
+    }
+
+    #[test]
+    fn feed_normalized() {
+        let mut chain = Chain::new_with_normalizer(2, lowercase);
+        chain.feed("Hi");
+        chain.feed("hI");
+        assert_eq!(successors(&chain, None), [(Some(&b"hi"[..]), 2)]);
+
+        let mut chain = Chain::new_tokenized(1).normalizer(|bytes: &[u8]| {
+            bytes.iter().copied().filter(|b| !b.is_ascii_punctuation()).collect::<Vec<_>>().into()
+        });
+        chain.feed("cat, sat!");
+        assert_eq!(chain.generator(StdRng::seed_from_u64(0)).collect::<Vec<_>>(), b"cat sat");
+        assert_eq!(lowercase("ÄB".as_bytes()), "äb".as_bytes());
+        assert_eq!(lowercase(b"AB\xff"), &b"ab\xff"[..]);
+    }
+
+    #[test]
+    fn word_windows_split_on_words() {
+        let bytes = normalize_whitespace(Bytes::from_static(b" the  cat\tsat on\n"));
+        assert_eq!(bytes, &b"the cat sat on"[..]);
+        assert_eq!(word_windows(&bytes, 2).collect::<Vec<_>>(), [&b"the cat"[..], b"cat sat", b"sat on"]);
+        assert_eq!(word_windows(&bytes, 8).collect::<Vec<_>>(), [&b"the cat sat on"[..]]);
+    }
+
+    #[test]
+    fn generate_tokenized() {
+        let mut chain = Chain::new_tokenized(1);
+        chain.feed("the cat  sat");
+
+        assert_eq!(successors(&chain, Some(b"cat")), [(Some(&b"sat"[..]), 1)]);
+        let output = chain.generator(StdRng::seed_from_u64(0)).collect::<Vec<_>>();
+        assert_eq!(output, b"the cat sat");
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let mut chain = Chain::new(2);
+        chain.feed("the cat sat on the mat");
+        chain.feed_weighted("the dog sat on the log", 3);
+
+        let mut saved = Vec::new();
+        chain.save(&mut saved).unwrap();
+        let loaded = Chain::load(&saved[..]).unwrap();
+
+        assert_eq!(loaded.values.len(), chain.values.len());
+        for (state, successors) in &chain.values {
+            assert_eq!(loaded.values[state].values, successors.values);
+            assert_eq!(loaded.values[state].total_size, successors.total_size);
+        }
+        assert_eq!(
+            loaded.generator(StdRng::seed_from_u64(7)).collect::<Vec<_>>(),
+            chain.generator(StdRng::seed_from_u64(7)).collect::<Vec<_>>()
+        );
+
+        // Saving the loaded chain should give back exactly the same bytes
+        let mut resaved = Vec::new();
+        loaded.save(&mut resaved).unwrap();
+        assert_eq!(resaved, saved);
+    }
+
+    #[test]
+    fn save_load_tokenized() {
+        let mut chain = Chain::new_tokenized(2);
+        chain.feed("one two three");
+
+        let mut saved = Vec::new();
+        chain.save(&mut saved).unwrap();
+        let loaded = Chain::load(&saved[..]).unwrap();
+
+        assert!(loaded.is_tokenized());
+        assert_eq!(loaded.generator(StdRng::seed_from_u64(0)).collect::<Vec<_>>(), b"one two three");
+    }
+
+    #[test]
+    fn load_truncated() {
+        let mut chain = Chain::new(3);
+        chain.feed("abcdef");
+        let mut saved = Vec::new();
+        chain.save(&mut saved).unwrap();
+
+        for len in 0..saved.len() {
+            assert!(Chain::load(&saved[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn load_rejects_other_files() {
+        assert_eq!(Chain::load(&b"not a chain at all"[..]).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(Chain::load(&b"MRKV"[..]).err().unwrap().kind(), io::ErrorKind::UnexpectedEof);
+
+        // Save doesn't look at the totals, so the weights can be set to
+        // anything here without upsetting it
+        let corrupt = |f: &dyn Fn(&mut Chain)| {
+            let mut chain = Chain::new(3);
+            chain.feed("abcdef");
+            f(&mut chain);
+            let mut saved = Vec::new();
+            chain.save(&mut saved).unwrap();
+            Chain::load(&saved[..]).err().unwrap().kind()
+        };
+        assert_eq!(corrupt(&|chain| {
+            chain.values.get_mut(&State(None)).unwrap().values.insert(State(Some(Bytes::from_static(b"xyz"))), 0);
+        }), io::ErrorKind::InvalidData);
+        assert_eq!(corrupt(&|chain| {
+            chain.values.insert(State(Some(Bytes::from_static(b"xyz"))), WeightedSet::new());
+        }), io::ErrorKind::InvalidData);
+        assert_eq!(corrupt(&|chain| {
+            let successors = &mut chain.values.get_mut(&State(None)).unwrap().values;
+            successors.insert(State(Some(Bytes::from_static(b"xyz"))), usize::MAX);
+        }), io::ErrorKind::InvalidData);
+    }
+}
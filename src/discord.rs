@@ -8,9 +8,17 @@ use crate::{
     ws,
 };
 use futures::{
-    future::FutureExt,
+    future::{
+        self,
+        BoxFuture,
+        FutureExt,
+    },
     pin_mut,
-    stream::StreamExt,
+    stream::{
+        self,
+        Stream,
+        StreamExt,
+    },
 };
 use hyper::{
     client::{
@@ -31,14 +39,20 @@ use tokio::{
         split,
         AsyncRead,
         AsyncWrite,
+        AsyncWriteExt,
         ReadHalf,
         WriteHalf
     },
     net::TcpStream,
+    sync::Mutex,
+    task::JoinHandle,
     time::{
         sleep,
-        Sleep,
+        sleep_until,
         interval,
+        interval_at,
+        timeout,
+        Instant,
         Interval,
     },
 };
@@ -47,40 +61,291 @@ use std::{
     cmp,
     future::Future,
     marker::Unpin,
+    mem,
+    ops::Deref,
+    pin::Pin,
     str::{
         self,
         FromStr,
     },
+    fmt,
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+    },
+    task::{
+        self,
+        Context,
+    },
     time::Duration,
 };
+use rand::Rng;
+use serde_json::value::RawValue;
 use unicase::UniCase;
 
+mod embed;
+mod event;
+mod json;
 mod model;
+mod poll;
+mod presence;
+
+pub use self::embed::Embed;
+pub use self::event::{
+    Event,
+    GuildCreate,
+    GuildMembersChunk,
+    PollVote,
+    Reaction,
+};
+pub use self::poll::Poll;
+pub use self::presence::Activity;
+
+// The client for the REST API, which can optionally wait and try again when
+// Discord rate limits a request
+#[derive(Clone, Debug)]
+struct HttpsClient {
+    client: Client<HttpsConnector<HttpConnector>>,
+    max_retries: u32,
+}
+impl HttpsClient {
+    fn new(https: HttpsConnector<HttpConnector>) -> Self {
+        Self {
+            client: Client::builder().build(https),
+            max_retries: 0,
+        }
+    }
 
-type HttpsClient = Client<HttpsConnector<HttpConnector>>;
+    async fn request(&self, req: Request<Body>) -> Result<Response<Body>, Error> {
+        if self.max_retries == 0 {
+            return Ok(self.client.request(req).await?);
+        }
 
-#[derive(Debug)]
+        // The body gets used up sending the request, so it has to be kept
+        // around to send it again
+        let (parts, body) = req.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        let mut retries = 0;
+        loop {
+            let mut builder = Request::builder()
+                .method(parts.method.clone())
+                .uri(parts.uri.clone())
+                .version(parts.version);
+            if let Some(headers) = builder.headers_mut() {
+                *headers = parts.headers.clone();
+            }
+            let res = self.client.request(builder.body(Body::from(body.clone()))?).await?;
+            if res.status() != http::StatusCode::TOO_MANY_REQUESTS || retries == self.max_retries {
+                return Ok(res);
+            }
+
+            // Reading how long to wait might mean reading the body, so the
+            // response has to be put back together if we're not retrying
+            let (res_parts, res_body) = res.into_parts();
+            let res_body = hyper::body::to_bytes(res_body).await?;
+            match api_error(res_parts.status, &res_parts.headers, res_body.clone()) {
+                Error::RateLimited { retry_after, .. } => sleep(retry_after).await,
+                _ => return Ok(Response::from_parts(res_parts, Body::from(res_body))),
+            }
+            retries += 1;
+        }
+    }
+
+    async fn get_success_response(&self, req: Request<Body>) -> Result<Response<Body>, Error> {
+        let res = self.request(req).await?;
+        let status = res.status();
+        if !status.is_success() {
+            let (parts, mut res_body) = res.into_parts();
+            let length = header::<usize>(&parts.headers, "content-length").unwrap_or(0);
+
+            let mut buffer = BytesMut::with_capacity(length);
+            while let Some(chunk) = res_body.next().await {
+                let chunk = chunk?;
+                buffer.reserve(chunk.len());
+                buffer.extend_from_slice(&chunk);
+            }
+            Err(api_error(status, &parts.headers, buffer.freeze()))
+        } else {
+            Ok(res)
+        }
+    }
+    // Stream the elements of a JSON array response to f as they arrive,
+    // rather than holding the whole body in memory before parsing it. This is
+    // for endpoints which can return very long lists. The response's headers
+    // are given back once the whole body has been read
+    async fn for_each_array_element<F>(&self, req: Request<Body>, mut f: F) -> Result<http::HeaderMap, Error>
+        where F: FnMut(Bytes) -> Result<(), Error>
+    {
+        let (parts, mut res_body) = self.get_success_response(req).await?.into_parts();
+
+        let mut splitter = json::ArraySplitter::new();
+        while let Some(chunk) = res_body.next().await {
+            splitter.feed(chunk?, &mut f)?;
+        }
+        splitter.finish::<Error>()?;
+        Ok(parts.headers)
+    }
+    async fn get_success_response_bytes(&self, req: Request<Body>) -> Result<Bytes, Error> {
+        let res = self.request(req).await?;
+        let status = res.status();
+        let (parts, mut res_body) = res.into_parts();
+        let length = header::<usize>(&parts.headers, "content-length").unwrap_or(0);
+
+        let mut buffer = BytesMut::with_capacity(length);
+        while let Some(chunk) = res_body.next().await {
+            let chunk = chunk?;
+            buffer.reserve(chunk.len());
+            buffer.extend_from_slice(&chunk);
+        }
+        let bytes = buffer.freeze();
+
+        if !status.is_success() {
+            Err(api_error(status, &parts.headers, bytes))
+        } else {
+            Ok(bytes)
+        }
+    }
+}
+
+// Anything a gateway connection can run over. This is normally the TLS
+// stream we get from upgrading the HTTP connection, but it's boxed up so the
+// gateway can be swapped out for something else, e.g. in tests
+trait GatewayStream: AsyncRead + AsyncWrite + Send + Unpin + fmt::Debug {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin + fmt::Debug> GatewayStream for T {}
+type BoxedStream = Box<dyn GatewayStream>;
+type GatewayConnection = (BoxedStream, ws::Extensions);
+
+// Opens a new websocket connection to the gateway, given whether we're going
+// to identify (rather than resume) on it, along with the websocket extensions
+// agreed on for it. This is kept around so reconnecting can open a new
+// connection the same way the original one was opened
+struct GatewayConnector(Box<dyn Fn(bool) -> BoxFuture<'static, Result<GatewayConnection, Error>> + Send + Sync>);
+impl GatewayConnector {
+    fn https(client: HttpsClient, auth_header: http::HeaderValue, options: &ConnectOptions) -> Self {
+        let api_base = options.api_base();
+        let api_version = options.version();
+        GatewayConnector(Box::new(move |identify| {
+            let client = client.clone();
+            let auth_header = auth_header.clone();
+            let api_base = api_base.clone();
+            async move {
+                let gateway_url_bytes = Discord::bot_gateway_url(&client, auth_header.clone(), &api_base, identify).await?;
+                let parameters = format!("?v={}&encoding=json", api_version);
+                let mut urlbuf = BytesMut::from(&*gateway_url_bytes);
+                urlbuf.reserve(parameters.len());
+                urlbuf.extend_from_slice(parameters.as_bytes());
+
+                let (upgrade, extensions) = Discord::connect_gateway(&client, auth_header, urlbuf.freeze()).await?;
+                let stream = upgrade.downcast::<TlsStream<TcpStream>>().unwrap();
+                // hyper may have read past the end of the upgrade response
+                // into the first websocket frame, so those bytes need to be
+                // read before anything else from the stream
+                Ok((Box::new(ws::Prebuffered::new(stream.read_buf, stream.io)) as BoxedStream, extensions))
+            }.boxed()
+        }))
+    }
+    async fn connect(&self, identify: bool) -> Result<GatewayConnection, Error> {
+        (self.0)(identify).await
+    }
+}
+impl fmt::Debug for GatewayConnector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad("GatewayConnector")
+    }
+}
+
+// The maximum number of characters Discord allows in a message's content
+pub const MAX_MESSAGE_LENGTH: usize = 2000;
+// The maximum number of embeds Discord allows on a message
+pub const MAX_EMBEDS: usize = 10;
+
+// Whether the gateway closing with this code means connecting again won't
+// help, e.g. because the token is wrong or we asked for intents we aren't
+// allowed. These are given back as `Error::Gateway` rather than reconnecting
+pub fn is_fatal_close_code(code: u16) -> bool {
+    matches!(code,
+        4004 // Authentication failed
+        | 4010 // Invalid shard
+        | 4011 // Sharding required
+        | 4012 // Invalid API version
+        | 4013 // Invalid intents
+        | 4014 // Disallowed intents
+    )
+}
+
+// Milliseconds since the Unix epoch of the first second of 2015, which is
+// where the timestamps in snowflakes count from
+const DISCORD_EPOCH: i64 = 1420070400000;
+
+// When something with the given id (a message, user, channel, ...) was
+// created, in milliseconds since the Unix epoch. Every id is a snowflake with
+// its creation time in the top 42 bits, so this doesn't need an API call
+pub fn snowflake_timestamp(id: &str) -> Option<i64> {
+    let id = id.parse::<u64>().ok()?;
+    Some((id >> 22) as i64 + DISCORD_EPOCH)
+}
+
+// Cloning a message only clones references to the buffer it was parsed from.
+// Messages are only equal if everything about them is, including their raw
+// JSON, so compare `message()` to see if just the content is the same
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Message {
     channel_id: Bytes,
     guild_id: Option<Bytes>,
     content: Bytes,
     author_id: Bytes,
+    author_username: Option<Bytes>,
+    author_discriminator: Option<Bytes>,
     message_id: Bytes,
+    webhook_id: Option<Bytes>,
+    timestamp: Bytes,
+    raw_json: Bytes,
+    mentioned_ids: Vec<Bytes>,
     mentioned: bool,
+    mentions_everyone: bool,
     is_me: bool,
+    is_reply: bool,
+    attachment_count: usize,
 }
 impl Message {
-    fn from_message_received(bytes: &Bytes, msg: model::MessageReceived, uid: &[u8]) -> Self {
-        Self {
-            is_me: msg.author.id.as_bytes() == uid,
+    // Message type for replies, older gateway versions still send replies as
+    // a default (0) message with a message reference instead
+    const REPLY_TYPE: u8 = 19;
+
+    // Parse a message from the raw JSON of the message object, raw must be
+    // borrowed from bytes. Partial messages (from message updates) which are
+    // missing their author or content give None
+    fn from_raw(bytes: &Bytes, raw: &RawValue, uid: &[u8]) -> Result<Option<Self>, Error> {
+        let msg = serde_json::from_str::<model::MessageReceived>(raw.get())?;
+        Ok(Self::from_message_received(bytes, bytes.slice_ref(raw.get().as_bytes()), msg, uid))
+    }
+    fn from_message_received(bytes: &Bytes, raw_json: Bytes, msg: model::MessageReceived, uid: &[u8]) -> Option<Self> {
+        let author = msg.author?;
+        let content = msg.content?;
+        let timestamp = msg.timestamp?;
+        Some(Self {
+            raw_json,
+
+            is_me: author.id.as_bytes() == uid,
             mentioned: msg.mentions.iter().any(|u| u.id.as_bytes() == uid),
+            mentions_everyone: msg.mention_everyone,
+            mentioned_ids: msg.mentions.into_iter().map(|u| model::bytes_from_cow(bytes, u.id)).collect(),
+            is_reply: msg.kind == Self::REPLY_TYPE || (msg.kind == 0 && msg.message_reference.is_some()),
+            attachment_count: msg.attachments.len(),
 
             message_id: model::bytes_from_cow(bytes, msg.id),
             channel_id: model::bytes_from_cow(bytes, msg.channel_id),
             guild_id: msg.guild_id.map(|c| model::bytes_from_cow(bytes, c)),
-            author_id: model::bytes_from_cow(bytes, msg.author.id),
-            content: model::bytes_from_cow(bytes, msg.content),
-        }
+            author_id: model::bytes_from_cow(bytes, author.id),
+            author_username: author.username.map(|u| model::bytes_from_cow(bytes, u)),
+            author_discriminator: author.discriminator.map(|d| model::bytes_from_cow(bytes, d)),
+            webhook_id: msg.webhook_id.map(|w| model::bytes_from_cow(bytes, w)),
+            content: model::bytes_from_cow(bytes, content),
+            timestamp: model::bytes_from_cow(bytes, timestamp),
+        })
     }
     pub fn channel_id(&self) -> &str {
         unsafe { str::from_utf8_unchecked(&self.channel_id) }
@@ -94,6 +359,12 @@ impl Message {
     pub fn guild_id_buf(&self) -> Option<&Bytes> {
         self.guild_id.as_ref()
     }
+    // Whether this message was sent in a DM rather than in a guild. Discord
+    // leaves the guild id out of messages fetched over REST, so this is only
+    // right for messages we received from the gateway
+    pub fn is_dm(&self) -> bool {
+        self.guild_id.is_none()
+    }
     pub fn message_id(&self) -> &str {
         unsafe { str::from_utf8_unchecked(&self.message_id) }
     }
@@ -106,76 +377,597 @@ impl Message {
     pub fn message_buf(&self) -> &Bytes {
         &self.content
     }
+    // The content split on newlines, borrowing from the message
+    pub fn message_lines(&self) -> impl Iterator<Item=&str> {
+        self.message().split('\n')
+    }
+    pub fn message_bytes_lines(&self) -> impl Iterator<Item=&[u8]> {
+        self.content.split(|&b| b == b'\n')
+    }
     pub fn author_id(&self) -> &str {
         unsafe { str::from_utf8_unchecked(&self.author_id) }
     }
     pub fn author_id_buf(&self) -> &Bytes {
         &self.author_id
     }
+    pub fn author_username(&self) -> Option<&str> {
+        unsafe { self.author_username.as_ref().map(|b| str::from_utf8_unchecked(b)) }
+    }
+    pub fn author_username_buf(&self) -> Option<&Bytes> {
+        self.author_username.as_ref()
+    }
+    // Users who have moved to unique usernames have a discriminator of "0",
+    // and webhooks may not have one at all
+    pub fn author_discriminator(&self) -> Option<&str> {
+        unsafe { self.author_discriminator.as_ref().map(|b| str::from_utf8_unchecked(b)) }
+    }
+    pub fn author_discriminator_buf(&self) -> Option<&Bytes> {
+        self.author_discriminator.as_ref()
+    }
+    // The id of the webhook that sent this message, if it was sent by one. The
+    // author of a webhook message isn't a real user
+    pub fn webhook_id(&self) -> Option<&str> {
+        unsafe { self.webhook_id.as_ref().map(|b| str::from_utf8_unchecked(b)) }
+    }
+    pub fn webhook_id_buf(&self) -> Option<&Bytes> {
+        self.webhook_id.as_ref()
+    }
+    pub fn is_webhook(&self) -> bool {
+        self.webhook_id.is_some()
+    }
+    // When the message was sent, as the ISO 8601 string Discord gave us
+    pub fn timestamp(&self) -> &str {
+        unsafe { str::from_utf8_unchecked(&self.timestamp) }
+    }
+    pub fn timestamp_buf(&self) -> &Bytes {
+        &self.timestamp
+    }
+    // When the message was sent, in milliseconds since the Unix epoch
+    pub fn timestamp_unix(&self) -> Option<i64> {
+        parse_timestamp(self.timestamp())
+    }
+    // The JSON message object this message was parsed from, exactly as it was
+    // received from Discord. This is a slice of the buffer the message was
+    // read into so doesn't require a copy
+    pub fn raw_json(&self) -> &str {
+        unsafe { str::from_utf8_unchecked(&self.raw_json) }
+    }
+    pub fn raw_json_buf(&self) -> &Bytes {
+        &self.raw_json
+    }
+    // Whether we were one of the users mentioned in this message
     pub fn mentioned(&self) -> bool {
         self.mentioned
     }
+    // The ids of every user mentioned in this message, in the order Discord
+    // sent them
+    pub fn mentioned_ids(&self) -> impl Iterator<Item=&str> {
+        self.mentioned_ids.iter().map(|b| unsafe { str::from_utf8_unchecked(b) })
+    }
+    pub fn mentioned_ids_buf(&self) -> &[Bytes] {
+        &self.mentioned_ids
+    }
+    // Whether this message pinged @everyone or @here. Discord only sets this
+    // if the author was actually allowed to, so it's more reliable than
+    // looking for the text in the content
+    pub fn mentions_everyone(&self) -> bool {
+        self.mentions_everyone
+    }
     pub fn is_me(&self) -> bool {
         self.is_me
     }
+    pub fn is_reply(&self) -> bool {
+        self.is_reply
+    }
+    pub fn attachment_count(&self) -> usize {
+        self.attachment_count
+    }
+    pub fn has_attachments(&self) -> bool {
+        self.attachment_count > 0
+    }
+}
+
+#[derive(Debug)]
+pub struct User {
+    id: Bytes,
+    username: Option<Bytes>,
+    discriminator: Option<Bytes>,
+    avatar: Option<Bytes>,
+}
+impl User {
+    fn from_user(bytes: &Bytes, user: model::User) -> Self {
+        Self {
+            id: model::bytes_from_cow(bytes, user.id),
+            username: user.username.map(|u| model::bytes_from_cow(bytes, u)),
+            discriminator: user.discriminator.map(|d| model::bytes_from_cow(bytes, d)),
+            avatar: user.avatar.map(|a| model::bytes_from_cow(bytes, a)),
+        }
+    }
+    pub fn id(&self) -> &str {
+        unsafe { str::from_utf8_unchecked(&self.id) }
+    }
+    pub fn id_buf(&self) -> &Bytes {
+        &self.id
+    }
+    pub fn username(&self) -> Option<&str> {
+        unsafe { self.username.as_ref().map(|b| str::from_utf8_unchecked(b)) }
+    }
+    pub fn username_buf(&self) -> Option<&Bytes> {
+        self.username.as_ref()
+    }
+    pub fn discriminator(&self) -> Option<&str> {
+        unsafe { self.discriminator.as_ref().map(|b| str::from_utf8_unchecked(b)) }
+    }
+    pub fn discriminator_buf(&self) -> Option<&Bytes> {
+        self.discriminator.as_ref()
+    }
+    // The hash of the user's avatar, or None if they're using a default one
+    pub fn avatar(&self) -> Option<&str> {
+        unsafe { self.avatar.as_ref().map(|b| str::from_utf8_unchecked(b)) }
+    }
+    pub fn avatar_buf(&self) -> Option<&Bytes> {
+        self.avatar.as_ref()
+    }
+}
+
+#[derive(Debug)]
+pub struct Invite {
+    code: Bytes,
+    url: String,
+}
+impl Invite {
+    const URL_PREFIX: &'static str = "https://discord.gg/";
+
+    fn from_invite_response(bytes: &Bytes, invite: model::InviteResponse) -> Self {
+        let code = model::bytes_from_cow(bytes, invite.code);
+        // safety: code always comes from a Cow<str> so will always be UTF-8
+        let url = format!("{}{}", Self::URL_PREFIX, unsafe { str::from_utf8_unchecked(&code) });
+        Self { code, url }
+    }
+    pub fn code(&self) -> &str {
+        unsafe { str::from_utf8_unchecked(&self.code) }
+    }
+    pub fn code_buf(&self) -> &Bytes {
+        &self.code
+    }
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+}
+
+#[derive(Debug)]
+pub struct Channel {
+    id: Bytes,
+    kind: u8,
+    guild_id: Option<Bytes>,
+    name: Option<Bytes>,
+}
+impl Channel {
+    fn from_channel(bytes: &Bytes, channel: model::Channel) -> Self {
+        Self {
+            kind: channel.kind,
+
+            id: model::bytes_from_cow(bytes, channel.id),
+            guild_id: channel.guild_id.map(|g| model::bytes_from_cow(bytes, g)),
+            name: channel.name.map(|n| model::bytes_from_cow(bytes, n)),
+        }
+    }
+    pub fn id(&self) -> &str {
+        unsafe { str::from_utf8_unchecked(&self.id) }
+    }
+    pub fn id_buf(&self) -> &Bytes {
+        &self.id
+    }
+    // The channel's type as Discord numbers them, e.g. 0 for a guild text
+    // channel, 1 for a DM or 11 for a public thread
+    pub fn kind(&self) -> u8 {
+        self.kind
+    }
+    pub fn guild_id(&self) -> Option<&str> {
+        unsafe { self.guild_id.as_ref().map(|b| str::from_utf8_unchecked(b)) }
+    }
+    pub fn guild_id_buf(&self) -> Option<&Bytes> {
+        self.guild_id.as_ref()
+    }
+    pub fn name(&self) -> Option<&str> {
+        unsafe { self.name.as_ref().map(|b| str::from_utf8_unchecked(b)) }
+    }
+    pub fn name_buf(&self) -> Option<&Bytes> {
+        self.name.as_ref()
+    }
+}
+
+#[derive(Debug)]
+pub struct Guild {
+    id: Bytes,
+    name: Bytes,
+}
+impl Guild {
+    fn from_guild(bytes: &Bytes, guild: model::Guild) -> Self {
+        Self {
+            id: model::bytes_from_cow(bytes, guild.id),
+            name: model::bytes_from_cow(bytes, guild.name),
+        }
+    }
+    pub fn id(&self) -> &str {
+        unsafe { str::from_utf8_unchecked(&self.id) }
+    }
+    pub fn id_buf(&self) -> &Bytes {
+        &self.id
+    }
+    pub fn name(&self) -> &str {
+        unsafe { str::from_utf8_unchecked(&self.name) }
+    }
+    pub fn name_buf(&self) -> &Bytes {
+        &self.name
+    }
+}
+
+#[derive(Debug)]
+pub struct ThreadMember {
+    user_id: Bytes,
+    join_timestamp: Bytes,
+    flags: u64,
+}
+impl ThreadMember {
+    fn from_thread_member(bytes: &Bytes, member: model::ThreadMember) -> Self {
+        Self {
+            flags: member.flags,
+
+            user_id: model::bytes_from_cow(bytes, member.user_id),
+            join_timestamp: model::bytes_from_cow(bytes, member.join_timestamp),
+        }
+    }
+    pub fn user_id(&self) -> &str {
+        unsafe { str::from_utf8_unchecked(&self.user_id) }
+    }
+    pub fn user_id_buf(&self) -> &Bytes {
+        &self.user_id
+    }
+    // When the user joined the thread, as an ISO 8601 timestamp
+    pub fn join_timestamp(&self) -> &str {
+        unsafe { str::from_utf8_unchecked(&self.join_timestamp) }
+    }
+    pub fn join_timestamp_buf(&self) -> &Bytes {
+        &self.join_timestamp
+    }
+    pub fn flags(&self) -> u64 {
+        self.flags
+    }
 }
 
+// A stream of a channel's messages, fetched a page at a time
 pub struct ChannelMessages {
+    // Taken while the next page is being fetched, and gone once there are no
+    // more messages
+    pages:    Option<Pages>,
+    fetching: Option<FetchingPage>,
+}
+type FetchingPage = BoxFuture<'static, (Pages, Result<Option<Message>, Error>)>;
+struct Pages {
     client:       HttpsClient,
     auth_header:  http::HeaderValue,
     user_id:      Bytes,
     base_uri:     String,
     next_res:     Option<std::vec::IntoIter<Message>>,
-    next_msg_id:  Option<String>,
+    direction:    Direction,
     remaining:    usize,
-    rate_limiter: Option<Sleep>,
+    // When we're allowed to ask for the next page
+    rate_limiter: Option<Instant>,
+}
+// Which way to page through a channel's messages, and the message to start
+// from
+#[derive(Clone, Debug)]
+pub enum Direction {
+    // Older messages, newest first, starting from the newest message in the
+    // channel if there's no id
+    Before(Option<String>),
+    // Newer messages, oldest first, for following a channel onwards from a
+    // message
+    After(String),
+    // The messages either side of a message, which is only a single page so
+    // at most 100 messages
+    Around(String),
+}
+impl Direction {
+    fn query(&self) -> Option<(&'static str, &str)> {
+        match self {
+            Direction::Before(None) => None,
+            Direction::Before(Some(id)) => Some(("before", id)),
+            Direction::After(id) => Some(("after", id)),
+            Direction::Around(id) => Some(("around", id)),
+        }
+    }
+    // Move on past a message we've handed out
+    fn advance(&mut self, msg_id: &str) {
+        match self {
+            Direction::Before(id) => *id = Some(msg_id.to_string()),
+            Direction::After(id) => *id = msg_id.to_string(),
+            Direction::Around(_) => (),
+        }
+    }
+}
+
+// How long to wait before making another request, going by the rate limit
+// headers on a response. We only need to wait once we've used up all of the
+// requests in the current window
+fn rate_limit_delay(headers: &http::HeaderMap) -> Option<Duration> {
+    if header::<u64>(headers, "x-ratelimit-remaining")? > 0 {
+        return None;
+    }
+    header::<f64>(headers, "x-ratelimit-reset-after").and_then(secs_to_duration)
+}
+
+// Parse an ISO 8601 timestamp like Discord sends, e.g.
+// "2015-12-31T23:59:59.999000+00:00", into milliseconds since the Unix epoch
+fn parse_timestamp(s: &str) -> Option<i64> {
+    fn num(s: &str, range: std::ops::Range<usize>) -> Option<i64> {
+        let digits = s.get(range)?;
+        if !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        digits.parse().ok()
+    }
+
+    let bytes = s.as_bytes();
+    if bytes.len() < 19 || bytes[4] != b'-' || bytes[7] != b'-' || bytes[10] != b'T' || bytes[13] != b':' || bytes[16] != b':' {
+        return None;
+    }
+    let (year, month, day) = (num(s, 0..4)?, num(s, 5..7)?, num(s, 8..10)?);
+    let (hour, minute, second) = (num(s, 11..13)?, num(s, 14..16)?, num(s, 17..19)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    // Fractional seconds can have any number of digits, we only keep
+    // milliseconds
+    let mut rest = &s[19..];
+    let mut millis = 0;
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let len = fraction.bytes().take_while(u8::is_ascii_digit).count();
+        if len == 0 {
+            return None;
+        }
+        millis = fraction[..len].bytes().chain(std::iter::repeat(b'0')).take(3).fold(0, |n, b| n * 10 + i64::from(b - b'0'));
+        rest = &fraction[len..];
+    }
+
+    let offset = match rest.as_bytes() {
+        b"Z" => 0,
+        [sign @ (b'+' | b'-'), _, _, b':', _, _] => {
+            let offset = num(rest, 1..3)? * 60 + num(rest, 4..6)?;
+            if *sign == b'+' { offset } else { -offset }
+        }
+        _ => return None,
+    };
+
+    // Days since 1970-01-01 in the proleptic Gregorian calendar, counting
+    // years from March so the leap day is at the end
+    let (y, m) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    let secs = days * 86400 + hour * 3600 + minute * 60 + second - offset * 60;
+    Some(secs * 1000 + millis)
+}
+
+// Where Discord's REST API lives, every request goes somewhere under here
+const API_URL: &str = "https://discord.com/api";
+
+// How long to wait before the given reconnect attempt (counting from 0).
+// This doubles each time up to a maximum, and is then randomly cut by up to
+// half so lots of bots that were disconnected at once don't all come back in
+// lockstep
+fn backoff_delay<R: Rng>(rng: &mut R, attempt: u32) -> Duration {
+    const BASE: Duration = Duration::from_secs(1);
+    const MAX: Duration = Duration::from_secs(60);
+
+    let delay = BASE.checked_mul(1 << cmp::min(attempt, 16)).map_or(MAX, |d| cmp::min(d, MAX));
+    delay.mul_f64(rng.gen_range(0.5..=1.0))
+}
+
+// The error for a response which wasn't successful. For 429s the time to wait
+// comes from the Retry-After header if it's there, otherwise the body
+fn api_error(status: http::StatusCode, headers: &http::HeaderMap, body: Bytes) -> Error {
+    if status != http::StatusCode::TOO_MANY_REQUESTS {
+        return Error::BadApiRequest(body);
+    }
+
+    let json = serde_json::from_slice::<model::RateLimited>(&body).ok();
+    let retry_after = header::<f64>(headers, "retry-after")
+        .or_else(|| json.as_ref().map(|j| j.retry_after))
+        .and_then(secs_to_duration);
+    let global = header::<bool>(headers, "x-ratelimit-global").unwrap_or(false)
+        || json.as_ref().is_some_and(|j| j.global);
+
+    match retry_after {
+        Some(retry_after) => Error::RateLimited { retry_after, global },
+        None => Error::BadApiRequest(body),
+    }
+}
+
+fn header<T: FromStr>(headers: &http::HeaderMap, name: &str) -> Option<T> {
+    headers.get(name)
+        .and_then(|hv| str::from_utf8(hv.as_bytes()).ok())
+        .and_then(|s| s.parse::<T>().ok())
+}
+fn secs_to_duration(secs: f64) -> Option<Duration> {
+    Some(secs)
+        .filter(|secs| secs.is_finite() && *secs > 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+// Build a multipart/form-data body with the file as files[0] and the rest of
+// the message as payload_json. The boundary is random so it's not going to
+// turn up in the file
+fn multipart_body(boundary: &str, filename: &str, content_type: &str, data: &[u8], payload_json: Option<&str>) -> Bytes {
+    // Quotes and newlines would break out of the header, and Discord doesn't
+    // keep them in filenames anyway
+    let filename = filename.replace(['"', '\r', '\n'], "_");
+    // The same goes for newlines in the content type, which could add headers
+    // of their own
+    let content_type = content_type.replace(['\r', '\n'], "");
+
+    let mut body = BytesMut::with_capacity(data.len() + payload_json.map_or(0, str::len) + 256);
+    if let Some(payload_json) = payload_json {
+        body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        body.extend_from_slice(b"Content-Disposition: form-data; name=\"payload_json\"\r\n");
+        body.extend_from_slice(b"Content-Type: application/json\r\n\r\n");
+        body.extend_from_slice(payload_json.as_bytes());
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+    body.extend_from_slice(format!("Content-Disposition: form-data; name=\"files[0]\"; filename=\"{}\"\r\n", filename).as_bytes());
+    body.extend_from_slice(format!("Content-Type: {}\r\n\r\n", content_type).as_bytes());
+    body.extend_from_slice(data);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    body.freeze()
+}
+
+// Percent encode an emoji so it can go in a URI path, unicode emoji aren't
+// valid in a URI as they are
+fn encode_emoji(emoji: &str) -> String {
+    use std::fmt::Write;
+
+    let mut encoded = String::with_capacity(emoji.len());
+    for &b in emoji.as_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b':' => encoded.push(b as char),
+            _ => { let _ = write!(encoded, "%{:02X}", b); }
+        }
+    }
+    encoded
 }
+
+// Split a message into parts of at most max_len characters. Each part ends at
+// the last line break that fits, or failing that the last space, which is
+// dropped. Words are only cut in half if one is too long to fit on its own.
+// Parts which would be nothing but whitespace are left out, since Discord
+// won't send them
+fn split_message(mut message: &str, max_len: usize) -> Vec<&str> {
+    let mut parts = Vec::new();
+    while let Some((end, _)) = message.char_indices().nth(max_len) {
+        let head = &message[..end];
+        match head.rfind('\n').or_else(|| head.rfind(' ')).filter(|&idx| idx > 0) {
+            Some(idx) => {
+                parts.push(&message[..idx]);
+                message = &message[idx + 1..];
+            }
+            None => {
+                parts.push(head);
+                message = &message[end..];
+            }
+        }
+    }
+    parts.push(message);
+    parts.retain(|part| !part.trim().is_empty());
+    parts
+}
+
 impl ChannelMessages {
-    pub async fn next(&mut self) -> Result<Option<Message>, Error> {
+    fn new(pages: Pages) -> Self {
+        Self {
+            pages: Some(pages),
+            fetching: None,
+        }
+    }
+}
+impl Stream for ChannelMessages {
+    type Item = Result<Message, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> task::Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.fetching.is_none() {
+            let mut pages = match this.pages.take() {
+                Some(pages) => pages,
+                None => return task::Poll::Ready(None),
+            };
+            // Messages from a page we already have don't need to wait for
+            // anything
+            if let Some(next) = pages.next_buffered() {
+                this.pages = Some(pages);
+                return task::Poll::Ready(Some(Ok(next)));
+            }
+            this.fetching = Some(async move {
+                let next = pages.next().await;
+                (pages, next)
+            }.boxed());
+        }
+
+        let (pages, next) = futures::ready!(this.fetching.as_mut().unwrap().poll_unpin(cx));
+        this.fetching = None;
+        match next {
+            Ok(Some(next)) => {
+                this.pages = Some(pages);
+                task::Poll::Ready(Some(Ok(next)))
+            }
+            Ok(None) => task::Poll::Ready(None),
+            // Without the pages the stream ends here, carrying on would only
+            // skip over the page that failed
+            Err(e) => task::Poll::Ready(Some(Err(e))),
+        }
+    }
+}
+impl Pages {
+    // The next message from the page we already have, if there's any left
+    fn next_buffered(&mut self) -> Option<Message> {
+        match self.next_res.as_mut()?.next() {
+            Some(next) => {
+                self.direction.advance(next.message_id());
+                Some(next)
+            }
+            None => {
+                self.next_res = None;
+                None
+            }
+        }
+    }
+
+    async fn next(&mut self) -> Result<Option<Message>, Error> {
         loop {
-            match self.next_res.take() {
-                Some(mut vec) => {
-                    let next = vec.next();
-                    if let Some(next) = next {
-                        self.next_res = Some(vec);
-                        self.next_msg_id = Some(next.message_id().to_string());
-                        return Ok(Some(next));
-                    } else {
-                        self.next_res = None;
-                    }
-                }
-                None => {
-                    if self.remaining == 0 {
-                        return Ok(None);
-                    }
-                    let limit = cmp::min(self.remaining, 100);
-                    self.remaining -= limit;
+            if let Some(next) = self.next_buffered() {
+                return Ok(Some(next));
+            }
+            if self.remaining == 0 {
+                return Ok(None);
+            }
+            let limit = cmp::min(self.remaining, 100);
+            self.remaining -= limit;
 
-                    if let Some(sleep) = self.rate_limiter.take() {
-                        sleep.await;
-                    }
-                    let uri = match self.next_msg_id.take() {
-                        Some(msg_id) => format!("{}?limit={}&before={}", self.base_uri, limit, msg_id),
-                        None => format!("{}?limit={}", self.base_uri, limit),
-                    };
+            if let Some(deadline) = self.rate_limiter.take() {
+                sleep_until(deadline).await;
+            }
+            let uri = match self.direction.query() {
+                Some((param, msg_id)) => format!("{}?limit={}&{}={}", self.base_uri, limit, param, msg_id),
+                None => format!("{}?limit={}", self.base_uri, limit),
+            };
 
-                    let req = Request::get(uri)
-                        .header(http::header::AUTHORIZATION, self.auth_header.clone())
-                        .body(Body::empty())?;
+            let req = Request::get(uri)
+                .header(http::header::AUTHORIZATION, self.auth_header.clone())
+                .body(Body::empty())?;
 
-                    let bytes = Discord::get_success_response_bytes(&self.client, req).await?;
-                    self.rate_limiter = Some(sleep(Duration::from_secs(10)));
+            let mut next_res = Vec::with_capacity(limit);
+            let user_id = &self.user_id;
+            let headers = self.client.for_each_array_element(req, |bytes| {
+                let raw = serde_json::from_slice::<&RawValue>(&bytes)?;
+                next_res.extend(Message::from_raw(&bytes, raw, user_id)?);
+                Ok(())
+            }).await?;
+            self.rate_limiter = rate_limit_delay(&headers).map(|delay| Instant::now() + delay);
 
-                    let response = serde_json::from_slice::<Vec<model::MessageReceived>>(&bytes)?;
-                    let next_res = response.into_iter()
-                        .map(|msg| Message::from_message_received(&bytes, msg, &self.user_id))
-                        .collect::<Vec<_>>();
-                    if next_res.len() < limit {
-                        self.remaining = 0;
-                    }
-                    self.next_res = Some(next_res.into_iter());
-                }
+            // There's only the one page around a message
+            if next_res.len() < limit || matches!(self.direction, Direction::Around(_)) {
+                self.remaining = 0;
             }
+            // Pages always come newest first, so going forwards each
+            // page needs turning around
+            if let Direction::After(_) = self.direction {
+                next_res.reverse();
+            }
+            self.next_res = Some(next_res.into_iter());
         }
     }
 }
@@ -197,30 +989,332 @@ bitflags! {
         const DIRECT_MESSAGES          = 1 << 12;
         const DIRECT_MESSAGE_REACTIONS = 1 << 13;
         const DIRECT_MESSAGE_TYPING    = 1 << 14;
+        const GUILD_MESSAGE_POLLS      = 1 << 24;
+        const DIRECT_MESSAGE_POLLS     = 1 << 25;
+    }
+}
+impl Intents {
+    // Every intent besides the privileged ones, which have to be turned on
+    // for the bot in the developer portal first. Asking for those without
+    // them gets the connection closed with 4014. `all()` comes from bitflags
+    pub const fn all_unprivileged() -> Self {
+        Self::all().difference(Self::GUILD_MEMBERS.union(Self::GUILD_PRESENCES))
+    }
+}
+
+// The name of each intent, as written above
+const INTENT_NAMES: &[(&str, Intents)] = &[
+    ("GUILDS", Intents::GUILDS),
+    ("GUILD_MEMBERS", Intents::GUILD_MEMBERS),
+    ("GUILD_BANS", Intents::GUILD_BANS),
+    ("GUILD_EMOJIS", Intents::GUILD_EMOJIS),
+    ("GUILD_INTEGRATIONS", Intents::GUILD_INTEGRATIONS),
+    ("GUILD_WEBHOOKS", Intents::GUILD_WEBHOOKS),
+    ("GUILD_INVITES", Intents::GUILD_INVITES),
+    ("GUILD_VOICE_STATES", Intents::GUILD_VOICE_STATES),
+    ("GUILD_PRESENCES", Intents::GUILD_PRESENCES),
+    ("GUILD_MESSAGES", Intents::GUILD_MESSAGES),
+    ("GUILD_MESSAGE_REACTIONS", Intents::GUILD_MESSAGE_REACTIONS),
+    ("GUILD_MESSAGE_TYPING", Intents::GUILD_MESSAGE_TYPING),
+    ("DIRECT_MESSAGES", Intents::DIRECT_MESSAGES),
+    ("DIRECT_MESSAGE_REACTIONS", Intents::DIRECT_MESSAGE_REACTIONS),
+    ("DIRECT_MESSAGE_TYPING", Intents::DIRECT_MESSAGE_TYPING),
+    ("GUILD_MESSAGE_POLLS", Intents::GUILD_MESSAGE_POLLS),
+    ("DIRECT_MESSAGE_POLLS", Intents::DIRECT_MESSAGE_POLLS),
+];
+
+// Parses a comma separated list of intent names, e.g.
+// "GUILD_MESSAGES,DIRECT_MESSAGES", in any case
+impl FromStr for Intents {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .try_fold(Intents::empty(), |intents, name| {
+                INTENT_NAMES.iter()
+                    .find(|(intent_name, _)| intent_name.eq_ignore_ascii_case(name))
+                    .map(|(_, intent)| intents | *intent)
+                    .ok_or_else(|| Error::UnknownIntent(name.to_owned()))
+            })
+    }
+}
+
+
+// What we tell the gateway the bot is running on, see
+// `ConnectOptions::identify_properties`
+#[derive(Clone, Debug)]
+struct IdentifyProperties {
+    os: String,
+    browser: String,
+    device: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ConnectOptions {
+    large_threshold: Option<u16>,
+    shard: Option<[u32; 2]>,
+    presence: Option<presence::Presence>,
+    api_version: Option<u8>,
+    base_url: Option<String>,
+    background_heartbeat: bool,
+    properties: Option<IdentifyProperties>,
+}
+impl ConnectOptions {
+    const MIN_LARGE_THRESHOLD: u16 = 50;
+    const MAX_LARGE_THRESHOLD: u16 = 250;
+    const DEFAULT_API_VERSION: u8 = 6;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+    // The number of members (between 50 and 250, defaulting to 50) above
+    // which a guild is considered large. Large guilds are sent without their
+    // offline members, see `GuildCreate::is_large` and
+    // `Discord::request_guild_members`
+    pub fn large_threshold(mut self, large_threshold: u16) -> Self {
+        self.large_threshold = Some(large_threshold.clamp(Self::MIN_LARGE_THRESHOLD, Self::MAX_LARGE_THRESHOLD));
+        self
+    }
+    // Connect as one shard of a bot split into shard_count shards. Each shard
+    // gets the events for the guilds where (guild_id >> 22) % shard_count is
+    // its shard_id, and direct messages only go to shard 0
+    pub fn shard(mut self, shard_id: u32, shard_count: u32) -> Self {
+        self.shard = Some([shard_id, shard_count]);
+        self
+    }
+    // The presence to connect with, see `Discord::update_presence`
+    pub fn presence(mut self, status: &str, activity: Option<Activity>) -> Self {
+        self.presence = Some(presence::Presence { status: String::from(status), activity });
+        self
+    }
+    // The version of Discord's API to use for both the gateway and REST
+    // requests, defaulting to 6. The payloads this crate sends and expects
+    // are the ones from version 6, newer versions change some of them
+    pub fn api_version(mut self, api_version: u8) -> Self {
+        self.api_version = Some(api_version);
+        self
+    }
+
+    // Send REST requests somewhere other than Discord, e.g. a mock server
+    // when testing. This replaces the "https://discord.com/api" at the start
+    // of every url, the API version still goes after it. The gateway is
+    // wherever the gateway/bot endpoint says it is. An http url allows
+    // connecting to it and to the gateway without TLS
+    pub fn base_url(mut self, base_url: &str) -> Self {
+        self.base_url = Some(String::from(base_url.trim_end_matches('/')));
+        self
+    }
+
+    // Send heartbeats from a task of their own rather than from
+    // `Discord::next_event`, so the connection stays up while events aren't
+    // being waited for, e.g. while working through a long backlog. Heartbeat
+    // acks are only seen when events are read, so something still has to be
+    // waiting for events whenever a heartbeat is due, otherwise the task gives
+    // up with `Error::NoAck` and the connection has to be reconnected.
+    // `Discord::split` turns this on
+    pub fn background_heartbeat(mut self, background: bool) -> Self {
+        self.background_heartbeat = background;
+        self
+    }
+
+    // What to tell the gateway the bot is running on when identifying,
+    // defaulting to "linux", "tokio" and "server"
+    pub fn identify_properties(mut self, os: &str, browser: &str, device: &str) -> Self {
+        self.properties = Some(IdentifyProperties {
+            os: String::from(os),
+            browser: String::from(browser),
+            device: String::from(device),
+        });
+        self
+    }
+
+    fn version(&self) -> u8 {
+        self.api_version.unwrap_or(Self::DEFAULT_API_VERSION)
+    }
+    // The base of every REST API url
+    // Whether we've been pointed at a plain http base url, which is the only
+    // time connecting without TLS is allowed
+    fn is_plaintext(&self) -> bool {
+        self.base_url.as_deref().is_some_and(|base_url| base_url.starts_with("http://"))
+    }
+
+    fn api_base(&self) -> String {
+        format!("{}/v{}", self.base_url.as_deref().unwrap_or(API_URL), self.version())
+    }
+
+    fn properties(&self) -> model::IdentifyProperties<'_> {
+        match self.properties {
+            Some(ref properties) => model::IdentifyProperties {
+                os: &properties.os,
+                browser: &properties.browser,
+                device: &properties.device,
+            },
+            None => model::IdentifyProperties {
+                os: "linux",
+                browser: "tokio",
+                device: "server",
+            },
+        }
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        match self.shard {
+            Some([shard_id, shard_count]) if shard_id >= shard_count => {
+                Err(Error::InvalidArgument("shard_id must be less than shard_count".into()))
+            }
+            _ => Ok(()),
+        }
     }
 }
 
-
+// What to do once the gateway has dropped us
+enum Reconnect {
+    Resume,
+    InvalidSession { resumable: bool },
+}
+
+// The half of the gateway connection that gets written to, shared between a
+// Discord and its writers. Reconnecting swaps the stream out from under the
+// writers, so they always write to the current connection
 #[derive(Debug)]
-pub struct Discord {
+struct GatewayWriter {
+    stream: WriteHalf<BoxedStream>,
+    // The presence to identify with, kept here rather than in the
+    // ConnectOptions so writers can change it too
+    presence: Option<presence::Presence>,
+    // When the last heartbeat was sent, until it's acked
+    heartbeat_sent: Option<Instant>,
+}
+
+// Sends heartbeats at the interval the gateway asked for
+#[derive(Debug)]
+enum Heartbeats {
+    // Sent from next_event whenever the interval ticks
+    Polled(Interval),
+    // Sent by a task of their own, which gives back why it stopped if it
+    // does. None once that's been passed on, or while we're reconnecting
+    Background(Option<JoinHandle<Error>>),
+}
+impl Heartbeats {
+    fn start(interval: Interval, background: bool, writer: &DiscordWriter, last_seq: &Arc<AtomicU64>) -> Self {
+        if !background {
+            return Heartbeats::Polled(interval);
+        }
+        let (mut interval, writer, last_seq) = (interval, writer.clone(), last_seq.clone());
+        Heartbeats::Background(Some(tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+                // The ack clears heartbeat_sent when it's read, so this only
+                // works if something is always reading
+                if writer.gateway.lock().await.heartbeat_sent.is_some() {
+                    break Error::NoAck;
+                }
+                if let Err(e) = writer.heartbeat(last_seq.load(Ordering::Relaxed)).await {
+                    break e;
+                }
+            }
+        })))
+    }
+    fn stop(&mut self) {
+        if let Heartbeats::Background(task) = self {
+            if let Some(task) = task.take() {
+                task.abort();
+            }
+        }
+    }
+    // Wait until it's time to send a heartbeat, or for the background task
+    // to stop, giving back why it stopped
+    async fn tick(&mut self) -> Result<(), Error> {
+        match self {
+            Heartbeats::Polled(interval) => {
+                interval.tick().await;
+                Ok(())
+            }
+            Heartbeats::Background(Some(running)) => {
+                let res = running.await;
+                *self = Heartbeats::Background(None);
+                Err(res.unwrap_or_else(|e| Error::UnknownError(Box::new(e))))
+            }
+            // Nothing more will happen until it's started again
+            Heartbeats::Background(None) => future::pending().await,
+        }
+    }
+}
+impl Drop for Heartbeats {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+// Sends to the gateway and makes REST requests on behalf of a `Discord`,
+// see `Discord::writer` and `Discord::split`. Discord derefs to its writer,
+// so all of these can be called on a Discord directly
+#[derive(Clone, Debug)]
+pub struct DiscordWriter {
+    // Where the REST API is, for the API version we connected with
+    api_base: String,
     client: HttpsClient,
-    prebuf: Option<Bytes>,
-    wsreader: ReadHalf<TlsStream<TcpStream>>,
-    wswriter: WriteHalf<TlsStream<TcpStream>>,
-    token: String,
     auth_header: http::HeaderValue,
-    session_id: Bytes,
-    last_seq: u64,
-    heartbeat_interval: Interval,
     user_id: Bytes,
+    gateway: Arc<Mutex<GatewayWriter>>,
+}
+
+#[derive(Debug)]
+pub struct Discord {
+    writer: DiscordWriter,
+    wsreader: ws::Reader<ReadHalf<BoxedStream>>,
+    connector: GatewayConnector,
+    token: String,
+    // Kept around to identify again if our session is invalidated
+    intents: Option<Intents>,
+    options: ConnectOptions,
+    session_id: Bytes,
+    // Shared with the background heartbeat task if there is one
+    last_seq: Arc<AtomicU64>,
+    heartbeats: Heartbeats,
+    username: Option<Bytes>,
     ack: Option<()>,
+    latency: Option<Duration>,
+}
+impl Deref for Discord {
+    type Target = DiscordWriter;
+
+    fn deref(&self) -> &DiscordWriter {
+        &self.writer
+    }
 }
+
+// The half of a `Discord` that waits for events, see `Discord::split`
+#[derive(Debug)]
+pub struct DiscordReader(Discord);
 impl Discord {
-    const GATEWAY_PARAMETERS: &'static str = "?v=6&encoding=json";
     const BOT_AUTH_HEADER_PREFIX: &'static str = "Bot ";
+    // How long to wait for Discord to send its close frame back after we've
+    // sent ours
+    const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+    // How many times `into_stream` tries to reconnect after an error
+    const STREAM_RECONNECT_ATTEMPTS: u32 = 10;
 
     pub async fn connect_bot(token: &str, intents: Option<Intents>) -> Result<Discord, Error> {
-        let client = Client::builder().build(HttpsConnector::new()?);
+        Self::connect_bot_with_options(token, intents, ConnectOptions::new()).await
+    }
+    pub async fn connect_bot_sharded(token: &str, intents: Option<Intents>, shard_id: u32, shard_count: u32) -> Result<Discord, Error> {
+        Self::connect_bot_with_options(token, intents, ConnectOptions::new().shard(shard_id, shard_count)).await
+    }
+    pub async fn connect_bot_with_options(token: &str, intents: Option<Intents>, options: ConnectOptions) -> Result<Discord, Error> {
+        Self::connect_bot_with_tls(token, intents, options, HttpsConnector::new()?).await
+    }
+    // Connect to something pretending to be Discord, see
+    // `ConnectOptions::base_url`
+    pub async fn connect_bot_with_base_url(token: &str, intents: Option<Intents>, base_url: &str) -> Result<Discord, Error> {
+        Self::connect_bot_with_options(token, intents, ConnectOptions::new().base_url(base_url)).await
+    }
+    // Connect using the given connector for both the REST API and the
+    // gateway, see `HttpsConnector::with_connector`
+    pub async fn connect_bot_with_tls(token: &str, intents: Option<Intents>, options: ConnectOptions, https: HttpsConnector<HttpConnector>) -> Result<Discord, Error> {
+        options.validate()?;
+        let client = HttpsClient::new(https.allow_plaintext(options.is_plaintext()));
 
         let mut bot_auth_buf = BytesMut::with_capacity(Self::BOT_AUTH_HEADER_PREFIX.len() + token.len());
         bot_auth_buf.extend_from_slice(Self::BOT_AUTH_HEADER_PREFIX.as_bytes());
@@ -229,95 +1323,215 @@ impl Discord {
 
         let auth_header = http::HeaderValue::from_maybe_shared(auth_header_bytes).map_err(|e| Error::Http(e.into()))?;
 
-        let gateway_url_bytes = Self::bot_gateway_url(&client, auth_header.clone()).await?;
-        let mut urlbuf = BytesMut::from(&*gateway_url_bytes);
-        urlbuf.reserve(Self::GATEWAY_PARAMETERS.len());
-        urlbuf.extend_from_slice(Self::GATEWAY_PARAMETERS.as_bytes());
-
-        let upgrade = Self::connect_gateway(&client, auth_header.clone(), urlbuf.freeze()).await?;
-        let stream = upgrade.downcast::<TlsStream<TcpStream>>().unwrap();
-        let prebuf = if !stream.read_buf.is_empty() { Some(stream.read_buf) } else { None };
-        let mut wsstream = stream.io;
-
-        let owned_message = ws::message::Owned::read(&mut wsstream).await?;
-        let hello = match owned_message.message() {
-            ws::Message::Text(t) => serde_json::from_str::<model::WsPayload<model::Hello>>(t)?,
-            _ => panic!()
-        };
+        let connector = GatewayConnector::https(client.clone(), auth_header.clone(), &options);
+        Self::connect_with(client, auth_header, connector, token, intents, options).await
+    }
 
-        let heartbeat_interval = interval(Duration::from_millis(hello.d.heartbeat_interval));
+    async fn connect_with(client: HttpsClient, auth_header: http::HeaderValue, connector: GatewayConnector, token: &str, intents: Option<Intents>, options: ConnectOptions) -> Result<Discord, Error> {
+        let (mut wsreader, mut wswriter, heartbeat_interval) = Self::open_gateway(&connector, true).await?;
+        let (last_seq, session_id, user_id, username) = Self::identify(&mut wsreader, &mut wswriter, token, intents, &options).await?;
 
-        let ready_message = Self::identify_handshake(&mut wsstream, token, intents).await?;
-        let ready = match ready_message.message() {
-            ws::Message::Text(t) => serde_json::from_str::<model::WsPayload<model::Ready>>(t)?,
-            _ => panic!()
+        let writer = DiscordWriter {
+            api_base: options.api_base(),
+            client,
+            auth_header,
+            user_id,
+            gateway: Arc::new(Mutex::new(GatewayWriter {
+                stream: wswriter,
+                presence: options.presence.clone(),
+                heartbeat_sent: None,
+            })),
         };
-
-        let last_seq = ready.s.unwrap_or(0);
-        let session_id = model::bytes_from_cow(ready_message.buf(), ready.d.session_id);
-        let user_id = model::bytes_from_cow(ready_message.buf(), ready.d.user.id);
-
-        let (wsreader, wswriter) = split(wsstream);
+        let last_seq = Arc::new(AtomicU64::new(last_seq));
+        let heartbeats = Heartbeats::start(heartbeat_interval, options.background_heartbeat, &writer, &last_seq);
 
         Ok(Discord {
-            client,
-            prebuf,
+            writer,
             wsreader,
-            wswriter,
+            connector,
             token: String::from(token),
-            auth_header,
+            intents,
+            options,
             session_id,
             last_seq,
-            heartbeat_interval,
-            user_id,
+            heartbeats,
+            username,
             ack: Some(()),
+            latency: None,
         })
     }
 
-    pub async fn reconnect(&mut self) -> Result<(), Error> {
-        let gateway_url_bytes = Self::bot_gateway_url(&self.client, self.auth_header.clone()).await?;
-        let mut urlbuf = BytesMut::from(&*gateway_url_bytes);
-        urlbuf.reserve(Self::GATEWAY_PARAMETERS.len());
-        urlbuf.extend_from_slice(Self::GATEWAY_PARAMETERS.as_bytes());
-
-        let upgrade = Self::connect_gateway(&self.client, self.auth_header.clone(), urlbuf.freeze()).await?;
-        let stream = upgrade.downcast::<TlsStream<TcpStream>>().unwrap();
-        let prebuf = if !stream.read_buf.is_empty() { Some(stream.read_buf) } else { None };
-        let mut wsstream = stream.io;
+    // Open a new connection to the gateway and wait for it to say hello,
+    // giving back the two halves of the stream along with the heartbeat
+    // interval it asked for
+    async fn open_gateway(connector: &GatewayConnector, identify: bool) -> Result<(ws::Reader<ReadHalf<BoxedStream>>, WriteHalf<BoxedStream>, Interval), Error> {
+        let (wsstream, extensions) = connector.connect(identify).await?;
+        let (wsreader, wswriter) = split(wsstream);
+        let mut wsreader = ws::Reader::with_extensions(wsreader, extensions);
 
-        let owned_message = ws::message::Owned::read(&mut wsstream).await?;
+        let owned_message = wsreader.read().await?;
         let hello = match owned_message.message() {
             ws::Message::Text(t) => serde_json::from_str::<model::WsPayload<model::Hello>>(t)?,
-            _ => panic!()
+            _ => return Err(Self::handshake_error(owned_message)),
+        };
+
+        // The first heartbeat should be sent at a random point in the first
+        // interval rather than straight away, so lots of clients connecting
+        // at once don't all heartbeat in lockstep
+        let period = Duration::from_millis(hello.d.heartbeat_interval);
+        let jitter = rand::thread_rng().gen::<f64>();
+        let heartbeat_interval = interval_at(Instant::now() + period.mul_f64(jitter), period);
+
+        Ok((wsreader, wswriter, heartbeat_interval))
+    }
+
+    // What went wrong when the gateway sent something other than what we were
+    // waiting for while connecting. A bad token or disallowed intents are
+    // reported by closing straight after we identify, and those need to be
+    // `Error::Gateway` so we don't keep trying to reconnect
+    fn handshake_error(message: ws::message::Owned) -> Error {
+        match message.message() {
+            ws::Message::Close(Some((code, reason))) if is_fatal_close_code(code) => {
+                Error::Gateway { code, reason: String::from(reason) }
+            }
+            _ => Error::UnexpectedWebsocketResponse(message),
+        }
+    }
+
+    // Start a new session, giving back the last sequence number, the session
+    // id, and our user id and username from the READY we get back
+    async fn identify<R, W>(reader: &mut ws::Reader<R>, writer: &mut W, token: &str, intents: Option<Intents>, options: &ConnectOptions) -> Result<(u64, Bytes, Bytes, Option<Bytes>), Error>
+        where R: AsyncRead + Unpin,
+              W: AsyncWrite + Unpin
+    {
+        let ready_message = Self::identify_handshake(reader, writer, token, intents, options).await?;
+        let ready = match ready_message.message() {
+            ws::Message::Text(t) => serde_json::from_str::<model::WsPayload<model::Ready>>(t)?,
+            _ => return Err(Self::handshake_error(ready_message)),
         };
 
-        self.heartbeat_interval = interval(Duration::from_millis(hello.d.heartbeat_interval));
+        let last_seq = ready.s.unwrap_or(0);
+        let session_id = model::bytes_from_cow(ready_message.buf(), ready.d.session_id);
+        let user_id = model::bytes_from_cow(ready_message.buf(), ready.d.user.id);
+        let username = ready.d.user.username.map(|u| model::bytes_from_cow(ready_message.buf(), u));
+        Ok((last_seq, session_id, user_id, username))
+    }
+
+    pub async fn reconnect(&mut self) -> Result<(), Error> {
+        self.heartbeats.stop();
+        let (wsreader, mut wswriter, heartbeat_interval) = Self::open_gateway(&self.connector, false).await?;
+
+        // Any heartbeat still waiting for an ack was sent on the old
+        // connection, so it's never going to get one
+        self.ack = Some(());
 
         ws::Message::Text(&serde_json::to_string(&model::WsPayload {
                 op: 6,
                 d: model::Resume {
                     token: Cow::Borrowed(&self.token),
                     session_id: Cow::Borrowed(self.session_id()),
-                    seq: self.last_seq,
+                    seq: self.last_seq.load(Ordering::Relaxed),
                 },
                 s: None,
                 t: None
             })?)
-            .write(&mut wsstream, ws::message::Context::Client).await?;
-
-        let (wsreader, wswriter) = split(wsstream);
+            .write(&mut wswriter, ws::message::Context::Client).await?;
 
         self.wsreader = wsreader;
-        self.wswriter = wswriter;
-        self.prebuf   = prebuf;
+        self.writer.replace_stream(wswriter).await;
+        self.heartbeats = Heartbeats::start(heartbeat_interval, self.options.background_heartbeat, &self.writer, &self.last_seq);
 
         Ok(())
     }
 
-    pub fn user_id(&self) -> &str {
-        // safety: self.user_id always comes from a Cow<str> so will always be
-        // UTF-8
-        unsafe { str::from_utf8_unchecked(&self.user_id) }
+    // Keep trying to reconnect and resume, waiting longer after each failed
+    // attempt so we don't hammer Discord while it's having problems. Gives
+    // back the last error after max_attempts failures
+    pub async fn reconnect_with_backoff(&mut self, max_attempts: u32) -> Result<(), Error> {
+        let mut attempt = 0;
+        loop {
+            match self.reconnect().await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt + 1 >= max_attempts => return Err(e),
+                Err(_) => (),
+            }
+            sleep(backoff_delay(&mut rand::thread_rng(), attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    // Connect again and start a whole new session, for when Discord tells us
+    // our old session can't be resumed. Any events sent while we were
+    // disconnected are lost
+    async fn reidentify(&mut self) -> Result<(), Error> {
+        self.heartbeats.stop();
+        let (mut wsreader, mut wswriter, heartbeat_interval) = Self::open_gateway(&self.connector, true).await?;
+        self.options.presence = self.writer.gateway.lock().await.presence.clone();
+        let (last_seq, session_id, user_id, username) = Self::identify(&mut wsreader, &mut wswriter, &self.token, self.intents, &self.options).await?;
+
+        self.writer.replace_stream(wswriter).await;
+        self.wsreader        = wsreader;
+        self.ack             = Some(());
+        self.session_id      = session_id;
+        self.writer.user_id  = user_id;
+        self.username        = username;
+        self.last_seq.store(last_seq, Ordering::Relaxed);
+        self.heartbeats = Heartbeats::start(heartbeat_interval, self.options.background_heartbeat, &self.writer, &self.last_seq);
+
+        Ok(())
+    }
+
+    // Have REST requests made after this wait and try again, up to
+    // max_retries times, when Discord rate limits them, rather than failing
+    // with Error::RateLimited straight away
+    pub fn with_auto_retry(mut self, max_retries: u32) -> Self {
+        self.writer.client.max_retries = max_retries;
+        self
+    }
+    // A handle for sending to the gateway and making REST requests from other
+    // tasks while this one waits for events, without putting the whole
+    // Discord behind a lock. Writers share the connection with the Discord
+    // they came from and can be cloned as much as needed.
+    //
+    // Heartbeats are still sent from `next_event`, since that's where their
+    // acks arrive and where we reconnect if they stop arriving, so the
+    // Discord has to keep being polled for the connection to stay up, unless
+    // it was connected with `ConnectOptions::background_heartbeat` or is
+    // `split`. Anything sent through a writer while the Discord is
+    // reconnecting may go to the old connection and fail
+    pub fn writer(&self) -> DiscordWriter {
+        self.writer.clone()
+    }
+    // Split into a reader, for waiting for events in one task, and a writer
+    // for sending to the gateway and making REST requests from any others.
+    //
+    // The writer sends the heartbeats from a task of its own from now on, but
+    // their acks still arrive with the events, so it's the reader that sees
+    // them. The reader is expected to always be waiting for events, and the
+    // heartbeat task gives up with `Error::NoAck` if a heartbeat hasn't been
+    // acked by the time the next one is due, which the reader then gives
+    // back from `next_event`. Reconnecting is done through the reader as
+    // usual, and the writer follows it onto the new connection
+    pub fn split(mut self) -> (DiscordReader, DiscordWriter) {
+        self.options.background_heartbeat = true;
+        // Carry on with the same interval so the next heartbeat isn't late
+        if let Heartbeats::Polled(interval) = &mut self.heartbeats {
+            let interval = mem::replace(interval, self::interval(interval.period()));
+            self.heartbeats = Heartbeats::start(interval, true, &self.writer, &self.last_seq);
+        }
+        let writer = self.writer.clone();
+        (DiscordReader(self), writer)
+    }
+
+    // Our username as of the READY we got when we identified, use
+    // get_current_user to get it fresh
+    pub fn username(&self) -> Option<&str> {
+        unsafe { self.username.as_ref().map(|b| str::from_utf8_unchecked(b)) }
+    }
+    // How long Discord took to ack our last acked heartbeat, which is about
+    // the round trip time to the gateway. None until the first ack arrives
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
     }
     pub fn session_id(&self) -> &str {
         // safety: self.session_id always comes from a Cow<str> so will always
@@ -325,206 +1539,227 @@ impl Discord {
         unsafe { str::from_utf8_unchecked(&self.session_id) }
     }
 
-    async fn get_success_response(client: &HttpsClient, req: Request<Body>) -> Result<Response<Body>, Error> {
-        let res = client.request(req).await?;
-        let status = res.status();
-        if !status.is_success() {
-            let length = res.headers()
-                .get(http::header::CONTENT_LENGTH)
-                .and_then(|hv| str::from_utf8(hv.as_bytes()).ok())
-                .and_then(|s| s.parse::<usize>().ok())
-                .unwrap_or(0);
-            let mut res_body = res.into_body();
-
-            let mut buffer = BytesMut::with_capacity(length);
-            while let Some(chunk) = res_body.next().await {
-                let chunk = chunk?;
-                buffer.reserve(chunk.len());
-                buffer.extend_from_slice(&chunk);
+    // Wait for the next message sent to a channel, ignoring any other events
+    pub async fn next(&mut self) -> Result<Message, Error> {
+        loop {
+            if let Event::MessageCreate(msg) = self.next_event().await? {
+                return Ok(msg);
             }
-            Err(Error::BadApiRequest(buffer.freeze()))
-        } else {
-            Ok(res)
         }
     }
-    async fn get_success_response_bytes(client: &HttpsClient, req: Request<Body>) -> Result<Bytes, Error> {
-        let res = client.request(req).await?;
-        let status = res.status();
-        let length = res.headers()
-            .get(http::header::CONTENT_LENGTH)
-            .and_then(|hv| str::from_utf8(hv.as_bytes()).ok())
-            .and_then(|s| s.parse::<usize>().ok())
-            .unwrap_or(0);
-        let mut res_body = res.into_body();
-
-        let mut buffer = BytesMut::with_capacity(length);
-        while let Some(chunk) = res_body.next().await {
-            let chunk = chunk?;
-            buffer.reserve(chunk.len());
-            buffer.extend_from_slice(&chunk);
-        }
-        let bytes = buffer.freeze();
 
-        if !status.is_success() {
-            Err(Error::BadApiRequest(bytes))
-        } else {
-            Ok(bytes)
-        }
+    // The messages sent to us as a stream, for using with stream combinators
+    // or select!. The stream owns the connection, so after any other error it
+    // reconnects by itself, gives the error back and carries on. It ends
+    // after an error that reconnecting won't fix, or once reconnecting has
+    // failed STREAM_RECONNECT_ATTEMPTS times, giving back that error
+    pub fn into_stream(self) -> impl Stream<Item=Result<Message, Error>> {
+        stream::unfold(Some(self), |discord| async move {
+            let mut discord = discord?;
+            match discord.next().await {
+                Err(e @ Error::Gateway { .. }) => Some((Err(e), None)),
+                Err(e) => match discord.reconnect_with_backoff(Self::STREAM_RECONNECT_ATTEMPTS).await {
+                    Ok(()) => Some((Err(e), Some(discord))),
+                    Err(e) => Some((Err(e), None)),
+                },
+                next => Some((next, Some(discord))),
+            }
+        })
     }
 
-    pub async fn next(&mut self) -> Result<Message, Error> {
+    pub async fn next_event(&mut self) -> Result<Event, Error> {
         let user_id = self.user_id.clone();
 
-        // loop until we get a message that's a proper discord message that we
-        // care about (i.e. not a Heartbeat Ack/Reaction/etc, actually a text
+        // loop until we get an event that's a proper discord event that we
+        // care about (i.e. not a Heartbeat Ack, actually something like a text
         // message sent to a channel)
         loop {
             let reconnect = {
-                let message = ws::message::Owned::read(&mut self.wsreader).fuse();
+                let message = self.wsreader.read().fuse();
                 pin_mut!(message);
 
                 // We also need to send a heartbeat occassionally, so loop until we
                 // get something that isn't our heartbeat interval (i.e. actually
                 // a proper websocket message)
-                let (msg, reconnect) = loop {
-                    let interval = self.heartbeat_interval.tick().fuse();
+                let (event, reconnect) = loop {
+                    let interval = self.heartbeats.tick().fuse();
                     pin_mut!(interval);
 
                     // Prefer sending heartbeats over receiving messages if we can
                     futures::select_biased! {
-                        _ = interval => match self.ack.take() {
-                            Some(()) => {
-                                let identify = model::WsPayload {
-                                    op: 1,
-                                    d: self.last_seq,
-                                    s: None,
-                                    t: None,
-                                };
-                                let serialized = serde_json::to_string(&identify)?;
-                                ws::Message::Text(&serialized)
-                                    .write(&mut self.wswriter, ws::message::Context::Client)
-                                    .await?;
-                            }
-                            None => return Err(Error::NoAck),
+                        res = interval => match (res, self.ack.take()) {
+                            (Err(e), _) => return Err(e),
+                            (Ok(()), Some(())) => self.writer.heartbeat(self.last_seq.load(Ordering::Relaxed)).await?,
+                            (Ok(()), None) => return Err(Error::NoAck),
                         },
                         msg_res = message => break {
-                            let owned_message = msg_res?;
+                            let owned_message = match msg_res {
+                                Ok(owned_message) => owned_message,
+                                // The connection being reset doesn't mean our
+                                // session is gone, so resume it rather than
+                                // returning an error and making the caller
+                                // identify from scratch
+                                Err(e) if e.is_connection_reset() => break (None, Some(Reconnect::Resume)),
+                                Err(e) => return Err(e.into()),
+                            };
 
                             match owned_message.message() {
                                 ws::Message::Text(t) => {
                                     let next = serde_json::from_str::<model::WsPayloadUnknownOp>(t)?;
 
                                     if let Some(s) = next.s {
-                                        self.last_seq = s;
+                                        self.last_seq.store(s, Ordering::Relaxed);
                                     }
 
                                     if next.op == 11 {
                                         self.ack = Some(());
+                                        if let Some(sent) = self.writer.gateway.lock().await.heartbeat_sent.take() {
+                                            self.latency = Some(sent.elapsed());
+                                        }
                                     }
-                                    if let Some("MESSAGE_CREATE") = next.t.as_deref() {
-                                        let msg = serde_json::from_str::<model::WsPayload<model::MessageReceived>>(t)?;
-                                        (Some(Message::from_message_received(owned_message.buf(), msg.d, &user_id)), false)
-                                    } else {
-                                        (None, false)
+                                    match (next.op, next.t.as_deref()) {
+                                        (0, Some(event)) => {
+                                            (Event::from_dispatch(event, t, owned_message.buf(), &user_id)?, None)
+                                        }
+                                        // Discord asking us to reconnect,
+                                        // e.g. so it can move us to another
+                                        // gateway. The session is still fine
+                                        // so just resume it
+                                        (7, _) => (None, Some(Reconnect::Resume)),
+                                        // Invalid session, d says whether
+                                        // we're allowed to resume it
+                                        (9, _) => {
+                                            let invalid = serde_json::from_str::<model::WsPayload<Option<bool>>>(t)?;
+                                            (None, Some(Reconnect::InvalidSession { resumable: invalid.d.unwrap_or(false) }))
+                                        }
+                                        _ => (None, None),
                                     }
                                 }
                                 ws::Message::Close(Some((1001, _))) => {
-                                    (None, true)
+                                    (None, Some(Reconnect::Resume))
+                                }
+                                ws::Message::Close(Some((code @ 4000..=4999, reason))) => match code {
+                                    // Our sequence number was wrong or we
+                                    // took too long, either way the session
+                                    // is gone
+                                    4007 | 4009 => (None, Some(Reconnect::InvalidSession { resumable: false })),
+                                    code if is_fatal_close_code(code) => {
+                                        return Err(Error::Gateway { code, reason: String::from(reason) });
+                                    }
+                                    _ => (None, Some(Reconnect::Resume)),
+                                },
+                                // Pings have to be answered with a pong with
+                                // the same payload or the peer may drop us
+                                ws::Message::Ping(payload) => {
+                                    self.writer.send(ws::Message::Pong(payload)).await?;
+                                    (None, None)
                                 }
+                                // Pongs can be sent unprompted as a heartbeat,
+                                // there's nothing to do with them
+                                ws::Message::Pong(_) => (None, None),
                                 _ => return Err(Error::UnexpectedWebsocketResponse(owned_message))
                             }
                         }
                     };
                 };
 
-                if let Some(msg) = msg {
-                    break Ok(msg);
+                if let Some(event) = event {
+                    break Ok(event);
                 }
                 reconnect
             };
-            if reconnect {
-                self.reconnect().await?;
+            match reconnect {
+                Some(Reconnect::Resume) => self.reconnect().await?,
+                Some(Reconnect::InvalidSession { resumable }) => {
+                    // Discord wants us to wait a random 1-5 seconds before
+                    // trying again so everyone doesn't come back at once
+                    let delay = Duration::from_millis(rand::thread_rng().gen_range(1000..=5000));
+                    sleep(delay).await;
+                    if resumable {
+                        self.reconnect().await?;
+                    } else {
+                        self.reidentify().await?;
+                    }
+                }
+                None => (),
             }
         }
     }
 
-    pub fn add_reaction(&self, channel_id: &str, message_id: &str, emoji: &str) -> impl Future<Output=Result<(), Error>> + Send + 'static {
-        let uri = format!("https://discordapp.com/api/v6/channels/{}/messages/{}/reactions/{}/@me",
-                          channel_id, message_id, emoji);
-        let req = Request::put(uri)
-            .header(http::header::AUTHORIZATION, self.auth_header.clone())
-            .header(http::header::CONTENT_LENGTH, 0)
-            .body(Body::empty());
-
-        let client = self.client.clone();
-        async move {
-            Self::get_success_response(&client, req?).await.map(|_| ())
+    // Close the connection cleanly, waiting a little while for Discord to
+    // close its end as well. Closing with 1000 or 1001 ends the session, any
+    // other code (e.g. 4000) leaves it to be resumed later
+    pub async fn close(mut self, code: u16, reason: &str) -> Result<(), Error> {
+        // A close frame's body is the 2 byte code and the reason, and control
+        // frames can be at most 125 bytes
+        if reason.len() > 123 {
+            return Err(Error::InvalidArgument("a close reason can be at most 123 bytes".into()));
         }
-    }
-    pub fn send_message(&self, channel_id: &str, message: &str) -> impl Future<Output=Result<(), Error>> + Send + 'static {
-        let uri = format!("https://discordapp.com/api/v6/channels/{}/messages", channel_id);
-        let req: Result<Request<Body>, Error> = try {
-            Request::post(uri)
-                .header(http::header::AUTHORIZATION, self.auth_header.clone())
-                .header(http::header::CONTENT_TYPE, "application/json")
-                .body(Body::from(serde_json::to_string(&model::CreateMessageRequest { content: message })?))?
+        self.writer.send(ws::Message::Close(Some((code, reason)))).await?;
+
+        // Anything other than the close frame is thrown away, and if reading
+        // fails the connection is gone anyway so there's nothing to wait for
+        let wsreader = &mut self.wsreader;
+        let closed = async {
+            while let Ok(message) = wsreader.read().await {
+                if let ws::Message::Close(_) = message.message() {
+                    break;
+                }
+            }
         };
-        let client = self.client.clone();
-        async move {
-            Self::get_success_response(&client, req?).await.map(|_| ())
-        }
-    }
-    pub fn channel_messages(&self, channel_id: &str, limit: usize, before_msg: Option<String>) -> ChannelMessages {
-        ChannelMessages {
-            auth_header: self.auth_header.clone(),
-            base_uri: format!("https://discordapp.com/api/v6/channels/{}/messages", channel_id),
-            client: self.client.clone(),
-            remaining: limit,
-            next_msg_id: before_msg,
-            next_res: None,
-            rate_limiter: None,
-            user_id: self.user_id.clone(),
-        }
+        let _ = timeout(Self::CLOSE_TIMEOUT, closed).await;
+
+        self.writer.gateway.lock().await.stream.shutdown().await.map_err(Error::from)
     }
-    async fn bot_gateway_url(client: &HttpsClient, auth_header: http::HeaderValue) -> Result<Bytes, Error> {
-        let req = Request::get("https://discordapp.com/api/v6/gateway/bot")
+
+    // Only identifying counts towards the session start limit, resuming
+    // doesn't, so the limit is only checked when we're about to identify
+    async fn bot_gateway_url(client: &HttpsClient, auth_header: http::HeaderValue, api_base: &str, identify: bool) -> Result<Bytes, Error> {
+        let req = Request::get(format!("{}/gateway/bot", api_base))
             .header(http::header::AUTHORIZATION, auth_header)
             .body(Body::empty())?;
 
-        let bytes = Self::get_success_response_bytes(client, req).await?;
+        let bytes = client.get_success_response_bytes(req).await?;
         let response = serde_json::from_slice::<model::BotGatewayResponse>(&bytes)?;
+        let limit = &response.session_start_limit;
+        if identify && limit.remaining == 0 {
+            return Err(Error::SessionStartLimit { reset_after: Duration::from_millis(limit.reset_after) });
+        }
         Ok(bytes.slice_ref(response.url.as_bytes()))
     }
-    async fn connect_gateway(client: &HttpsClient, auth_header: http::HeaderValue, gateway_url: Bytes) -> Result<Upgraded, Error> {
+    async fn connect_gateway(client: &HttpsClient, auth_header: http::HeaderValue, gateway_url: Bytes) -> Result<(Upgraded, ws::Extensions), Error> {
         let nonce = ws::RequestKey::generate()?;
-        let req = Request::get(&*gateway_url)
+        let mut req = Request::get(&*gateway_url)
             .header(http::header::AUTHORIZATION, auth_header)
             .header(http::header::UPGRADE, "websocket")
             .header(http::header::CONNECTION, "upgrade")
             .header(http::header::SEC_WEBSOCKET_VERSION, "13")
-            .header(http::header::SEC_WEBSOCKET_KEY, nonce.as_ref())
-            .body(Body::empty())?;
+            .header(http::header::SEC_WEBSOCKET_KEY, nonce.as_ref());
+        if let Some(extensions) = ws::Extensions::offer() {
+            req = req.header(http::header::SEC_WEBSOCKET_EXTENSIONS, extensions);
+        }
+        let req = req.body(Body::empty())?;
 
-        let res = Self::verify_ws_handshake_response(&nonce, client.request(req).await?)?;
-        Ok(hyper::upgrade::on(res).await?)
+        let (res, extensions) = Self::verify_ws_handshake_response(&nonce, client.request(req).await?)?;
+        Ok((hyper::upgrade::on(res).await?, extensions))
     }
-    fn verify_ws_handshake_response(nonce: &ws::RequestKey, res: Response<Body>) -> Result<Response<Body>, Error> {
+    fn verify_ws_handshake_response(nonce: &ws::RequestKey, res: Response<Body>) -> Result<(Response<Body>, ws::Extensions), Error> {
         if res.status() != http::status::StatusCode::SWITCHING_PROTOCOLS {
-            return Err(Error::Handshake(res));
+            return Err(Error::Handshake(Box::new(res)));
         }
         if res.headers()
             .get(http::header::UPGRADE)
             .and_then(|h| h.to_str().ok())
             .map(UniCase::new) != Some(UniCase::new("WEBSOCKET"))
         {
-            return Err(Error::Handshake(res));
+            return Err(Error::Handshake(Box::new(res)));
         }
         if res.headers()
             .get(http::header::CONNECTION)
             .and_then(|h| h.to_str().ok())
             .map(UniCase::new) != Some(UniCase::new("UPGRADE"))
         {
-            return Err(Error::Handshake(res));
+            return Err(Error::Handshake(Box::new(res)));
         }
         if let Some(value) = res.headers()
             .get(http::header::SEC_WEBSOCKET_ACCEPT)
@@ -532,37 +1767,1631 @@ impl Discord {
             .and_then(|h| ws::ResponseKey::from_str(h).ok())
         {
             if !nonce.verify(value) {
-                return Err(Error::Handshake(res));
+                return Err(Error::Handshake(Box::new(res)));
             }
         } else {
-            return Err(Error::Handshake(res));
+            return Err(Error::Handshake(Box::new(res)));
+        }
+        let extensions = match res.headers().get(http::header::SEC_WEBSOCKET_EXTENSIONS) {
+            Some(h) => h.to_str().ok().and_then(|h| ws::Extensions::accept(Some(h))),
+            None => ws::Extensions::accept(None),
+        };
+        match extensions {
+            Some(extensions) => Ok((res, extensions)),
+            None => Err(Error::Handshake(Box::new(res))),
         }
-
-        Ok(res)
     }
 
-    async fn identify_handshake<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, token: &str, intents: Option<Intents>) -> Result<ws::message::Owned, Error> {
+    async fn identify_handshake<R, W>(reader: &mut ws::Reader<R>, writer: &mut W, token: &str, intents: Option<Intents>, options: &ConnectOptions) -> Result<ws::message::Owned, Error>
+        where R: AsyncRead + Unpin,
+              W: AsyncWrite + Unpin
+    {
         ws::Message::Text(&serde_json::to_string(&model::WsPayload {
                 op: 2,
                 d: model::Identify {
                     token,
-                    properties: model::IdentifyProperties {
-                        os: "linux",
-                        browser: "tokio",
-                        device: "server",
-                    },
+                    properties: options.properties(),
                     compress: Some(false),
-                    large_threshold: None,
-                    shard: None,
-                    presence: None,
+                    large_threshold: options.large_threshold,
+                    shard: options.shard,
+                    presence: options.presence.as_ref().map(|p| model::UpdateStatus {
+                        since: None,
+                        game: p.activity.as_ref(),
+                        status: &p.status,
+                        afk: false,
+                    }),
                     guild_subscriptions: Some(false),
                     intents: intents.map(|i| i.bits())
                 },
                 s: None,
                 t: None
             })?)
-            .write(stream, ws::message::Context::Client).await?;
+            .write(writer, ws::message::Context::Client).await?;
+
+        reader.read().await.map_err(Error::from)
+    }
+}
+
+impl DiscordReader {
+    pub async fn next_event(&mut self) -> Result<Event, Error> {
+        self.0.next_event().await
+    }
+    pub async fn next(&mut self) -> Result<Message, Error> {
+        self.0.next().await
+    }
+    pub fn into_stream(self) -> impl Stream<Item=Result<Message, Error>> {
+        self.0.into_stream()
+    }
+    pub async fn reconnect(&mut self) -> Result<(), Error> {
+        self.0.reconnect().await
+    }
+    pub async fn reconnect_with_backoff(&mut self, max_attempts: u32) -> Result<(), Error> {
+        self.0.reconnect_with_backoff(max_attempts).await
+    }
+    pub async fn close(self, code: u16, reason: &str) -> Result<(), Error> {
+        self.0.close(code, reason).await
+    }
+    pub fn username(&self) -> Option<&str> {
+        self.0.username()
+    }
+    pub fn latency(&self) -> Option<Duration> {
+        self.0.latency()
+    }
+    pub fn session_id(&self) -> &str {
+        self.0.session_id()
+    }
+}
+
+impl DiscordWriter {
+    pub fn user_id(&self) -> &str {
+        // safety: self.user_id always comes from a Cow<str> so will always be
+        // UTF-8
+        unsafe { str::from_utf8_unchecked(&self.user_id) }
+    }
+
+    // Write a message to the gateway, on whichever connection is current
+    async fn send(&self, message: ws::Message<'_>) -> Result<(), Error> {
+        let mut gateway = self.gateway.lock().await;
+        message.write(&mut gateway.stream, ws::message::Context::Client).await.map_err(Error::from)
+    }
+    async fn heartbeat(&self, last_seq: u64) -> Result<(), Error> {
+        let heartbeat = serde_json::to_string(&model::WsPayload {
+            op: 1,
+            d: last_seq,
+            s: None,
+            t: None,
+        })?;
+        let mut gateway = self.gateway.lock().await;
+        ws::Message::Text(&heartbeat).write(&mut gateway.stream, ws::message::Context::Client).await?;
+        gateway.heartbeat_sent = Some(Instant::now());
+        Ok(())
+    }
+    // Swap in a new connection after reconnecting. Any heartbeat sent on the
+    // old one is never going to be acked
+    async fn replace_stream(&self, stream: WriteHalf<BoxedStream>) {
+        let mut gateway = self.gateway.lock().await;
+        gateway.stream = stream;
+        gateway.heartbeat_sent = None;
+    }
+
+    // Change the bot's status and activity. The status is one of "online",
+    // "idle", "dnd" (do not disturb) or "invisible"
+    pub async fn update_presence(&self, status: &str, activity: Option<Activity>) -> Result<(), Error> {
+        let mut gateway = self.gateway.lock().await;
+        ws::Message::Text(&serde_json::to_string(&model::WsPayload {
+                op: 3,
+                d: model::UpdateStatus {
+                    since: None,
+                    game: activity.as_ref(),
+                    status,
+                    afk: false,
+                },
+                s: None,
+                t: None
+            })?)
+            .write(&mut gateway.stream, ws::message::Context::Client).await?;
+
+        // Keep hold of it so we come back with the same presence if we have
+        // to identify again
+        gateway.presence = Some(presence::Presence { status: String::from(status), activity });
+        Ok(())
+    }
+
+    // Ask for the members of a guild, which will be sent back as a series of
+    // `Event::GuildMembersChunk`s. This is how to get the offline members of
+    // a large guild.
+    //
+    // An empty query with a limit of 0 requests every member, which needs the
+    // GUILD_MEMBERS intent. Otherwise members whose username starts with query
+    // are sent, up to limit members
+    pub async fn request_guild_members(&self, guild_id: &str, query: &str, limit: u32) -> Result<(), Error> {
+        let request = model::WsPayload {
+            op: 8,
+            d: model::RequestGuildMembers {
+                guild_id,
+                query,
+                limit,
+            },
+            s: None,
+            t: None,
+        };
+        self.send(ws::Message::Text(&serde_json::to_string(&request)?)).await
+    }
+
+    // Emoji are either unicode emoji or name:id for custom emoji
+    pub fn add_reaction(&self, channel_id: &str, message_id: &str, emoji: &str) -> impl Future<Output=Result<(), Error>> + Send + 'static {
+        let uri = format!("{}/channels/{}/messages/{}/reactions/{}/@me",
+                          self.api_base, channel_id, message_id, encode_emoji(emoji));
+        let req = Request::put(uri)
+            .header(http::header::AUTHORIZATION, self.auth_header.clone())
+            .header(http::header::CONTENT_LENGTH, 0)
+            .body(Body::empty());
+
+        let client = self.client.clone();
+        async move {
+            client.get_success_response(req?).await.map(|_| ())
+        }
+    }
+    pub fn remove_own_reaction(&self, channel_id: &str, message_id: &str, emoji: &str) -> impl Future<Output=Result<(), Error>> + Send + 'static {
+        let uri = format!("{}/channels/{}/messages/{}/reactions/{}/@me",
+                          self.api_base, channel_id, message_id, encode_emoji(emoji));
+        let req = Request::delete(uri)
+            .header(http::header::AUTHORIZATION, self.auth_header.clone())
+            .body(Body::empty());
+
+        let client = self.client.clone();
+        async move {
+            client.get_success_response(req?).await.map(|_| ())
+        }
+    }
+    // Get every user who reacted to a message with an emoji, which is given
+    // the same way as for `add_reaction`
+    pub fn get_reactions(&self, channel_id: &str, message_id: &str, emoji: &str) -> impl Future<Output=Result<Vec<User>, Error>> + Send + 'static {
+        const LIMIT: usize = 100;
+
+        let base_uri = format!("{}/channels/{}/messages/{}/reactions/{}",
+                               self.api_base, channel_id, message_id, encode_emoji(emoji));
+        let auth_header = self.auth_header.clone();
+        let client = self.client.clone();
+        async move {
+            let mut users = Vec::<User>::new();
+            loop {
+                let uri = match users.last() {
+                    Some(user) => format!("{}?limit={}&after={}", base_uri, LIMIT, user.id()),
+                    None => format!("{}?limit={}", base_uri, LIMIT),
+                };
+                let req = Request::get(uri)
+                    .header(http::header::AUTHORIZATION, auth_header.clone())
+                    .body(Body::empty())?;
+
+                let bytes = client.get_success_response_bytes(req).await?;
+                let page = serde_json::from_slice::<Vec<model::User>>(&bytes)?;
+                let count = page.len();
+                users.extend(page.into_iter().map(|u| User::from_user(&bytes, u)));
+                if count < LIMIT {
+                    break Ok(users);
+                }
+            }
+        }
+    }
+    // Remove every reaction from a message, this needs the MANAGE_MESSAGES
+    // permission
+    pub fn remove_all_reactions(&self, channel_id: &str, message_id: &str) -> impl Future<Output=Result<(), Error>> + Send + 'static {
+        let uri = format!("{}/channels/{}/messages/{}/reactions", self.api_base, channel_id, message_id);
+        let req = Request::delete(uri)
+            .header(http::header::AUTHORIZATION, self.auth_header.clone())
+            .body(Body::empty());
+
+        let client = self.client.clone();
+        async move {
+            client.get_success_response(req?).await.map(|_| ())
+        }
+    }
+    pub fn send_message(&self, channel_id: &str, message: &str) -> impl Future<Output=Result<(), Error>> + Send + 'static {
+        self.create_message(channel_id, message, None)
+    }
+    // Send a message without pinging anyone, even if it contains @everyone or
+    // user and role mentions. This is what to use when the content comes from
+    // other users, e.g. echoing or generating text from what they've said
+    pub fn send_message_safe(&self, channel_id: &str, message: &str) -> impl Future<Output=Result<(), Error>> + Send + 'static {
+        self.create_message(channel_id, message, Some(model::AllowedMentions::NONE))
+    }
+    // Send a message of any length, split into as many messages as it takes
+    // (see `MAX_MESSAGE_LENGTH`) at line breaks or spaces where possible. The
+    // parts are sent one at a time so they arrive in order, and if one fails
+    // the error says how many were sent before it
+    pub fn send_message_chunked(&self, channel_id: &str, message: &str) -> impl Future<Output=Result<(), Error>> + Send + 'static {
+        let parts = split_message(message, MAX_MESSAGE_LENGTH)
+            .into_iter()
+            .map(|part| self.send_message(channel_id, part))
+            .collect::<Vec<_>>();
+        async move {
+            for (sent, part) in parts.into_iter().enumerate() {
+                part.await.map_err(|e| Error::PartiallySent { sent, source: Box::new(e) })?;
+            }
+            Ok(())
+        }
+    }
+    fn create_message(&self, channel_id: &str, message: &str, allowed_mentions: Option<model::AllowedMentions>) -> impl Future<Output=Result<(), Error>> + Send + 'static {
+        let uri = format!("{}/channels/{}/messages", self.api_base, channel_id);
+        let length = message.chars().count();
+        let req: Result<Request<Body>, Error> = if length > MAX_MESSAGE_LENGTH {
+            Err(Error::MessageTooLong(length))
+        } else {
+            try {
+                Request::post(uri)
+                    .header(http::header::AUTHORIZATION, self.auth_header.clone())
+                    .header(http::header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::to_string(&model::CreateMessageRequest { content: Some(message), allowed_mentions, ..Default::default() }).map_err(Error::from)?)).map_err(Error::from)?
+            }
+        };
+        let client = self.client.clone();
+        async move {
+            client.get_success_response(req?).await.map(|_| ())
+        }
+    }
+    // Show the bot as typing in a channel. This lasts for about 10 seconds or
+    // until the bot sends a message, so it needs triggering again every so
+    // often for anything that takes longer
+    pub fn trigger_typing(&self, channel_id: &str) -> impl Future<Output=Result<(), Error>> + Send + 'static {
+        let uri = format!("{}/channels/{}/typing", self.api_base, channel_id);
+        let req = Request::post(uri)
+            .header(http::header::AUTHORIZATION, self.auth_header.clone())
+            .header(http::header::CONTENT_LENGTH, 0)
+            .body(Body::empty());
+
+        let client = self.client.clone();
+        async move {
+            client.get_success_response(req?).await.map(|_| ())
+        }
+    }
+    // Upload a file to a channel, optionally with a message to go with it
+    pub fn send_file(&self, channel_id: &str, filename: &str, content_type: &str, data: Bytes, message: Option<&str>) -> impl Future<Output=Result<(), Error>> + Send + 'static {
+        let uri = format!("{}/channels/{}/messages", self.api_base, channel_id);
+        let length = message.map_or(0, |m| m.chars().count());
+        let req: Result<Request<Body>, Error> = if length > MAX_MESSAGE_LENGTH {
+            Err(Error::MessageTooLong(length))
+        } else {
+            try {
+                let payload_json = match message {
+                    Some(content) => Some(serde_json::to_string(&model::CreateMessageRequest { content: Some(content), ..Default::default() }).map_err(Error::from)?),
+                    None => None,
+                };
+                let boundary = rand::thread_rng()
+                    .sample_iter(rand::distributions::Alphanumeric)
+                    .take(32)
+                    .map(char::from)
+                    .collect::<String>();
+                let body = multipart_body(&boundary, filename, content_type, &data, payload_json.as_deref());
+                Request::post(uri)
+                    .header(http::header::AUTHORIZATION, self.auth_header.clone())
+                    .header(http::header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", boundary))
+                    .body(Body::from(body)).map_err(Error::from)?
+            }
+        };
+        let client = self.client.clone();
+        async move {
+            client.get_success_response(req?).await.map(|_| ())
+        }
+    }
+    // Send a message as a reply to another message, so it shows up with a
+    // link back to the original. If mention_author is false the author of the
+    // original message isn't pinged, other mentions in the message still are
+    pub fn send_message_with_reply(&self, reply_to: &Message, message: &str, mention_author: bool) -> impl Future<Output=Result<(), Error>> + Send + 'static {
+        let uri = format!("{}/channels/{}/messages", self.api_base, reply_to.channel_id());
+        let length = message.chars().count();
+        let req: Result<Request<Body>, Error> = if length > MAX_MESSAGE_LENGTH {
+            Err(Error::MessageTooLong(length))
+        } else {
+            try {
+                let body = serde_json::to_string(&model::CreateMessageRequest {
+                    content: Some(message),
+                    message_reference: Some(model::MessageReference {
+                        message_id: reply_to.message_id(),
+                        channel_id: reply_to.channel_id(),
+                        guild_id: reply_to.guild_id(),
+                    }),
+                    // Giving allowed_mentions at all turns off every kind of
+                    // mention that isn't listed, so list the default ones
+                    allowed_mentions: (!mention_author).then_some(model::AllowedMentions {
+                        parse: &["users", "roles", "everyone"],
+                        replied_user: false,
+                    }),
+                    ..Default::default()
+                }).map_err(Error::from)?;
+                Request::post(uri)
+                    .header(http::header::AUTHORIZATION, self.auth_header.clone())
+                    .header(http::header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body)).map_err(Error::from)?
+            }
+        };
+        let client = self.client.clone();
+        async move {
+            client.get_success_response(req?).await.map(|_| ())
+        }
+    }
+    pub fn send_embed(&self, channel_id: &str, embed: &Embed) -> impl Future<Output=Result<(), Error>> + Send + 'static {
+        self.send_message_with_embeds(channel_id, None, std::slice::from_ref(embed))
+    }
+    // Send a message with embeds and, optionally, some content above them
+    pub fn send_message_with_embeds(&self, channel_id: &str, content: Option<&str>, embeds: &[Embed]) -> impl Future<Output=Result<(), Error>> + Send + 'static {
+        let uri = format!("{}/channels/{}/messages", self.api_base, channel_id);
+        let req: Result<Request<Body>, Error> = try {
+            let length = content.map_or(0, |content| content.chars().count());
+            if length > MAX_MESSAGE_LENGTH {
+                Err(Error::MessageTooLong(length))?;
+            }
+            if embeds.len() > MAX_EMBEDS {
+                Err(Error::InvalidArgument(format!("a message can have at most {} embeds", MAX_EMBEDS).into()))?;
+            }
+            let body = serde_json::to_string(&model::CreateMessageRequest { content, embeds: Some(embeds), ..Default::default() }).map_err(Error::from)?;
+            Request::post(uri)
+                .header(http::header::AUTHORIZATION, self.auth_header.clone())
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body)).map_err(Error::from)?
+        };
+        let client = self.client.clone();
+        async move {
+            client.get_success_response(req?).await.map(|_| ())
+        }
+    }
+    pub fn send_poll(&self, channel_id: &str, poll: &Poll) -> impl Future<Output=Result<(), Error>> + Send + 'static {
+        let uri = format!("{}/channels/{}/messages", self.api_base, channel_id);
+        let req: Result<Request<Body>, Error> = try {
+            poll.validate()?;
+            let body = serde_json::to_string(&model::CreateMessageRequest { poll: Some(poll), ..Default::default() }).map_err(Error::from)?;
+            Request::post(uri)
+                .header(http::header::AUTHORIZATION, self.auth_header.clone())
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body)).map_err(Error::from)?
+        };
+        let client = self.client.clone();
+        async move {
+            client.get_success_response(req?).await.map(|_| ())
+        }
+    }
+    // Get every user who voted for an answer in a poll, answers are numbered
+    // from 1
+    pub fn get_poll_answer_voters(&self, channel_id: &str, message_id: &str, answer_id: u32) -> impl Future<Output=Result<Vec<User>, Error>> + Send + 'static {
+        const LIMIT: usize = 100;
+
+        let base_uri = format!("{}/channels/{}/polls/{}/answers/{}",
+                               self.api_base, channel_id, message_id, answer_id);
+        let auth_header = self.auth_header.clone();
+        let client = self.client.clone();
+        async move {
+            let mut users = Vec::<User>::new();
+            loop {
+                let uri = match users.last() {
+                    Some(user) => format!("{}?limit={}&after={}", base_uri, LIMIT, user.id()),
+                    None => format!("{}?limit={}", base_uri, LIMIT),
+                };
+                let req = Request::get(uri)
+                    .header(http::header::AUTHORIZATION, auth_header.clone())
+                    .body(Body::empty())?;
+
+                let bytes = client.get_success_response_bytes(req).await?;
+                let response = serde_json::from_slice::<model::PollAnswerVoters>(&bytes)?;
+                let count = response.users.len();
+                users.extend(response.users.into_iter().map(|u| User::from_user(&bytes, u)));
+                if count < LIMIT {
+                    break Ok(users);
+                }
+            }
+        }
+    }
+    // Fetch the bot's own user
+    pub fn get_current_user(&self) -> impl Future<Output=Result<User, Error>> + Send + 'static {
+        let req = Request::get(format!("{}/users/@me", self.api_base))
+            .header(http::header::AUTHORIZATION, self.auth_header.clone())
+            .body(Body::empty());
+        let client = self.client.clone();
+        async move {
+            let bytes = client.get_success_response_bytes(req?).await?;
+            let user = serde_json::from_slice::<model::User>(&bytes)?;
+            Ok(User::from_user(&bytes, user))
+        }
+    }
+    // Fetch a channel, e.g. to get its name. DMs don't have a name
+    pub fn get_channel(&self, channel_id: &str) -> impl Future<Output=Result<Channel, Error>> + Send + 'static {
+        let req = Request::get(format!("{}/channels/{}", self.api_base, channel_id))
+            .header(http::header::AUTHORIZATION, self.auth_header.clone())
+            .body(Body::empty());
+        let client = self.client.clone();
+        async move {
+            let bytes = client.get_success_response_bytes(req?).await?;
+            let channel = serde_json::from_slice::<model::Channel>(&bytes)?;
+            Ok(Channel::from_channel(&bytes, channel))
+        }
+    }
+    // Fetch a guild the bot is in, e.g. to get its name
+    pub fn get_guild(&self, guild_id: &str) -> impl Future<Output=Result<Guild, Error>> + Send + 'static {
+        let req = Request::get(format!("{}/guilds/{}", self.api_base, guild_id))
+            .header(http::header::AUTHORIZATION, self.auth_header.clone())
+            .body(Body::empty());
+        let client = self.client.clone();
+        async move {
+            let bytes = client.get_success_response_bytes(req?).await?;
+            let guild = serde_json::from_slice::<model::Guild>(&bytes)?;
+            Ok(Guild::from_guild(&bytes, guild))
+        }
+    }
+    // Fetch a single message, e.g. to see if it still exists or to get its
+    // content after it's been edited
+    pub fn get_channel_message(&self, channel_id: &str, message_id: &str) -> impl Future<Output=Result<Message, Error>> + Send + 'static {
+        let uri = format!("{}/channels/{}/messages/{}", self.api_base, channel_id, message_id);
+        let req = Request::get(uri)
+            .header(http::header::AUTHORIZATION, self.auth_header.clone())
+            .body(Body::empty());
+        let client = self.client.clone();
+        let user_id = self.user_id.clone();
+        async move {
+            let bytes = client.get_success_response_bytes(req?).await?;
+            let message = serde_json::from_slice::<model::MessageReceived>(&bytes)?;
+            // Messages from the API always have an author, content and
+            // timestamp, the content is just empty if we can't see it
+            Message::from_message_received(&bytes, bytes.clone(), message, &user_id)
+                .ok_or_else(|| Error::Serde(serde::de::Error::custom("message has no author, content or timestamp")))
+        }
+    }
+    // Replace the content of a message the bot sent earlier
+    pub fn edit_message(&self, channel_id: &str, message_id: &str, content: &str) -> impl Future<Output=Result<(), Error>> + Send + 'static {
+        let uri = format!("{}/channels/{}/messages/{}", self.api_base, channel_id, message_id);
+        let length = content.chars().count();
+        let req: Result<Request<Body>, Error> = if length > MAX_MESSAGE_LENGTH {
+            Err(Error::MessageTooLong(length))
+        } else {
+            try {
+                let body = serde_json::to_string(&model::EditMessageRequest { content: Some(content), embeds: None }).map_err(Error::from)?;
+                Request::patch(uri)
+                    .header(http::header::AUTHORIZATION, self.auth_header.clone())
+                    .header(http::header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body)).map_err(Error::from)?
+            }
+        };
+        let client = self.client.clone();
+        async move {
+            client.get_success_response(req?).await.map(|_| ())
+        }
+    }
+    // Replace the embeds on a message, leaving its content as it is
+    pub fn edit_message_embeds(&self, channel_id: &str, message_id: &str, embeds: &[Embed]) -> impl Future<Output=Result<(), Error>> + Send + 'static {
+        let uri = format!("{}/channels/{}/messages/{}", self.api_base, channel_id, message_id);
+        let req: Result<Request<Body>, Error> = try {
+            let body = serde_json::to_string(&model::EditMessageRequest { content: None, embeds: Some(embeds) }).map_err(Error::from)?;
+            Request::patch(uri)
+                .header(http::header::AUTHORIZATION, self.auth_header.clone())
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body)).map_err(Error::from)?
+        };
+        let client = self.client.clone();
+        async move {
+            client.get_success_response(req?).await.map(|_| ())
+        }
+    }
+    // Deleting a message that's already gone fails with a BadApiRequest like
+    // any other error response, so check its body for Discord's "Unknown
+    // Message" error code (10008) to tell the two apart
+    pub fn delete_message(&self, channel_id: &str, message_id: &str) -> impl Future<Output=Result<(), Error>> + Send + 'static {
+        let uri = format!("{}/channels/{}/messages/{}", self.api_base, channel_id, message_id);
+        let req = Request::delete(uri)
+            .header(http::header::AUTHORIZATION, self.auth_header.clone())
+            .body(Body::empty());
+        let client = self.client.clone();
+        async move {
+            client.get_success_response(req?).await.map(|_| ())
+        }
+    }
+    // Pin a message in its channel, this needs the MANAGE_MESSAGES permission
+    // and a channel can have at most 50 pins
+    pub fn pin_message(&self, channel_id: &str, message_id: &str) -> impl Future<Output=Result<(), Error>> + Send + 'static {
+        let uri = format!("{}/channels/{}/pins/{}", self.api_base, channel_id, message_id);
+        let req = Request::put(uri)
+            .header(http::header::AUTHORIZATION, self.auth_header.clone())
+            .header(http::header::CONTENT_LENGTH, 0)
+            .body(Body::empty());
+
+        let client = self.client.clone();
+        async move {
+            client.get_success_response(req?).await.map(|_| ())
+        }
+    }
+    pub fn unpin_message(&self, channel_id: &str, message_id: &str) -> impl Future<Output=Result<(), Error>> + Send + 'static {
+        let uri = format!("{}/channels/{}/pins/{}", self.api_base, channel_id, message_id);
+        let req = Request::delete(uri)
+            .header(http::header::AUTHORIZATION, self.auth_header.clone())
+            .header(http::header::CONTENT_LENGTH, 0)
+            .body(Body::empty());
+
+        let client = self.client.clone();
+        async move {
+            client.get_success_response(req?).await.map(|_| ())
+        }
+    }
+    // Every pinned message in a channel, newest first
+    pub fn get_pinned_messages(&self, channel_id: &str) -> impl Future<Output=Result<Vec<Message>, Error>> + Send + 'static {
+        let uri = format!("{}/channels/{}/pins", self.api_base, channel_id);
+        let req = Request::get(uri)
+            .header(http::header::AUTHORIZATION, self.auth_header.clone())
+            .body(Body::empty());
+        let client = self.client.clone();
+        let user_id = self.user_id.clone();
+        async move {
+            let bytes = client.get_success_response_bytes(req?).await?;
+            let mut messages = Vec::new();
+            for raw in serde_json::from_slice::<Vec<&RawValue>>(&bytes)? {
+                messages.extend(Message::from_raw(&bytes, raw, &user_id)?);
+            }
+            Ok(messages)
+        }
+    }
+    // Delete between 2 and 100 messages from a channel in one request. None of
+    // them can be more than 14 days old, Discord rejects the whole request if
+    // any of them are
+    pub fn bulk_delete_messages(&self, channel_id: &str, message_ids: &[&str]) -> impl Future<Output=Result<(), Error>> + Send + 'static {
+        const MAX_AGE: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+        let uri = format!("{}/channels/{}/messages/bulk-delete", self.api_base, channel_id);
+        let req: Result<Request<Body>, Error> = try {
+            if !(2..=100).contains(&message_ids.len()) {
+                Err(Error::InvalidArgument("bulk delete needs between 2 and 100 message ids".into()))?;
+            }
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as i64);
+            let oldest = now - MAX_AGE.as_millis() as i64;
+            if message_ids.iter().any(|id| snowflake_timestamp(id).is_none_or(|t| t < oldest)) {
+                Err(Error::InvalidArgument("bulk delete can only delete messages from the last 14 days".into()))?;
+            }
+
+            let body = serde_json::to_string(&model::BulkDeleteRequest { messages: message_ids }).map_err(Error::from)?;
+            Request::post(uri)
+                .header(http::header::AUTHORIZATION, self.auth_header.clone())
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body)).map_err(Error::from)?
+        };
+        let client = self.client.clone();
+        async move {
+            client.get_success_response(req?).await.map(|_| ())
+        }
+    }
+    // Change the bot's username and/or avatar, the avatar is the raw bytes of
+    // a PNG, JPEG or GIF image
+    pub fn modify_current_user(&self, username: Option<&str>, avatar: Option<&[u8]>) -> impl Future<Output=Result<(), Error>> + Send + 'static {
+        // Discord wants the avatar as a data URI, so work out the image type
+        // from its magic number
+        fn avatar_data_uri(avatar: &[u8]) -> String {
+            let mime = if avatar.starts_with(b"\xFF\xD8\xFF") {
+                "image/jpeg"
+            } else if avatar.starts_with(b"GIF8") {
+                "image/gif"
+            } else {
+                "image/png"
+            };
+            format!("data:{};base64,{}", mime, base64::encode(avatar))
+        }
+
+        let req: Result<Request<Body>, Error> = try {
+            let body = serde_json::to_string(&model::ModifyCurrentUserRequest {
+                username,
+                avatar: avatar.map(avatar_data_uri),
+            }).map_err(Error::from)?;
+            Request::patch(format!("{}/users/@me", self.api_base))
+                .header(http::header::AUTHORIZATION, self.auth_header.clone())
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body)).map_err(Error::from)?
+        };
+        let client = self.client.clone();
+        async move {
+            client.get_success_response(req?).await.map(|_| ())
+        }
+    }
+    // Open a DM channel with a user, giving back the channel's id to send
+    // messages to. If there's already a DM channel with them that one is
+    // given back instead
+    pub fn create_dm(&self, recipient_id: &str) -> impl Future<Output=Result<String, Error>> + Send + 'static {
+        let req: Result<Request<Body>, Error> = try {
+            let body = serde_json::to_string(&model::CreateDmRequest { recipient_id }).map_err(Error::from)?;
+            Request::post(format!("{}/users/@me/channels", self.api_base))
+                .header(http::header::AUTHORIZATION, self.auth_header.clone())
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body)).map_err(Error::from)?
+        };
+        let client = self.client.clone();
+        async move {
+            let bytes = client.get_success_response_bytes(req?).await?;
+            let channel = serde_json::from_slice::<model::Channel>(&bytes)?;
+            Ok(channel.id.into_owned())
+        }
+    }
+    // Create a thread from an existing message, returning the new thread.
+    // auto_archive_duration is how many minutes of inactivity before the
+    // thread is archived, one of 60, 1440, 4320 or 10080
+    pub fn start_thread_from_message(&self, channel_id: &str, message_id: &str, name: &str, auto_archive_duration: Option<u32>) -> impl Future<Output=Result<Channel, Error>> + Send + 'static {
+        let uri = format!("{}/channels/{}/messages/{}/threads", self.api_base, channel_id, message_id);
+        let req: Result<Request<Body>, Error> = try {
+            let body = serde_json::to_string(&model::StartThreadRequest { name, auto_archive_duration }).map_err(Error::from)?;
+            Request::post(uri)
+                .header(http::header::AUTHORIZATION, self.auth_header.clone())
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body)).map_err(Error::from)?
+        };
+        let client = self.client.clone();
+        async move {
+            let bytes = client.get_success_response_bytes(req?).await?;
+            let response = serde_json::from_slice::<model::Channel>(&bytes)?;
+            Ok(Channel::from_channel(&bytes, response))
+        }
+    }
+    // Depending on a thread's settings, the bot may need to join it before it
+    // receives any of the messages sent in it
+    pub fn join_thread(&self, channel_id: &str) -> impl Future<Output=Result<(), Error>> + Send + 'static {
+        let uri = format!("{}/channels/{}/thread-members/@me", self.api_base, channel_id);
+        let req = Request::put(uri)
+            .header(http::header::AUTHORIZATION, self.auth_header.clone())
+            .header(http::header::CONTENT_LENGTH, 0)
+            .body(Body::empty());
+
+        let client = self.client.clone();
+        async move {
+            client.get_success_response(req?).await.map(|_| ())
+        }
+    }
+    pub fn leave_thread(&self, channel_id: &str) -> impl Future<Output=Result<(), Error>> + Send + 'static {
+        let uri = format!("{}/channels/{}/thread-members/@me", self.api_base, channel_id);
+        let req = Request::delete(uri)
+            .header(http::header::AUTHORIZATION, self.auth_header.clone())
+            .body(Body::empty());
+
+        let client = self.client.clone();
+        async move {
+            client.get_success_response(req?).await.map(|_| ())
+        }
+    }
+    // Listing the members of a thread needs the GUILD_MEMBERS intent
+    pub fn get_thread_members(&self, channel_id: &str) -> impl Future<Output=Result<Vec<ThreadMember>, Error>> + Send + 'static {
+        let uri = format!("{}/channels/{}/thread-members", self.api_base, channel_id);
+        let req = Request::get(uri)
+            .header(http::header::AUTHORIZATION, self.auth_header.clone())
+            .body(Body::empty());
+
+        let client = self.client.clone();
+        async move {
+            let mut members = Vec::new();
+            client.for_each_array_element(req?, |bytes| {
+                let member = serde_json::from_slice::<model::ThreadMember>(&bytes)?;
+                members.push(ThreadMember::from_thread_member(&bytes, member));
+                Ok(())
+            }).await?;
+            Ok(members)
+        }
+    }
+    // max_age is in seconds and max_uses is a count, 0 for either means the
+    // invite never expires/has unlimited uses. Temporary invites only grant
+    // temporary membership
+    pub fn create_invite(&self, channel_id: &str, max_age: u32, max_uses: u32, temporary: bool) -> impl Future<Output=Result<Invite, Error>> + Send + 'static {
+        let uri = format!("{}/channels/{}/invites", self.api_base, channel_id);
+        let req: Result<Request<Body>, Error> = try {
+            let body = serde_json::to_string(&model::CreateInviteRequest { max_age, max_uses, temporary }).map_err(Error::from)?;
+            Request::post(uri)
+                .header(http::header::AUTHORIZATION, self.auth_header.clone())
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body)).map_err(Error::from)?
+        };
+        let client = self.client.clone();
+        async move {
+            let bytes = client.get_success_response_bytes(req?).await?;
+            let response = serde_json::from_slice::<model::InviteResponse>(&bytes)?;
+            Ok(Invite::from_invite_response(&bytes, response))
+        }
+    }
+    pub fn delete_invite(&self, code: &str) -> impl Future<Output=Result<(), Error>> + Send + 'static {
+        let uri = format!("{}/invites/{}", self.api_base, code);
+        let req = Request::delete(uri)
+            .header(http::header::AUTHORIZATION, self.auth_header.clone())
+            .body(Body::empty());
+
+        let client = self.client.clone();
+        async move {
+            client.get_success_response(req?).await.map(|_| ())
+        }
+    }
+    // Page through up to limit of a channel's messages in the given direction
+    pub fn channel_messages(&self, channel_id: &str, limit: usize, direction: Direction) -> ChannelMessages {
+        ChannelMessages::new(Pages {
+            auth_header: self.auth_header.clone(),
+            base_uri: format!("{}/channels/{}/messages", self.api_base, channel_id),
+            client: self.client.clone(),
+            remaining: limit,
+            direction,
+            next_res: None,
+            rate_limiter: None,
+            user_id: self.user_id.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        collections::VecDeque,
+        sync::Mutex,
+    };
+    use futures::stream::TryStreamExt;
+    use tokio::io::{
+        duplex,
+        DuplexStream,
+    };
+
+    const TOKEN: &str = "test-token";
+
+    // A connector which hands out the client ends of in-memory connections
+    // in order, the test plays the gateway on the other ends
+    fn mock_connector(connections: Vec<DuplexStream>) -> GatewayConnector {
+        let connections = Mutex::new(VecDeque::from(connections));
+        GatewayConnector(Box::new(move |_| {
+            let connection = connections.lock().unwrap().pop_front();
+            async move {
+                let stream = connection.expect("tried to connect too many times");
+                Ok((Box::new(stream) as BoxedStream, ws::Extensions::default()))
+            }.boxed()
+        }))
+    }
+
+    async fn send(server: &mut DuplexStream, message: ws::Message<'_>) {
+        message.write(server, ws::message::Context::Server).await.unwrap();
+    }
+    async fn send_json(server: &mut DuplexStream, json: serde_json::Value) {
+        send(server, ws::Message::Text(&json.to_string())).await;
+    }
+    async fn recv_json(server: &mut DuplexStream) -> serde_json::Value {
+        let owned_message = ws::message::Owned::read(server).await.unwrap();
+        match owned_message.message() {
+            ws::Message::Text(t) => serde_json::from_str(t).unwrap(),
+            m => panic!("expected a text message, got {:?}", m),
+        }
+    }
+
+    fn hello() -> serde_json::Value {
+        serde_json::json!({ "op": 10, "d": { "heartbeat_interval": 45000 } })
+    }
+    fn message_create(seq: u64, id: &str, content: &str) -> serde_json::Value {
+        serde_json::json!({
+            "op": 0,
+            "s": seq,
+            "t": "MESSAGE_CREATE",
+            "d": {
+                "id": id,
+                "channel_id": "100",
+                "content": content,
+                "author": { "id": "200", "username": "someone", "discriminator": "0" },
+                "timestamp": "2015-12-31T23:59:59.999000+00:00",
+                "mentions": [],
+            },
+        })
+    }
+
+    fn ready(seq: u64, session_id: &str) -> serde_json::Value {
+        serde_json::json!({
+            "op": 0,
+            "s": seq,
+            "t": "READY",
+            "d": { "session_id": session_id, "user": { "id": "1", "username": "bot" } },
+        })
+    }
+
+    async fn connect(connections: Vec<DuplexStream>) -> Discord {
+        connect_with_options(connections, ConnectOptions::new()).await
+    }
+    async fn connect_with_options(connections: Vec<DuplexStream>, options: ConnectOptions) -> Discord {
+        let client = HttpsClient::new(HttpsConnector::new().unwrap().allow_plaintext(options.is_plaintext()));
+        let auth_header = http::HeaderValue::from_static("Bot test-token");
+        Discord::connect_with(client, auth_header, mock_connector(connections), TOKEN, None, options)
+            .await
+            .unwrap()
+    }
+    // Connect with a gateway that only says hello and READY, and the REST API
+    // pointed at the given base url
+    async fn connect_rest(base_url: &str) -> Discord {
+        let (client, mut server) = duplex(1 << 16);
+        send_json(&mut server, hello()).await;
+        send_json(&mut server, ready(1, "session-1")).await;
+        connect_with_options(vec![client], ConnectOptions::new().base_url(base_url)).await
+    }
+
+    // Play the REST API over plain HTTP, answering each request with the next
+    // of the given responses. Each request's request line and body are sent
+    // back for the test to check
+    async fn mock_api(responses: Vec<String>) -> (String, tokio::sync::mpsc::UnboundedReceiver<(String, Vec<u8>)>) {
+        use tokio::io::{
+            AsyncBufReadExt,
+            AsyncReadExt,
+            BufReader,
+        };
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}/api", listener.local_addr().unwrap());
+        let (requests, received) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut responses = VecDeque::from(responses);
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            while let Some(response) = responses.pop_front() {
+                let mut request_line = String::new();
+                stream.read_line(&mut request_line).await.unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    stream.read_line(&mut line).await.unwrap();
+                    match line.to_ascii_lowercase().trim_end().split_once(": ") {
+                        Some(("content-length", len)) => content_length = len.parse().unwrap(),
+                        Some(_) => (),
+                        None => break,
+                    }
+                }
+                let mut body = vec![0; content_length];
+                stream.read_exact(&mut body).await.unwrap();
+                requests.send((String::from(request_line.trim_end()), body)).unwrap();
+                stream.get_mut().write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (base_url, received)
+    }
+
+    fn rate_limit_headers(remaining: &'static str, reset_after: &'static str) -> http::HeaderMap {
+        let mut headers = http::HeaderMap::new();
+        headers.insert("x-ratelimit-remaining", http::HeaderValue::from_static(remaining));
+        headers.insert("x-ratelimit-reset-after", http::HeaderValue::from_static(reset_after));
+        headers
+    }
+
+    #[test]
+    fn encode_emoji_for_uri() {
+        assert_eq!(encode_emoji("\u{1F44D}"), "%F0%9F%91%8D");
+        assert_eq!(encode_emoji("party_parrot:123456"), "party_parrot:123456");
+    }
+
+    #[test]
+    fn api_version_defaults_to_6() {
+        assert_eq!(ConnectOptions::new().version(), 6);
+        assert_eq!(ConnectOptions::new().api_version(10).api_base(), "https://discord.com/api/v10");
+    }
+
+    #[test]
+    fn shard_id_must_be_below_count() {
+        assert!(ConnectOptions::new().shard(1, 2).validate().is_ok());
+        assert!(matches!(ConnectOptions::new().shard(2, 2).validate(), Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn message_mentions() {
+        let bytes = Bytes::from(serde_json::json!({
+            "id": "1",
+            "channel_id": "2",
+            "content": "<@3> <@4>",
+            "author": { "id": "5" },
+            "timestamp": "2015-12-31T23:59:59.999000+00:00",
+            "mentions": [{ "id": "3" }, { "id": "4" }],
+        }).to_string());
+        let raw = serde_json::from_slice::<&RawValue>(&bytes).unwrap();
+        let message = Message::from_raw(&bytes, raw, b"4").unwrap().unwrap();
+        assert!(message.mentioned());
+        assert_eq!(message.mentioned_ids().collect::<Vec<_>>(), ["3", "4"]);
+        assert!(!message.mentions_everyone());
+        assert_eq!(message.timestamp_unix(), Some(1451606399999));
+    }
+
+    #[test]
+    fn message_lines() {
+        let bytes = Bytes::from(serde_json::to_string(&message_create(0, "1", "one\ntwo\n")["d"]).unwrap());
+        let raw = serde_json::from_slice::<&RawValue>(&bytes).unwrap();
+        let message = Message::from_raw(&bytes, raw, b"1").unwrap().unwrap();
+        assert_eq!(message.message_lines().collect::<Vec<_>>(), ["one", "two", ""]);
+        assert_eq!(message.message_bytes_lines().collect::<Vec<_>>(), [&b"one"[..], b"two", b""]);
+    }
+
+    #[test]
+    fn message_equality() {
+        let from_json = |json: serde_json::Value| {
+            let bytes = Bytes::from(serde_json::to_string(&json).unwrap());
+            let raw = serde_json::from_slice::<&RawValue>(&bytes).unwrap();
+            Message::from_raw(&bytes, raw, b"1").unwrap().unwrap()
+        };
+        let message = from_json(message_create(0, "1", "hi")["d"].clone());
+        assert_eq!(message, from_json(message_create(0, "1", "hi")["d"].clone()));
+        assert_eq!(message, message.clone());
+        assert_ne!(message, from_json(message_create(0, "1", "bye")["d"].clone()));
+    }
+
+    #[test]
+    fn message_is_dm() {
+        let from_json = |json: serde_json::Value| {
+            let bytes = Bytes::from(serde_json::to_string(&json).unwrap());
+            let raw = serde_json::from_slice::<&RawValue>(&bytes).unwrap();
+            Message::from_raw(&bytes, raw, b"1").unwrap().unwrap()
+        };
+        let mut json = message_create(0, "1", "hi")["d"].clone();
+        assert!(from_json(json.clone()).is_dm());
+        json["guild_id"] = serde_json::json!("300");
+        assert!(!from_json(json).is_dm());
+    }
+
+    #[test]
+    fn parse_intents() {
+        assert_eq!("GUILD_MESSAGES,DIRECT_MESSAGES".parse::<Intents>().unwrap(), Intents::GUILD_MESSAGES | Intents::DIRECT_MESSAGES);
+        assert_eq!(" guilds , Guild_Members ".parse::<Intents>().unwrap(), Intents::GUILDS | Intents::GUILD_MEMBERS);
+        assert_eq!("".parse::<Intents>().unwrap(), Intents::empty());
+        assert!(matches!("GUILDS,MESSAGE".parse::<Intents>(), Err(Error::UnknownIntent(name)) if name == "MESSAGE"));
+    }
+
+    #[test]
+    fn unprivileged_intents() {
+        let intents = Intents::all_unprivileged();
+        assert!(!intents.intersects(Intents::GUILD_MEMBERS | Intents::GUILD_PRESENCES));
+        assert_eq!(intents | Intents::GUILD_MEMBERS | Intents::GUILD_PRESENCES, Intents::all());
+    }
+
+    #[test]
+    fn split_long_messages() {
+        assert_eq!(split_message("one two\nthree four", 12), ["one two", "three four"]);
+        assert_eq!(split_message("one two three four", 12), ["one two", "three four"]);
+        assert_eq!(split_message("onetwothreefour", 6), ["onetwo", "threef", "our"]);
+        assert_eq!(split_message("ééé ééé", 4), ["ééé", "ééé"]);
+        assert_eq!(split_message("short", 12), ["short"]);
+        assert!(split_message(" \n ", 12).is_empty());
+    }
+
+    #[test]
+    fn direction_moves_past_messages() {
+        let mut before = Direction::Before(None);
+        assert_eq!(before.query(), None);
+        before.advance("10");
+        assert_eq!(before.query(), Some(("before", "10")));
+
+        let mut after = Direction::After(String::from("10"));
+        after.advance("11");
+        assert_eq!(after.query(), Some(("after", "11")));
+
+        let mut around = Direction::Around(String::from("10"));
+        around.advance("11");
+        assert_eq!(around.query(), Some(("around", "10")));
+    }
+
+    #[test]
+    fn safe_message_mentions_nobody() {
+        let request = model::CreateMessageRequest { content: Some("@everyone"), allowed_mentions: Some(model::AllowedMentions::NONE), ..Default::default() };
+        assert_eq!(
+            serde_json::to_value(&request).unwrap(),
+            serde_json::json!({ "content": "@everyone", "allowed_mentions": { "parse": [], "replied_user": false } })
+        );
+    }
+
+    #[test]
+    fn multipart_with_payload() {
+        let body = multipart_body("XYZ", "a\"b.txt", "text/plain", b"hello", Some(r#"{"content":"hi"}"#));
+        assert_eq!(
+            &body[..],
+            &b"--XYZ\r\n\
+               Content-Disposition: form-data; name=\"payload_json\"\r\n\
+               Content-Type: application/json\r\n\r\n\
+               {\"content\":\"hi\"}\r\n\
+               --XYZ\r\n\
+               Content-Disposition: form-data; name=\"files[0]\"; filename=\"a_b.txt\"\r\n\
+               Content-Type: text/plain\r\n\r\n\
+               hello\r\n\
+               --XYZ--\r\n"[..]
+        );
+    }
+
+    #[test]
+    fn multipart_content_type_stays_one_header() {
+        let body = multipart_body("XYZ", "a.txt", "text/plain\r\nX-Injected: yes", b"", None);
+        assert_eq!(
+            &body[..],
+            &b"--XYZ\r\n\
+               Content-Disposition: form-data; name=\"files[0]\"; filename=\"a.txt\"\r\n\
+               Content-Type: text/plainX-Injected: yes\r\n\r\n\
+               \r\n\
+               --XYZ--\r\n"[..]
+        );
+    }
+
+    #[test]
+    fn rate_limit_only_waits_when_exhausted() {
+        assert_eq!(rate_limit_delay(&rate_limit_headers("4", "1.5")), None);
+        assert_eq!(rate_limit_delay(&rate_limit_headers("0", "1.5")), Some(Duration::from_millis(1500)));
+        assert_eq!(rate_limit_delay(&rate_limit_headers("0", "nonsense")), None);
+        assert_eq!(rate_limit_delay(&http::HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn snowflake_creation_time() {
+        assert_eq!(snowflake_timestamp("175928847299117063"), Some(1462015105796));
+        assert_eq!(snowflake_timestamp("0"), Some(DISCORD_EPOCH));
+        assert_eq!(snowflake_timestamp("not an id"), None);
+    }
+
+    #[test]
+    fn parse_timestamps() {
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00+00:00"), Some(0));
+        assert_eq!(parse_timestamp("2015-12-31T23:59:59.999000+00:00"), Some(1451606399999));
+        assert_eq!(parse_timestamp("2024-02-29T12:00:00.5Z"), Some(1709208000500));
+        assert_eq!(parse_timestamp("2024-02-29T13:30:00+01:30"), Some(1709208000000));
+        assert_eq!(parse_timestamp("2024-02-29 12:00:00"), None);
+        assert_eq!(parse_timestamp("2024-13-01T00:00:00+00:00"), None);
+        assert_eq!(parse_timestamp("2024-01-01T00:00:00"), None);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let mut rng = rand::thread_rng();
+        for (attempt, max) in [(0, 1), (1, 2), (3, 8), (10, 60), (100, 60)] {
+            let delay = backoff_delay(&mut rng, attempt);
+            assert!(delay >= Duration::from_secs(max) / 2 && delay <= Duration::from_secs(max), "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn rate_limited_error() {
+        let mut headers = http::HeaderMap::new();
+        let body = Bytes::from_static(br#"{"message":"You are being rate limited.","retry_after":0.25,"global":true}"#);
+        assert!(matches!(
+            api_error(http::StatusCode::TOO_MANY_REQUESTS, &headers, body.clone()),
+            Error::RateLimited { retry_after, global: true } if retry_after == Duration::from_millis(250)
+        ));
+
+        headers.insert("retry-after", http::HeaderValue::from_static("2"));
+        assert!(matches!(
+            api_error(http::StatusCode::TOO_MANY_REQUESTS, &headers, Bytes::new()),
+            Error::RateLimited { retry_after, global: false } if retry_after == Duration::from_secs(2)
+        ));
+        assert!(matches!(api_error(http::StatusCode::BAD_REQUEST, &headers, body), Error::BadApiRequest(_)));
+    }
+
+    #[tokio::test]
+    async fn answers_ping_with_pong() {
+        let (client, mut server) = duplex(1 << 16);
+
+        send_json(&mut server, hello()).await;
+        send_json(&mut server, ready(1, "session-1")).await;
+        send(&mut server, ws::Message::Ping(b"are you there")).await;
+        send_json(&mut server, message_create(2, "10", "after ping")).await;
+
+        let mut discord = connect(vec![client]).await;
+        let identify = recv_json(&mut server).await;
+        assert_eq!(identify["op"], 2);
+
+        let message = discord.next().await.unwrap();
+        assert_eq!(message.message(), "after ping");
+
+        let pong = ws::message::Owned::read(&mut server).await.unwrap();
+        assert_eq!(pong.message(), ws::Message::Pong(b"are you there"));
+    }
+
+    #[tokio::test]
+    async fn send_message_to_mock_api() {
+        let (base_url, mut requests) = mock_api(vec![String::from("HTTP/1.1 204 No Content\r\n\r\n")]).await;
+        let discord = connect_rest(&base_url).await;
+
+        discord.send_message("100", "hello").await.unwrap();
+        let (request_line, body) = requests.recv().await.unwrap();
+        assert_eq!(request_line, "POST /api/v6/channels/100/messages HTTP/1.1");
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap(), serde_json::json!({ "content": "hello" }));
+    }
+
+    #[tokio::test]
+    async fn send_chunked_message() {
+        const SENT: &str = "HTTP/1.1 204 No Content\r\n\r\n";
+        let (base_url, mut requests) = mock_api(vec![
+            String::from(SENT),
+            String::from(SENT),
+            String::from(SENT),
+            String::from("HTTP/1.1 403 Forbidden\r\ncontent-length: 2\r\n\r\n{}"),
+        ]).await;
+        let discord = connect_rest(&base_url).await;
+
+        let first = "a".repeat(MAX_MESSAGE_LENGTH - 10);
+        let second = "b".repeat(20);
+        discord.send_message_chunked("100", &format!("{}\n{}", first, second)).await.unwrap();
+        for part in [first, second] {
+            let (_, body) = requests.recv().await.unwrap();
+            assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap()["content"], part);
+        }
+
+        let failed = discord.send_message_chunked("100", &"c ".repeat(MAX_MESSAGE_LENGTH)).await;
+        assert!(matches!(failed, Err(Error::PartiallySent { sent: 1, .. })));
+    }
+
+    #[tokio::test]
+    async fn send_content_with_embeds() {
+        const SENT: &str = "HTTP/1.1 204 No Content\r\n\r\n";
+        let (base_url, mut requests) = mock_api(vec![String::from(SENT), String::from(SENT)]).await;
+        let discord = connect_rest(&base_url).await;
+
+        let embeds = [Embed::new().title("first"), Embed::new().title("second")];
+        discord.send_message_with_embeds("100", Some("look"), &embeds).await.unwrap();
+        let (request_line, body) = requests.recv().await.unwrap();
+        assert_eq!(request_line, "POST /api/v6/channels/100/messages HTTP/1.1");
+        let body = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(body["content"], "look");
+        assert_eq!(body["embeds"][0]["title"], "first");
+        assert_eq!(body["embeds"][1]["title"], "second");
+
+        discord.send_embed("100", &embeds[0]).await.unwrap();
+        let (_, body) = requests.recv().await.unwrap();
+        let body = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert!(body.get("content").is_none());
+        assert_eq!(body["embeds"][0]["title"], "first");
+
+        let long = "a".repeat(MAX_MESSAGE_LENGTH + 1);
+        assert!(matches!(discord.send_message_with_embeds("100", Some(&long), &embeds).await, Err(Error::MessageTooLong(_))));
+        let too_many = vec![Embed::new(); MAX_EMBEDS + 1];
+        assert!(matches!(discord.send_message_with_embeds("100", None, &too_many).await, Err(Error::InvalidArgument(_))));
+    }
+
+    #[tokio::test]
+    async fn channel_and_guild_names() {
+        let ok = |json: serde_json::Value| {
+            let body = json.to_string();
+            format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{}", body.len(), body)
+        };
+        let (base_url, mut requests) = mock_api(vec![
+            ok(serde_json::json!({ "id": "100", "type": 0, "guild_id": "300", "name": "general" })),
+            ok(serde_json::json!({ "id": "300", "name": "Some Guild", "roles": [] })),
+        ]).await;
+        let discord = connect_rest(&base_url).await;
+
+        let channel = discord.get_channel("100").await.unwrap();
+        assert_eq!((channel.id(), channel.name(), channel.guild_id()), ("100", Some("general"), Some("300")));
+        assert_eq!(requests.recv().await.unwrap().0, "GET /api/v6/channels/100 HTTP/1.1");
+
+        let guild = discord.get_guild("300").await.unwrap();
+        assert_eq!((guild.id(), guild.name()), ("300", "Some Guild"));
+        assert_eq!(requests.recv().await.unwrap().0, "GET /api/v6/guilds/300 HTTP/1.1");
+    }
+
+    #[tokio::test]
+    async fn retries_when_rate_limited() {
+        const RATE_LIMITED: &str = "HTTP/1.1 429 Too Many Requests\r\nretry-after: 0.01\r\ncontent-length: 0\r\n\r\n";
+        let (base_url, mut requests) = mock_api(vec![
+            String::from(RATE_LIMITED),
+            String::from(RATE_LIMITED),
+            String::from("HTTP/1.1 204 No Content\r\n\r\n"),
+        ]).await;
+        let discord = connect_rest(&base_url).await;
+
+        assert!(matches!(discord.add_reaction("100", "10", "x").await, Err(Error::RateLimited { global: false, .. })));
+        let discord = discord.with_auto_retry(1);
+        discord.add_reaction("100", "10", "x").await.unwrap();
+        for _ in 0..3 {
+            let (request_line, _) = requests.recv().await.unwrap();
+            assert_eq!(request_line, "PUT /api/v6/channels/100/messages/10/reactions/x/@me HTTP/1.1");
+        }
+    }
+
+    #[tokio::test]
+    async fn channel_messages_stream() {
+        fn page(ids: std::ops::Range<u64>) -> String {
+            let messages = ids.rev()
+                .map(|id| message_create(0, &id.to_string(), "old")["d"].clone())
+                .collect::<Vec<_>>();
+            let body = serde_json::to_string(&messages).unwrap();
+            format!("HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}", body.len(), body)
+        }
+
+        let (base_url, mut requests) = mock_api(vec![page(100..200), page(0..1)]).await;
+        let discord = connect_rest(&base_url).await;
+
+        let messages = discord.channel_messages("100", 150, Direction::Before(None))
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(messages.len(), 101);
+        assert_eq!(messages[0].message_id(), "199");
+        assert_eq!(messages[100].message_id(), "0");
+
+        assert_eq!(requests.recv().await.unwrap().0, "GET /api/v6/channels/100/messages?limit=100 HTTP/1.1");
+        assert_eq!(requests.recv().await.unwrap().0, "GET /api/v6/channels/100/messages?limit=50&before=100 HTTP/1.1");
+    }
+
+    #[tokio::test]
+    async fn reactions_page_by_user() {
+        fn page(ids: std::ops::Range<u64>) -> String {
+            let users = ids.map(|id| serde_json::json!({ "id": id.to_string(), "username": "someone" })).collect::<Vec<_>>();
+            let body = serde_json::to_string(&users).unwrap();
+            format!("HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}", body.len(), body)
+        }
+
+        let (base_url, mut requests) = mock_api(vec![page(1..101), page(101..103)]).await;
+        let discord = connect_rest(&base_url).await;
+
+        let users = discord.get_reactions("100", "10", "\u{1F44D}").await.unwrap();
+        assert_eq!(users.len(), 102);
+        assert_eq!(users[101].id(), "102");
+        assert_eq!(users[0].username(), Some("someone"));
+
+        assert_eq!(requests.recv().await.unwrap().0, "GET /api/v6/channels/100/messages/10/reactions/%F0%9F%91%8D?limit=100 HTTP/1.1");
+        assert_eq!(requests.recv().await.unwrap().0, "GET /api/v6/channels/100/messages/10/reactions/%F0%9F%91%8D?limit=100&after=100 HTTP/1.1");
+    }
+
+    #[tokio::test]
+    async fn pins() {
+        let body = serde_json::to_string(&[message_create(0, "10", "pinned")["d"].clone()]).unwrap();
+        let (base_url, mut requests) = mock_api(vec![
+            String::from("HTTP/1.1 204 No Content\r\n\r\n"),
+            format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{}", body.len(), body),
+            String::from("HTTP/1.1 204 No Content\r\n\r\n"),
+        ]).await;
+        let discord = connect_rest(&base_url).await;
+
+        discord.pin_message("100", "10").await.unwrap();
+        let pinned = discord.get_pinned_messages("100").await.unwrap();
+        assert_eq!(pinned.len(), 1);
+        assert_eq!(pinned[0].message(), "pinned");
+        discord.unpin_message("100", "10").await.unwrap();
+
+        assert_eq!(requests.recv().await.unwrap().0, "PUT /api/v6/channels/100/pins/10 HTTP/1.1");
+        assert_eq!(requests.recv().await.unwrap().0, "GET /api/v6/channels/100/pins HTTP/1.1");
+        assert_eq!(requests.recv().await.unwrap().0, "DELETE /api/v6/channels/100/pins/10 HTTP/1.1");
+    }
+
+    #[tokio::test]
+    async fn bulk_delete_checks_ids() {
+        let (client, mut server) = duplex(1 << 16);
+        send_json(&mut server, hello()).await;
+        send_json(&mut server, ready(1, "session-1")).await;
+        let discord = connect(vec![client]).await;
+
+        assert!(matches!(discord.bulk_delete_messages("1", &[]).await, Err(Error::InvalidArgument(_))));
+        assert!(matches!(discord.bulk_delete_messages("1", &["1"; 101]).await, Err(Error::InvalidArgument(_))));
+        // Ids from 2015 are well past the 14 day limit
+        assert!(matches!(discord.bulk_delete_messages("1", &["1", "2"]).await, Err(Error::InvalidArgument(_))));
+    }
+
+    #[tokio::test]
+    async fn unexpected_hello_is_an_error() {
+        let (client, mut server) = duplex(1 << 16);
+        send(&mut server, ws::Message::Binary(b"not a hello")).await;
+
+        let http_client = HttpsClient::new(HttpsConnector::new().unwrap());
+        let auth_header = http::HeaderValue::from_static("Bot test-token");
+        let result = Discord::connect_with(http_client, auth_header, mock_connector(vec![client]), TOKEN, None, ConnectOptions::new()).await;
+        assert!(matches!(result, Err(Error::UnexpectedWebsocketResponse(_))));
+    }
+
+    #[tokio::test]
+    async fn fatal_close_while_connecting() {
+        let connect = |client| {
+            let http_client = HttpsClient::new(HttpsConnector::new().unwrap());
+            let auth_header = http::HeaderValue::from_static("Bot test-token");
+            Discord::connect_with(http_client, auth_header, mock_connector(vec![client]), TOKEN, None, ConnectOptions::new())
+        };
+
+        // Instead of a hello
+        let (client, mut server) = duplex(1 << 16);
+        send(&mut server, ws::Message::Close(Some((4014, "Disallowed intent(s)")))).await;
+        assert!(matches!(connect(client).await, Err(Error::Gateway { code: 4014, .. })));
+
+        // Instead of READY, which is how a bad token is reported
+        let (client, mut server) = duplex(1 << 16);
+        send_json(&mut server, hello()).await;
+        let connecting = tokio::spawn(connect(client));
+        assert_eq!(recv_json(&mut server).await["op"], 2);
+        send(&mut server, ws::Message::Close(Some((4004, "Authentication failed")))).await;
+        match connecting.await.unwrap() {
+            Err(Error::Gateway { code: 4004, reason }) => assert_eq!(reason, "Authentication failed"),
+            r => panic!("expected a gateway error, got {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn close_waits_for_close_frame() {
+        use tokio::io::AsyncReadExt;
+
+        let (client, mut server) = duplex(1 << 16);
+
+        send_json(&mut server, hello()).await;
+        send_json(&mut server, ready(1, "session-1")).await;
+        // Discord may still be sending events when we close
+        send_json(&mut server, message_create(2, "10", "still going")).await;
+        send(&mut server, ws::Message::Close(Some((4000, "")))).await;
+
+        let discord = connect(vec![client]).await;
+        let identify = recv_json(&mut server).await;
+        assert_eq!(identify["op"], 2);
+
+        discord.close(4000, "shutting down").await.unwrap();
+
+        let close = ws::message::Owned::read(&mut server).await.unwrap();
+        assert_eq!(close.message(), ws::Message::Close(Some((4000, "shutting down"))));
+        // Then the stream is shut down
+        assert_eq!(server.read(&mut [0; 1]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn resumes_after_going_away() {
+        let (client1, mut server1) = duplex(1 << 16);
+        let (client2, mut server2) = duplex(1 << 16);
+
+        // The buffers are big enough to queue up everything the gateway sends
+        // ahead of time, so nothing here depends on timing
+        send_json(&mut server1, hello()).await;
+        send_json(&mut server1, ready(1, "session-1")).await;
+        send_json(&mut server1, message_create(2, "10", "before")).await;
+        send(&mut server1, ws::Message::Close(Some((1001, "")))).await;
+
+        send_json(&mut server2, hello()).await;
+        send_json(&mut server2, message_create(3, "11", "after")).await;
+
+        let mut discord = connect(vec![client1, client2]).await;
+        assert_eq!(discord.session_id(), "session-1");
+        assert_eq!(discord.user_id(), "1");
+        assert_eq!(discord.username(), Some("bot"));
+
+        let identify = recv_json(&mut server1).await;
+        assert_eq!(identify["op"], 2);
+        assert_eq!(identify["d"]["token"], TOKEN);
+
+        let before = discord.next().await.unwrap();
+        assert_eq!(before.message_id(), "10");
+        assert_eq!(before.message(), "before");
+        assert_eq!(before.author_username(), Some("someone"));
+        assert_eq!(before.author_discriminator(), Some("0"));
+
+        let after = discord.next().await.unwrap();
+        assert_eq!(after.message_id(), "11");
+        assert_eq!(after.message(), "after");
+
+        // The first thing sent on the new connection should be a resume of
+        // the old session from the last sequence number we saw
+        let resume = recv_json(&mut server2).await;
+        assert_eq!(resume["op"], 6);
+        assert_eq!(resume["d"]["token"], TOKEN);
+        assert_eq!(resume["d"]["session_id"], "session-1");
+        assert_eq!(resume["d"]["seq"], 2);
+    }
+
+    #[tokio::test]
+    async fn resumes_when_asked_to_reconnect() {
+        let (client1, mut server1) = duplex(1 << 16);
+        let (client2, mut server2) = duplex(1 << 16);
+
+        send_json(&mut server1, hello()).await;
+        send_json(&mut server1, ready(1, "session-1")).await;
+        send_json(&mut server1, serde_json::json!({ "op": 7, "d": null })).await;
+
+        send_json(&mut server2, hello()).await;
+        send_json(&mut server2, message_create(2, "10", "after")).await;
+
+        let mut discord = connect(vec![client1, client2]).await;
+        let after = discord.next().await.unwrap();
+        assert_eq!(after.message(), "after");
+
+        let resume = recv_json(&mut server2).await;
+        assert_eq!(resume["op"], 6);
+        assert_eq!(resume["d"]["session_id"], "session-1");
+        assert_eq!(resume["d"]["seq"], 1);
+    }
+
+    #[tokio::test]
+    async fn writer_follows_reconnects() {
+        let (client1, mut server1) = duplex(1 << 16);
+        let (client2, mut server2) = duplex(1 << 16);
+
+        send_json(&mut server1, hello()).await;
+        send_json(&mut server1, ready(1, "session-1")).await;
+
+        let mut discord = connect(vec![client1, client2]).await;
+        let writer = discord.writer();
+        assert_eq!(recv_json(&mut server1).await["op"], 2);
+
+        // Sending from another task while the Discord waits for events
+        let sent = tokio::spawn(async move {
+            writer.request_guild_members("5", "", 0).await.unwrap();
+            writer
+        });
+        let next = tokio::spawn(async move {
+            let msg = discord.next().await.unwrap();
+            (discord, msg)
+        });
+        let writer = sent.await.unwrap();
+        let request = recv_json(&mut server1).await;
+        assert_eq!(request["op"], 8);
+        assert_eq!(request["d"]["guild_id"], "5");
+
+        send_json(&mut server1, serde_json::json!({ "op": 7, "d": null })).await;
+        send_json(&mut server2, hello()).await;
+        send_json(&mut server2, message_create(2, "10", "after")).await;
+        let (_discord, msg) = next.await.unwrap();
+        assert_eq!(msg.message(), "after");
+        assert_eq!(recv_json(&mut server2).await["op"], 6);
+
+        writer.update_presence("idle", None).await.unwrap();
+        let presence = recv_json(&mut server2).await;
+        assert_eq!(presence["op"], 3);
+        assert_eq!(presence["d"]["status"], "idle");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn split_reader_and_writer() {
+        let (client, mut server) = duplex(1 << 16);
+
+        send_json(&mut server, hello()).await;
+        send_json(&mut server, ready(3, "session-1")).await;
+
+        let discord = connect(vec![client]).await;
+        assert_eq!(recv_json(&mut server).await["op"], 2);
+        let (mut reader, writer) = discord.split();
+        let reading = tokio::spawn(async move {
+            let msg = reader.next().await.unwrap();
+            (reader, msg)
+        });
+
+        writer.request_guild_members("5", "", 0).await.unwrap();
+        assert_eq!(recv_json(&mut server).await["op"], 8);
+
+        // Heartbeats come from the writer's side, and their acks are read by
+        // the reader along with everything else
+        assert_eq!(recv_json(&mut server).await["op"], 1);
+        send_json(&mut server, serde_json::json!({ "op": 11, "d": null })).await;
+        send_json(&mut server, message_create(4, "10", "hello")).await;
+        let (mut reader, msg) = reading.await.unwrap();
+        assert_eq!(msg.message(), "hello");
+
+        // Once a heartbeat goes unacked the reader finds out
+        let heartbeat = recv_json(&mut server).await;
+        assert_eq!(heartbeat["op"], 1);
+        assert_eq!(heartbeat["d"], 4);
+        assert!(matches!(reader.next().await, Err(Error::NoAck)));
+        assert!(reader.latency().is_some());
+    }
+
+    #[tokio::test]
+    async fn stream_of_messages() {
+        let (client, mut server) = duplex(1 << 16);
+
+        send_json(&mut server, hello()).await;
+        send_json(&mut server, ready(1, "session-1")).await;
+        send_json(&mut server, message_create(2, "10", "first")).await;
+        send_json(&mut server, message_create(3, "11", "second")).await;
+        send(&mut server, ws::Message::Close(Some((4004, "Authentication failed")))).await;
+
+        let messages = connect(vec![client]).await.into_stream().collect::<Vec<_>>().await;
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0].as_ref().unwrap().message(), "first");
+        assert_eq!(messages[1].as_ref().unwrap().message(), "second");
+        assert!(matches!(messages[2], Err(Error::Gateway { code: 4004, .. })));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stream_reconnects_after_errors() {
+        let (client1, mut server1) = duplex(1 << 16);
+        let (client2, mut server2) = duplex(1 << 16);
+
+        send_json(&mut server1, hello()).await;
+        send_json(&mut server1, ready(1, "session-1")).await;
+        send_json(&mut server1, message_create(2, "10", "first")).await;
+
+        let mut messages = Box::pin(connect(vec![client1, client2]).await.into_stream());
+        assert_eq!(messages.next().await.unwrap().unwrap().message(), "first");
+
+        // The heartbeats never get acked, so the connection is given up on.
+        // Rather than giving back the same error forever the stream resumes
+        // on a new connection
+        send_json(&mut server2, hello()).await;
+        send_json(&mut server2, message_create(3, "11", "second")).await;
+        send(&mut server2, ws::Message::Close(Some((4004, "Authentication failed")))).await;
+        assert!(matches!(messages.next().await, Some(Err(Error::NoAck))));
+        assert_eq!(messages.next().await.unwrap().unwrap().message(), "second");
+        assert!(matches!(messages.next().await, Some(Err(Error::Gateway { code: 4004, .. }))));
+        assert!(messages.next().await.is_none());
+        drop(server1);
+    }
+
+    #[tokio::test]
+    async fn fatal_close_code_is_an_error() {
+        let (client, mut server) = duplex(1 << 16);
+
+        send_json(&mut server, hello()).await;
+        send_json(&mut server, ready(1, "session-1")).await;
+        send(&mut server, ws::Message::Close(Some((4004, "Authentication failed")))).await;
+
+        let mut discord = connect(vec![client]).await;
+        match discord.next().await {
+            Err(Error::Gateway { code: 4004, reason }) => assert_eq!(reason, "Authentication failed"),
+            r => panic!("expected a gateway error, got {:?}", r),
+        }
+    }
+
+    // Time is paused so the heartbeat interval passes as soon as everything
+    // is waiting on it, and the ack takes exactly as long as we say
+    #[tokio::test(start_paused = true)]
+    async fn latency_from_heartbeat_ack() {
+        let (client, mut server) = duplex(1 << 16);
+        send_json(&mut server, hello()).await;
+        send_json(&mut server, ready(1, "session-1")).await;
+
+        let mut discord = connect(vec![client]).await;
+        assert_eq!(discord.latency(), None);
+        let next = tokio::spawn(async move {
+            discord.next().await.unwrap();
+            discord
+        });
+        assert_eq!(recv_json(&mut server).await["op"], 2);
+        assert_eq!(recv_json(&mut server).await["op"], 1);
+
+        sleep(Duration::from_millis(50)).await;
+        send_json(&mut server, serde_json::json!({ "op": 11 })).await;
+        send_json(&mut server, message_create(2, "10", "hi")).await;
+        assert_eq!(next.await.unwrap().latency(), Some(Duration::from_millis(50)));
+    }
+
+    #[tokio::test]
+    async fn identify_properties() {
+        for (options, properties) in [
+            (ConnectOptions::new(), ["linux", "tokio", "server"]),
+            (ConnectOptions::new().identify_properties("windows", "bot", "desktop"), ["windows", "bot", "desktop"]),
+        ] {
+            let (client, mut server) = duplex(1 << 16);
+            send_json(&mut server, hello()).await;
+            send_json(&mut server, ready(1, "session-1")).await;
+            let _discord = connect_with_options(vec![client], options).await;
+
+            let identify = recv_json(&mut server).await;
+            assert_eq!(identify["d"]["properties"], serde_json::json!({
+                "$os": properties[0],
+                "$browser": properties[1],
+                "$device": properties[2],
+            }));
+        }
+    }
+
+    // Time is paused so the random wait before identifying again is skipped
+    // over straight away
+    #[tokio::test(start_paused = true)]
+    async fn identifies_again_after_invalid_session() {
+        let (client1, mut server1) = duplex(1 << 16);
+        let (client2, mut server2) = duplex(1 << 16);
+
+        send_json(&mut server1, hello()).await;
+        send_json(&mut server1, ready(1, "session-1")).await;
+        send_json(&mut server1, serde_json::json!({ "op": 9, "d": false })).await;
+
+        send_json(&mut server2, hello()).await;
+        send_json(&mut server2, ready(1, "session-2")).await;
+        send_json(&mut server2, message_create(2, "10", "hello again")).await;
+
+        let mut discord = connect(vec![client1, client2]).await;
+
+        let msg = discord.next().await.unwrap();
+        assert_eq!(msg.message(), "hello again");
+        assert_eq!(discord.session_id(), "session-2");
+
+        let identify = recv_json(&mut server2).await;
+        assert_eq!(identify["op"], 2);
+        assert_eq!(identify["d"]["token"], TOKEN);
+    }
+
+    // Time is paused so the heartbeat interval passes as soon as everything
+    // is waiting on it
+    #[tokio::test(start_paused = true)]
+    async fn heartbeats_in_background() {
+        let (client, mut server) = duplex(1 << 16);
+
+        send_json(&mut server, hello()).await;
+        send_json(&mut server, ready(3, "session-1")).await;
+
+        let mut discord = connect_with_options(vec![client], ConnectOptions::new().background_heartbeat(true)).await;
+        assert_eq!(recv_json(&mut server).await["op"], 2);
+
+        // Nothing is waiting for events, but heartbeats are still sent
+        let heartbeat = recv_json(&mut server).await;
+        assert_eq!(heartbeat["op"], 1);
+        assert_eq!(heartbeat["d"], 3);
 
-        ws::message::Owned::read(stream).await.map_err(Error::from)
+        // No ack has been read by the time the next heartbeat is due, so the
+        // connection is given up on rather than carrying on as if it's fine
+        assert!(matches!(discord.next().await, Err(Error::NoAck)));
     }
 }
@@ -0,0 +1,110 @@
+use serde_derive::Serialize;
+
+// A rich embed attached to a message, built up with the builder methods:
+//
+//     Embed::new()
+//         .title("Status")
+//         .description("Everything is fine")
+//         .color(0x00ff00)
+//         .field("Uptime", "3 days", true)
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Embed {
+    #[serde(skip_serializing_if="Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    url: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    timestamp: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    color: Option<u32>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    footer: Option<EmbedFooter>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    author: Option<EmbedAuthor>,
+    #[serde(skip_serializing_if="Vec::is_empty")]
+    fields: Vec<EmbedField>,
+}
+impl Embed {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn title<T: Into<String>>(mut self, title: T) -> Self {
+        self.title = Some(title.into());
+        self
+    }
+    pub fn description<T: Into<String>>(mut self, description: T) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+    // The link the title points to
+    pub fn url<T: Into<String>>(mut self, url: T) -> Self {
+        self.url = Some(url.into());
+        self
+    }
+    // An ISO 8601 timestamp shown at the bottom of the embed
+    pub fn timestamp<T: Into<String>>(mut self, timestamp: T) -> Self {
+        self.timestamp = Some(timestamp.into());
+        self
+    }
+    // The colour of the bar on the side of the embed, as 0xRRGGBB
+    pub fn color(mut self, color: u32) -> Self {
+        self.color = Some(color);
+        self
+    }
+    pub fn footer<T: Into<String>>(mut self, text: T) -> Self {
+        self.footer = Some(EmbedFooter { text: text.into() });
+        self
+    }
+    pub fn author<T: Into<String>>(mut self, name: T) -> Self {
+        self.author = Some(EmbedAuthor { name: name.into() });
+        self
+    }
+    // Inline fields are shown side by side rather than one per line
+    pub fn field<N: Into<String>, V: Into<String>>(mut self, name: N, value: V, inline: bool) -> Self {
+        self.fields.push(EmbedField {
+            name: name.into(),
+            value: value.into(),
+            inline,
+        });
+        self
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct EmbedFooter {
+    text: String,
+}
+#[derive(Clone, Debug, Serialize)]
+struct EmbedAuthor {
+    name: String,
+}
+#[derive(Clone, Debug, Serialize)]
+struct EmbedField {
+    name: String,
+    value: String,
+    inline: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize_skips_unset_fields() {
+        let embed = Embed::new()
+            .title("Status")
+            .color(0x00ff00)
+            .field("Uptime", "3 days", true);
+        assert_eq!(
+            serde_json::to_value(&embed).unwrap(),
+            serde_json::json!({
+                "title": "Status",
+                "color": 0x00ff00,
+                "fields": [{ "name": "Uptime", "value": "3 days", "inline": true }],
+            })
+        );
+        assert_eq!(serde_json::to_string(&Embed::new()).unwrap(), "{}");
+    }
+}
@@ -0,0 +1,330 @@
+use bytes::Bytes;
+use crate::error::Error;
+use serde_json::value::RawValue;
+use std::{
+    borrow::Cow,
+    str,
+};
+
+use super::{
+    model,
+    Message,
+};
+
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum Event {
+    MessageCreate(Message),
+    // Only updates which include the message's content are sent, other
+    // updates (e.g. a link's embed being loaded) are ignored
+    MessageUpdate(Message),
+    GuildCreate(GuildCreate),
+    GuildMembersChunk(GuildMembersChunk),
+    PollVoteAdd(PollVote),
+    PollVoteRemove(PollVote),
+    ReactionAdd(Reaction),
+    ReactionRemove(Reaction),
+    // Any other dispatch, with its event name
+    Unknown { t: String },
+}
+impl Event {
+    // Parse a gateway dispatch (op 0) payload with the event name `t`, giving
+    // None for events which should be skipped entirely
+    pub(super) fn from_dispatch(t: &str, payload: &str, bytes: &Bytes, uid: &[u8]) -> Result<Option<Self>, Error> {
+        let event = match t {
+            "MESSAGE_CREATE" => {
+                let msg = serde_json::from_str::<model::WsPayload<&RawValue>>(payload)?;
+                match Message::from_raw(bytes, msg.d, uid)? {
+                    Some(msg) => Event::MessageCreate(msg),
+                    None => return Ok(None),
+                }
+            }
+            "MESSAGE_UPDATE" => {
+                let msg = serde_json::from_str::<model::WsPayload<&RawValue>>(payload)?;
+                match Message::from_raw(bytes, msg.d, uid)? {
+                    Some(msg) => Event::MessageUpdate(msg),
+                    None => return Ok(None),
+                }
+            }
+            "GUILD_CREATE" => {
+                let guild = serde_json::from_str::<model::WsPayload<model::GuildCreate>>(payload)?;
+                Event::GuildCreate(GuildCreate::from_guild_create(bytes, guild.d))
+            }
+            "GUILD_MEMBERS_CHUNK" => {
+                let chunk = serde_json::from_str::<model::WsPayload<model::GuildMembersChunk>>(payload)?;
+                Event::GuildMembersChunk(GuildMembersChunk::from_guild_members_chunk(bytes, chunk.d))
+            }
+            "MESSAGE_POLL_VOTE_ADD" => {
+                let vote = serde_json::from_str::<model::WsPayload<model::PollVote>>(payload)?;
+                Event::PollVoteAdd(PollVote::from_poll_vote(bytes, vote.d))
+            }
+            "MESSAGE_POLL_VOTE_REMOVE" => {
+                let vote = serde_json::from_str::<model::WsPayload<model::PollVote>>(payload)?;
+                Event::PollVoteRemove(PollVote::from_poll_vote(bytes, vote.d))
+            }
+            "MESSAGE_REACTION_ADD" => {
+                let reaction = serde_json::from_str::<model::WsPayload<model::Reaction>>(payload)?;
+                Event::ReactionAdd(Reaction::from_reaction(bytes, reaction.d))
+            }
+            "MESSAGE_REACTION_REMOVE" => {
+                let reaction = serde_json::from_str::<model::WsPayload<model::Reaction>>(payload)?;
+                Event::ReactionRemove(Reaction::from_reaction(bytes, reaction.d))
+            }
+            _ => Event::Unknown { t: String::from(t) },
+        };
+        Ok(Some(event))
+    }
+}
+
+// Sent when a guild becomes available, either while connecting or when the
+// bot joins a new guild.
+//
+// Guilds with more members than the `large_threshold` given when connecting
+// are "large", and are sent without their offline members. Use
+// `Discord::request_guild_members` to get the rest of them
+#[derive(Debug)]
+pub struct GuildCreate {
+    guild_id: Bytes,
+    large: bool,
+    unavailable: bool,
+    member_count: Option<u64>,
+}
+impl GuildCreate {
+    fn from_guild_create(bytes: &Bytes, guild: model::GuildCreate) -> Self {
+        Self {
+            large: guild.large.unwrap_or(false),
+            unavailable: guild.unavailable.unwrap_or(false),
+            member_count: guild.member_count,
+
+            guild_id: model::bytes_from_cow(bytes, guild.id),
+        }
+    }
+    pub fn guild_id(&self) -> &str {
+        unsafe { str::from_utf8_unchecked(&self.guild_id) }
+    }
+    pub fn guild_id_buf(&self) -> &Bytes {
+        &self.guild_id
+    }
+    // Whether the guild was sent without its offline members
+    pub fn is_large(&self) -> bool {
+        self.large
+    }
+    // Whether the guild is unavailable due to an outage, in which case none
+    // of the other information is present
+    pub fn is_unavailable(&self) -> bool {
+        self.unavailable
+    }
+    // The total number of members in the guild, including offline ones
+    pub fn member_count(&self) -> Option<u64> {
+        self.member_count
+    }
+}
+
+// One of the chunks of members sent in response to
+// `Discord::request_guild_members`
+#[derive(Debug)]
+pub struct GuildMembersChunk {
+    guild_id: Bytes,
+    member_ids: Vec<Bytes>,
+    chunk_index: u32,
+    chunk_count: u32,
+}
+impl GuildMembersChunk {
+    fn from_guild_members_chunk(bytes: &Bytes, chunk: model::GuildMembersChunk) -> Self {
+        Self {
+            chunk_index: chunk.chunk_index,
+            chunk_count: chunk.chunk_count,
+
+            guild_id: model::bytes_from_cow(bytes, chunk.guild_id),
+            member_ids: chunk.members.into_iter()
+                .map(|m| model::bytes_from_cow(bytes, m.user.id))
+                .collect(),
+        }
+    }
+    pub fn guild_id(&self) -> &str {
+        unsafe { str::from_utf8_unchecked(&self.guild_id) }
+    }
+    pub fn guild_id_buf(&self) -> &Bytes {
+        &self.guild_id
+    }
+    pub fn member_ids(&self) -> impl Iterator<Item=&str> {
+        self.member_ids.iter().map(|b| unsafe { str::from_utf8_unchecked(b) })
+    }
+    pub fn member_ids_buf(&self) -> &[Bytes] {
+        &self.member_ids
+    }
+    // Chunks are numbered from 0 to chunk_count - 1, the last chunk for a
+    // request is the one where chunk_index + 1 == chunk_count
+    pub fn chunk_index(&self) -> u32 {
+        self.chunk_index
+    }
+    pub fn chunk_count(&self) -> u32 {
+        self.chunk_count
+    }
+}
+
+// A vote being added to or removed from a poll, needs the
+// GUILD_MESSAGE_POLLS or DIRECT_MESSAGE_POLLS intent
+#[derive(Debug)]
+pub struct PollVote {
+    user_id: Bytes,
+    channel_id: Bytes,
+    message_id: Bytes,
+    guild_id: Option<Bytes>,
+    answer_id: u32,
+}
+impl PollVote {
+    fn from_poll_vote(bytes: &Bytes, vote: model::PollVote) -> Self {
+        Self {
+            answer_id: vote.answer_id,
+
+            user_id: model::bytes_from_cow(bytes, vote.user_id),
+            channel_id: model::bytes_from_cow(bytes, vote.channel_id),
+            message_id: model::bytes_from_cow(bytes, vote.message_id),
+            guild_id: vote.guild_id.map(|g| model::bytes_from_cow(bytes, g)),
+        }
+    }
+    pub fn user_id(&self) -> &str {
+        unsafe { str::from_utf8_unchecked(&self.user_id) }
+    }
+    pub fn user_id_buf(&self) -> &Bytes {
+        &self.user_id
+    }
+    pub fn channel_id(&self) -> &str {
+        unsafe { str::from_utf8_unchecked(&self.channel_id) }
+    }
+    pub fn channel_id_buf(&self) -> &Bytes {
+        &self.channel_id
+    }
+    pub fn message_id(&self) -> &str {
+        unsafe { str::from_utf8_unchecked(&self.message_id) }
+    }
+    pub fn message_id_buf(&self) -> &Bytes {
+        &self.message_id
+    }
+    pub fn guild_id(&self) -> Option<&str> {
+        unsafe { self.guild_id.as_ref().map(|b| str::from_utf8_unchecked(b)) }
+    }
+    pub fn guild_id_buf(&self) -> Option<&Bytes> {
+        self.guild_id.as_ref()
+    }
+    // Answers are numbered from 1 in the order they were added to the poll
+    pub fn answer_id(&self) -> u32 {
+        self.answer_id
+    }
+}
+
+// A reaction being added to or removed from a message, needs the
+// GUILD_MESSAGE_REACTIONS or DIRECT_MESSAGE_REACTIONS intent
+#[derive(Debug)]
+pub struct Reaction {
+    user_id: Bytes,
+    channel_id: Bytes,
+    message_id: Bytes,
+    guild_id: Option<Bytes>,
+    emoji_id: Option<Bytes>,
+    emoji_name: Option<Bytes>,
+}
+impl Reaction {
+    fn from_reaction(bytes: &Bytes, reaction: model::Reaction) -> Self {
+        Self {
+            user_id: model::bytes_from_cow(bytes, reaction.user_id),
+            channel_id: model::bytes_from_cow(bytes, reaction.channel_id),
+            message_id: model::bytes_from_cow(bytes, reaction.message_id),
+            guild_id: reaction.guild_id.map(|g| model::bytes_from_cow(bytes, g)),
+            emoji_id: reaction.emoji.id.map(|i| model::bytes_from_cow(bytes, i)),
+            emoji_name: reaction.emoji.name.map(|n| model::bytes_from_cow(bytes, n)),
+        }
+    }
+    pub fn user_id(&self) -> &str {
+        unsafe { str::from_utf8_unchecked(&self.user_id) }
+    }
+    pub fn user_id_buf(&self) -> &Bytes {
+        &self.user_id
+    }
+    pub fn channel_id(&self) -> &str {
+        unsafe { str::from_utf8_unchecked(&self.channel_id) }
+    }
+    pub fn channel_id_buf(&self) -> &Bytes {
+        &self.channel_id
+    }
+    pub fn message_id(&self) -> &str {
+        unsafe { str::from_utf8_unchecked(&self.message_id) }
+    }
+    pub fn message_id_buf(&self) -> &Bytes {
+        &self.message_id
+    }
+    pub fn guild_id(&self) -> Option<&str> {
+        unsafe { self.guild_id.as_ref().map(|b| str::from_utf8_unchecked(b)) }
+    }
+    pub fn guild_id_buf(&self) -> Option<&Bytes> {
+        self.guild_id.as_ref()
+    }
+    // Only custom emoji have an id
+    pub fn emoji_id(&self) -> Option<&str> {
+        unsafe { self.emoji_id.as_ref().map(|b| str::from_utf8_unchecked(b)) }
+    }
+    pub fn emoji_id_buf(&self) -> Option<&Bytes> {
+        self.emoji_id.as_ref()
+    }
+    // The unicode emoji itself, or the name of a custom emoji
+    pub fn emoji_name(&self) -> Option<&str> {
+        unsafe { self.emoji_name.as_ref().map(|b| str::from_utf8_unchecked(b)) }
+    }
+    pub fn emoji_name_buf(&self) -> Option<&Bytes> {
+        self.emoji_name.as_ref()
+    }
+    // The emoji in the form `Discord::add_reaction` takes, i.e. the unicode
+    // emoji or name:id for custom emoji
+    pub fn emoji(&self) -> Option<Cow<'_, str>> {
+        match (self.emoji_name(), self.emoji_id()) {
+            (Some(name), Some(id)) => Some(Cow::Owned(format!("{}:{}", name, id))),
+            (Some(name), None) => Some(Cow::Borrowed(name)),
+            (None, _) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dispatch(t: &str, payload: &'static str) -> Option<Event> {
+        Event::from_dispatch(t, payload, &Bytes::from_static(payload.as_bytes()), b"1").unwrap()
+    }
+
+    #[test]
+    fn reaction_add() {
+        let payload = r#"{"op":0,"s":5,"t":"MESSAGE_REACTION_ADD","d":{"user_id":"2","channel_id":"3","message_id":"4","emoji":{"id":"5","name":"party"}}}"#;
+        match dispatch("MESSAGE_REACTION_ADD", payload) {
+            Some(Event::ReactionAdd(reaction)) => {
+                assert_eq!(reaction.user_id(), "2");
+                assert_eq!(reaction.message_id(), "4");
+                assert_eq!(reaction.guild_id(), None);
+                assert_eq!(reaction.emoji().as_deref(), Some("party:5"));
+            }
+            e => panic!("unexpected event {:?}", e),
+        }
+    }
+
+    #[test]
+    fn reaction_remove_unicode() {
+        let payload = r#"{"op":0,"s":6,"t":"MESSAGE_REACTION_REMOVE","d":{"user_id":"2","channel_id":"3","message_id":"4","guild_id":"6","emoji":{"id":null,"name":"👍"}}}"#;
+        match dispatch("MESSAGE_REACTION_REMOVE", payload) {
+            Some(Event::ReactionRemove(reaction)) => {
+                assert_eq!(reaction.guild_id(), Some("6"));
+                assert_eq!(reaction.emoji_id(), None);
+                assert_eq!(reaction.emoji().as_deref(), Some("👍"));
+            }
+            e => panic!("unexpected event {:?}", e),
+        }
+    }
+
+    #[test]
+    fn unknown_event() {
+        match dispatch("TYPING_START", r#"{"op":0,"s":5,"t":"TYPING_START","d":{}}"#) {
+            Some(Event::Unknown { t }) => assert_eq!(t, "TYPING_START"),
+            e => panic!("unexpected event {:?}", e),
+        }
+    }
+}
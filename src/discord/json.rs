@@ -0,0 +1,172 @@
+use bytes::{
+    Bytes,
+    BytesMut,
+};
+use serde::de::Error as _;
+
+// Splits the body of a JSON array response into the bytes of each of its
+// elements as the body arrives, so only the element currently being received
+// has to be kept around rather than the whole body.
+//
+// This doesn't validate the elements themselves, it only tracks enough of
+// the JSON structure (nesting and strings) to know where each element ends,
+// each element still needs to be parsed separately
+#[derive(Debug, Default)]
+pub struct ArraySplitter {
+    // The start of an element which began in a previous chunk
+    partial: BytesMut,
+    state: State,
+    // How deeply nested we are inside the current element
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+enum State {
+    // Before the opening '['
+    #[default]
+    Start,
+    // Inside the array, between elements
+    Between,
+    // Inside an element
+    Element,
+    // After the closing ']'
+    End,
+}
+
+impl ArraySplitter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Feed the next chunk of the body, calling f with every element which
+    // was completed by this chunk
+    pub fn feed<F, E>(&mut self, chunk: Bytes, mut f: F) -> Result<(), E>
+        where F: FnMut(Bytes) -> Result<(), E>,
+              E: From<serde_json::Error>
+    {
+        let mut element_start = 0;
+        for (idx, &b) in chunk.iter().enumerate() {
+            match self.state {
+                State::Start => match b {
+                    b'[' => self.state = State::Between,
+                    b' ' | b'\t' | b'\n' | b'\r' => (),
+                    _ => return Err(serde_json::Error::custom("expected a JSON array").into()),
+                },
+                State::Between => match b {
+                    b']' => self.state = State::End,
+                    b',' | b' ' | b'\t' | b'\n' | b'\r' => (),
+                    _ => {
+                        self.state = State::Element;
+                        element_start = idx;
+                        self.element_byte(b);
+                    }
+                },
+                State::Element => {
+                    if !self.in_string && self.depth == 0 && (b == b',' || b == b']') {
+                        f(self.take_element(&chunk, element_start, idx))?;
+                        self.state = if b == b']' { State::End } else { State::Between };
+                    } else {
+                        self.element_byte(b);
+                    }
+                }
+                State::End => match b {
+                    b' ' | b'\t' | b'\n' | b'\r' => (),
+                    _ => return Err(serde_json::Error::custom("trailing characters after JSON array").into()),
+                },
+            }
+        }
+        if self.state == State::Element {
+            self.partial.extend_from_slice(&chunk[element_start..]);
+        }
+        Ok(())
+    }
+
+    // Check that the whole array was received once the body has finished
+    pub fn finish<E: From<serde_json::Error>>(self) -> Result<(), E> {
+        if self.state == State::End {
+            Ok(())
+        } else {
+            Err(serde_json::Error::custom("JSON array ended prematurely").into())
+        }
+    }
+
+    fn element_byte(&mut self, b: u8) {
+        if self.in_string {
+            if self.escaped {
+                self.escaped = false;
+            } else if b == b'\\' {
+                self.escaped = true;
+            } else if b == b'"' {
+                self.in_string = false;
+            }
+        } else {
+            match b {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => self.depth += 1,
+                // Mismatched brackets will fail to parse later anyway, just
+                // make sure we don't underflow
+                b'}' | b']' => self.depth = self.depth.saturating_sub(1),
+                _ => (),
+            }
+        }
+    }
+
+    fn take_element(&mut self, chunk: &Bytes, start: usize, end: usize) -> Bytes {
+        // If the whole element is in this chunk we can just hand out a slice
+        // of it, otherwise we need to join it onto the parts we've already
+        // received
+        if self.partial.is_empty() {
+            chunk.slice(start..end)
+        } else {
+            self.partial.extend_from_slice(&chunk[start..end]);
+            self.partial.split().freeze()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(chunks: &[&'static [u8]]) -> Result<Vec<Bytes>, serde_json::Error> {
+        let mut splitter = ArraySplitter::new();
+        let mut elements = Vec::new();
+        for chunk in chunks {
+            splitter.feed(Bytes::from_static(chunk), |e| {
+                elements.push(e);
+                Ok::<_, serde_json::Error>(())
+            })?;
+        }
+        splitter.finish::<serde_json::Error>()?;
+        Ok(elements)
+    }
+
+    #[test]
+    fn single_chunk() {
+        let elements = split(&[br#"[{"a":1}, {"b":[2,3]}, "x,]", 4]"#]).unwrap();
+        assert_eq!(elements, [&br#"{"a":1}"#[..], br#"{"b":[2,3]}"#, br#""x,]""#, b"4"]);
+    }
+
+    #[test]
+    fn empty_array() {
+        assert!(split(&[b" [ ] "]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn elements_across_chunks() {
+        let elements = split(&[b"[{\"a\":\"\\", b"\"}\"}", b",{\"b\"", b":2}]"]).unwrap();
+        assert_eq!(elements, [&b"{\"a\":\"\\\"}\"}"[..], b"{\"b\":2}"]);
+    }
+
+    #[test]
+    fn not_an_array() {
+        assert!(split(&[br#"{"a":1}"#]).is_err());
+    }
+
+    #[test]
+    fn unterminated_array() {
+        assert!(split(&[br#"[{"a":1}"#]).is_err());
+    }
+}
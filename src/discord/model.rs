@@ -1,7 +1,14 @@
 use bytes::Bytes;
+use serde::de::IgnoredAny;
 use serde_derive::{Serialize, Deserialize};
 use std::borrow::Cow;
 
+use super::{
+    Activity,
+    Embed,
+    Poll,
+};
+
 pub fn bytes_from_cow(parent: &Bytes, cow: Cow<str>) -> Bytes {
     match cow {
         Cow::Owned(s)    => Bytes::from(s),
@@ -39,7 +46,7 @@ pub struct Identify<'a> {
     #[serde(skip_serializing_if="Option::is_none")]
     pub large_threshold: Option<u16>,
     #[serde(skip_serializing_if="Option::is_none")]
-    pub shard: Option<[i32; 2]>,
+    pub shard: Option<[u32; 2]>,
     #[serde(skip_serializing_if="Option::is_none")]
     pub presence: Option<UpdateStatus<'a>>,
     #[serde(skip_serializing_if="Option::is_none")]
@@ -61,18 +68,10 @@ pub struct UpdateStatus<'a> {
     #[serde(skip_serializing_if="Option::is_none")]
     pub since: Option<u64>,
     #[serde(skip_serializing_if="Option::is_none")]
-    pub game: Option<Activity<'a>>,
+    pub game: Option<&'a Activity>,
     pub status: &'a str,
     pub afk: bool
 }
-#[derive(Deserialize, Serialize)]
-pub struct Activity<'a> {
-    pub name: &'a str,
-    #[serde(rename="type")]
-    pub ty: i32,
-    #[serde(skip_serializing_if="Option::is_none")]
-    pub url: Option<&'a str>,
-}
 #[derive(Deserialize)]
 pub struct Ready<'a> {
     pub session_id: Cow<'a, str>,
@@ -83,10 +82,12 @@ pub struct Ready<'a> {
 #[derive(Deserialize)]
 pub struct User<'a> {
     pub id: Cow<'a, str>,
-    // username: Cow<'a, str>,
-    // discriminator: Cow<'a, str>,
-    // #[serde(skip_serializing_if="Option::is_none")]
-    // avatar: Option<Cow<'a, str>>,
+    #[serde(default)]
+    pub username: Option<Cow<'a, str>>,
+    #[serde(default)]
+    pub discriminator: Option<Cow<'a, str>>,
+    #[serde(default)]
+    pub avatar: Option<Cow<'a, str>>,
     // #[serde(skip_serializing_if="Option::is_none")]
     // bot: Option<bool>,
     // #[serde(skip_serializing_if="Option::is_none")]
@@ -103,6 +104,59 @@ pub struct User<'a> {
     // premium_type: Option<i32>,
 }
 
+#[derive(Deserialize)]
+pub struct PollAnswerVoters<'a> {
+    pub users: Vec<User<'a>>,
+}
+#[derive(Deserialize)]
+pub struct PollVote<'a> {
+    pub user_id: Cow<'a, str>,
+    pub channel_id: Cow<'a, str>,
+    pub message_id: Cow<'a, str>,
+    pub guild_id: Option<Cow<'a, str>>,
+    pub answer_id: u32,
+}
+#[derive(Deserialize)]
+pub struct Reaction<'a> {
+    pub user_id: Cow<'a, str>,
+    pub channel_id: Cow<'a, str>,
+    pub message_id: Cow<'a, str>,
+    pub guild_id: Option<Cow<'a, str>>,
+    pub emoji: Emoji<'a>,
+}
+#[derive(Deserialize)]
+pub struct Emoji<'a> {
+    // Only custom emoji have an id, and the name can be missing if the
+    // custom emoji has been deleted
+    pub id: Option<Cow<'a, str>>,
+    pub name: Option<Cow<'a, str>>,
+}
+
+#[derive(Serialize)]
+pub struct RequestGuildMembers<'a> {
+    pub guild_id: &'a str,
+    pub query: &'a str,
+    pub limit: u32,
+}
+#[derive(Deserialize)]
+pub struct GuildCreate<'a> {
+    pub id: Cow<'a, str>,
+    pub large: Option<bool>,
+    pub unavailable: Option<bool>,
+    pub member_count: Option<u64>,
+}
+#[derive(Deserialize)]
+pub struct GuildMember<'a> {
+    pub user: User<'a>,
+}
+#[derive(Deserialize)]
+pub struct GuildMembersChunk<'a> {
+    pub guild_id: Cow<'a, str>,
+    pub members: Vec<GuildMember<'a>>,
+    pub chunk_index: u32,
+    pub chunk_count: u32,
+}
+
 #[derive(Serialize)]
 pub struct Resume<'a> {
     pub token: Cow<'a, str>,
@@ -115,24 +169,126 @@ pub struct MessageReceived<'a> {
     pub id: Cow<'a, str>,
     pub channel_id: Cow<'a, str>,
     pub guild_id: Option<Cow<'a, str>>,
-    pub content: Cow<'a, str>,
+    // Message updates can be partial, in which case these are missing
+    #[serde(default)]
+    pub content: Option<Cow<'a, str>>,
+    #[serde(default)]
     pub mentions: Vec<User<'a>>,
-    pub author: User<'a>,
+    #[serde(default)]
+    pub mention_everyone: bool,
+    #[serde(default)]
+    pub author: Option<User<'a>>,
+    pub webhook_id: Option<Cow<'a, str>>,
+    #[serde(default)]
+    pub timestamp: Option<Cow<'a, str>>,
+    #[serde(rename="type", default)]
+    pub kind: u8,
+    #[serde(default)]
+    pub attachments: Vec<IgnoredAny>,
+    #[serde(default)]
+    pub message_reference: Option<IgnoredAny>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct BotGatewaySessionStartLimit {
-    pub total: u64,
     pub remaining: u64,
     pub reset_after: u64
 }
+// The body of a 429 response
+#[derive(Debug, Deserialize)]
+pub struct RateLimited {
+    pub retry_after: f64,
+    #[serde(default)]
+    pub global: bool,
+}
 #[derive(Debug, Deserialize)]
 pub struct BotGatewayResponse<'a> {
     pub url: &'a str,
-    pub shards: i32,
     pub session_start_limit: BotGatewaySessionStartLimit
 }
-#[derive(Debug, Serialize)]
+#[derive(Debug, Default, Serialize)]
 pub struct CreateMessageRequest<'a> {
-    pub content: &'a str,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub content: Option<&'a str>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub poll: Option<&'a Poll>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub embeds: Option<&'a [Embed]>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub message_reference: Option<MessageReference<'a>>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub allowed_mentions: Option<AllowedMentions>,
+}
+#[derive(Debug, Serialize)]
+pub struct MessageReference<'a> {
+    pub message_id: &'a str,
+    pub channel_id: &'a str,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub guild_id: Option<&'a str>,
+}
+#[derive(Debug, Serialize)]
+pub struct AllowedMentions {
+    pub parse: &'static [&'static str],
+    pub replied_user: bool,
+}
+impl AllowedMentions {
+    // Nothing in the message pings anyone, mentions still show up as links
+    pub const NONE: Self = Self { parse: &[], replied_user: false };
+}
+#[derive(Debug, Serialize)]
+pub struct EditMessageRequest<'a> {
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub content: Option<&'a str>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub embeds: Option<&'a [Embed]>,
+}
+#[derive(Debug, Serialize)]
+pub struct BulkDeleteRequest<'a> {
+    pub messages: &'a [&'a str],
+}
+#[derive(Debug, Serialize)]
+pub struct ModifyCurrentUserRequest<'a> {
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub username: Option<&'a str>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub avatar: Option<String>,
+}
+#[derive(Debug, Serialize)]
+pub struct CreateInviteRequest {
+    pub max_age: u32,
+    pub max_uses: u32,
+    pub temporary: bool,
+}
+#[derive(Debug, Serialize)]
+pub struct CreateDmRequest<'a> {
+    pub recipient_id: &'a str,
+}
+#[derive(Debug, Serialize)]
+pub struct StartThreadRequest<'a> {
+    pub name: &'a str,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub auto_archive_duration: Option<u32>,
+}
+#[derive(Debug, Deserialize)]
+pub struct Channel<'a> {
+    pub id: Cow<'a, str>,
+    #[serde(rename="type")]
+    pub kind: u8,
+    pub guild_id: Option<Cow<'a, str>>,
+    pub name: Option<Cow<'a, str>>,
+}
+#[derive(Debug, Deserialize)]
+pub struct Guild<'a> {
+    pub id: Cow<'a, str>,
+    pub name: Cow<'a, str>,
+}
+#[derive(Debug, Deserialize)]
+pub struct ThreadMember<'a> {
+    pub user_id: Cow<'a, str>,
+    pub join_timestamp: Cow<'a, str>,
+    pub flags: u64,
+}
+#[derive(Debug, Deserialize)]
+pub struct InviteResponse<'a> {
+    pub code: Cow<'a, str>,
 }
\ No newline at end of file
@@ -0,0 +1,81 @@
+use crate::error::Error;
+use serde_derive::Serialize;
+
+// A poll to send with `Discord::send_poll`:
+//
+//     Poll::new("Pineapple on pizza?", 24)
+//         .answer("Yes")
+//         .answer("No")
+#[derive(Clone, Debug, Serialize)]
+pub struct Poll {
+    question: PollMedia,
+    answers: Vec<PollAnswer>,
+    duration: u32,
+    allow_multiselect: bool,
+}
+impl Poll {
+    pub const MIN_ANSWERS: usize = 1;
+    pub const MAX_ANSWERS: usize = 10;
+    // Polls can last at most 32 days
+    pub const MAX_DURATION_HOURS: u32 = 32 * 24;
+
+    // duration is how many hours the poll is open for
+    pub fn new<T: Into<String>>(question: T, duration: u32) -> Self {
+        Self {
+            question: PollMedia { text: question.into() },
+            answers: Vec::new(),
+            duration,
+            allow_multiselect: false,
+        }
+    }
+    pub fn answer<T: Into<String>>(mut self, text: T) -> Self {
+        self.answers.push(PollAnswer {
+            poll_media: PollMedia { text: text.into() },
+        });
+        self
+    }
+    pub fn allow_multiselect(mut self, allow_multiselect: bool) -> Self {
+        self.allow_multiselect = allow_multiselect;
+        self
+    }
+
+    // Check the poll against the limits Discord puts on polls, so we can
+    // give a useful error rather than a generic failed request
+    pub(super) fn validate(&self) -> Result<(), Error> {
+        if self.answers.len() < Self::MIN_ANSWERS || self.answers.len() > Self::MAX_ANSWERS {
+            Err(Error::InvalidArgument(format!("a poll must have between {} and {} answers", Self::MIN_ANSWERS, Self::MAX_ANSWERS).into()))
+        } else if self.duration == 0 || self.duration > Self::MAX_DURATION_HOURS {
+            Err(Error::InvalidArgument(format!("a poll must last between 1 and {} hours", Self::MAX_DURATION_HOURS).into()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct PollMedia {
+    text: String,
+}
+#[derive(Clone, Debug, Serialize)]
+struct PollAnswer {
+    poll_media: PollMedia,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invalid_argument(poll: &Poll) -> String {
+        match poll.validate() {
+            Err(Error::InvalidArgument(message)) => message.into_owned(),
+            res => panic!("expected an invalid argument, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn validate_limits() {
+        assert!(Poll::new("?", 1).answer("a").validate().is_ok());
+        assert_eq!(invalid_argument(&Poll::new("?", 1)), "a poll must have between 1 and 10 answers");
+        assert_eq!(invalid_argument(&Poll::new("?", Poll::MAX_DURATION_HOURS + 1).answer("a")), "a poll must last between 1 and 768 hours");
+    }
+}
@@ -0,0 +1,65 @@
+use serde_derive::Serialize;
+
+// What the bot is shown as doing under its name, e.g. "Playing X"
+#[derive(Clone, Debug, Serialize)]
+pub struct Activity {
+    name: String,
+    #[serde(rename="type")]
+    kind: u8,
+    #[serde(skip_serializing_if="Option::is_none")]
+    url: Option<String>,
+}
+impl Activity {
+    pub fn playing<T: Into<String>>(name: T) -> Self {
+        Self::new(name, 0)
+    }
+    // Only twitch.tv and youtube.com urls are shown as streams
+    pub fn streaming<T: Into<String>, U: Into<String>>(name: T, url: U) -> Self {
+        Self {
+            url: Some(url.into()),
+            ..Self::new(name, 1)
+        }
+    }
+    pub fn listening<T: Into<String>>(name: T) -> Self {
+        Self::new(name, 2)
+    }
+    pub fn watching<T: Into<String>>(name: T) -> Self {
+        Self::new(name, 3)
+    }
+    pub fn competing<T: Into<String>>(name: T) -> Self {
+        Self::new(name, 5)
+    }
+
+    fn new<T: Into<String>>(name: T, kind: u8) -> Self {
+        Self {
+            name: name.into(),
+            kind,
+            url: None,
+        }
+    }
+}
+
+// The presence the bot identifies with, and the one to go back to if it has
+// to identify again
+#[derive(Clone, Debug)]
+pub(super) struct Presence {
+    pub status: String,
+    pub activity: Option<Activity>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize_activity() {
+        assert_eq!(
+            serde_json::to_value(Activity::playing("chess")).unwrap(),
+            serde_json::json!({ "name": "chess", "type": 0 })
+        );
+        assert_eq!(
+            serde_json::to_value(Activity::streaming("chess", "https://twitch.tv/chess")).unwrap(),
+            serde_json::json!({ "name": "chess", "type": 1, "url": "https://twitch.tv/chess" })
+        );
+    }
+}
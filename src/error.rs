@@ -1,9 +1,13 @@
+use serde_derive::Deserialize;
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Connection failure")]
     Hyper(#[from] hyper::Error),
     #[error("Connection TLS failure")]
-    Tls(#[from] native_tls::Error),
+    Tls(#[from] crate::tls::TlsError),
+    #[error("Timed out connecting")]
+    ConnectTimeout,
     #[error("Http failure")]
     Http(#[from] http::Error),
     #[error("Tokio I/O failure")]
@@ -13,7 +17,7 @@ pub enum Error {
     #[error("Randomness failure")]
     Rand(#[from] rand::Error),
     #[error("Invalid Websocket Handshake Response")]
-    Handshake(hyper::Response<hyper::Body>),
+    Handshake(Box<hyper::Response<hyper::Body>>),
     #[error("Websocket Error")]
     WebSocket(#[from] crate::ws::message::Error),
     #[error("An Unknown Error happened")]
@@ -26,4 +30,83 @@ pub enum Error {
     NoAck,
     #[error("A channel was closed when it shouldn't have been")]
     SendChannelClosed,
+    #[error("Message is {0} characters long, the maximum is {}", crate::discord::MAX_MESSAGE_LENGTH)]
+    MessageTooLong(usize),
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(std::borrow::Cow<'static, str>),
+    #[error("Unknown intent: {0}")]
+    UnknownIntent(String),
+    // Discord only allows so many identifies a day, once they're used up we
+    // have to wait until the limit resets before we can connect
+    #[error("Session start limit reached, it resets in {reset_after:?}")]
+    SessionStartLimit { reset_after: std::time::Duration },
+    // Discord responded with 429 Too Many Requests. A global rate limit
+    // applies to every request the bot makes, not just the one that failed
+    #[error("Rate limited, retry after {retry_after:?}")]
+    RateLimited { retry_after: std::time::Duration, global: bool },
+    // The gateway closed the connection with a code that means reconnecting
+    // won't help, see `discord::is_fatal_close_code`
+    #[error("Gateway closed with code {code}: {reason}")]
+    Gateway { code: u16, reason: String },
+    // Sending one part of a message split up by
+    // `Discord::send_message_chunked` failed, after sent parts had already
+    // been sent
+    #[error("Failed to send a message after sending {sent} parts of it")]
+    PartiallySent { sent: usize, source: Box<Error> },
+}
+
+impl Error {
+    // The error Discord gave back for a failed API request, e.g. so callers
+    // can tell Missing Permissions (50013) apart from other errors. None if
+    // this isn't an API error or Discord didn't say what went wrong
+    pub fn api_error(&self) -> Option<ApiError> {
+        match self {
+            Error::BadApiRequest(body) => serde_json::from_slice(body).ok(),
+            Error::PartiallySent { source, .. } => source.api_error(),
+            _ => None,
+        }
+    }
+}
+
+// The body Discord sends back with most failed API requests, see
+// https://discord.com/developers/docs/topics/opcodes-and-status-codes#json
+#[derive(Debug, Deserialize)]
+pub struct ApiError {
+    code: u32,
+    message: String,
+    #[serde(default)]
+    errors: Option<serde_json::Value>,
+}
+impl ApiError {
+    pub fn code(&self) -> u32 {
+        self.code
+    }
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+    // Which fields of the request were wrong and why, for requests Discord
+    // couldn't make sense of
+    pub fn errors(&self) -> Option<&serde_json::Value> {
+        self.errors.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_api_error() {
+        let body = br#"{"code": 50035, "message": "Invalid Form Body", "errors": {"content": {}}}"#;
+        let error = Error::BadApiRequest(bytes::Bytes::from_static(body));
+        let api_error = error.api_error().unwrap();
+        assert_eq!((api_error.code(), api_error.message()), (50035, "Invalid Form Body"));
+        assert_eq!(api_error.errors(), Some(&serde_json::json!({ "content": {} })));
+
+        let partial = Error::PartiallySent { sent: 1, source: Box::new(error) };
+        assert_eq!(partial.api_error().unwrap().code(), 50035);
+
+        assert!(Error::BadApiRequest(bytes::Bytes::from_static(b"<html>")).api_error().is_none());
+        assert!(Error::NoAck.api_error().is_none());
+    }
 }
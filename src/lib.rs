@@ -1,5 +1,5 @@
 #![recursion_limit="1024"]
-#![feature(hash_set_entry, maybe_uninit_slice, try_blocks)]
+#![feature(try_blocks)]
 
 pub mod chain;
 pub mod discord;
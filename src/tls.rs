@@ -18,16 +18,34 @@ use std::{
         Context,
         Poll,
     },
+    time::Duration,
 };
 use tokio::io::{
     AsyncRead,
     AsyncWrite,
     ReadBuf,
 };
+#[cfg(not(feature = "rustls"))]
 use tokio_native_tls::{
     self,
     TlsConnector,
 };
+#[cfg(feature = "rustls")]
+use tokio_rustls::{
+    self,
+    rustls,
+    TlsConnector,
+};
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("either the native-tls or the rustls feature needs to be enabled");
+
+// The error given by whichever TLS backend is being used. rustls is used over
+// native-tls if both features are enabled
+#[cfg(not(feature = "rustls"))]
+pub type TlsError = native_tls::Error;
+#[cfg(feature = "rustls")]
+pub type TlsError = rustls::Error;
 
 
 // This shouldn't be necessary because hyper-tls is already a thing, but
@@ -38,39 +56,88 @@ use tokio_native_tls::{
 // will mean that we'll just get an error. If we just don't use the flag, we'll
 // just be given a regular Http stream, but our traffic is https, so had to
 // create my own TlsStream and HttpsConnector.
+//
+// Plain http and ws urls are only allowed through without TLS when asked for
+// with `allow_plaintext`, which is for pointing the client at something local
+// like a mock server in tests. Otherwise they're refused, so the token is
+// never sent unencrypted because of a bad url.
+#[derive(Debug)]
+pub struct TlsStream<T>(MaybeTls<T>);
 #[derive(Debug)]
-pub struct TlsStream<T>(tokio_native_tls::TlsStream<T>);
+enum MaybeTls<T> {
+    #[cfg(not(feature = "rustls"))]
+    Tls(tokio_native_tls::TlsStream<T>),
+    // rustls keeps its buffers inline, which makes it a lot bigger than the
+    // plain stream
+    #[cfg(feature = "rustls")]
+    Tls(Box<tokio_rustls::client::TlsStream<T>>),
+    Plain(T),
+}
 impl<T: AsyncRead + AsyncWrite + Connection + Unpin> Connection for TlsStream<T> {
+    #[cfg(not(feature = "rustls"))]
     fn connected(&self) -> Connected {
-        self.0.get_ref().get_ref().get_ref().connected()
+        match self.0 {
+            MaybeTls::Tls(ref tls) => tls.get_ref().get_ref().get_ref().connected(),
+            MaybeTls::Plain(ref plain) => plain.connected(),
+        }
+    }
+    #[cfg(feature = "rustls")]
+    fn connected(&self) -> Connected {
+        match self.0 {
+            MaybeTls::Tls(ref tls) => tls.get_ref().0.connected(),
+            MaybeTls::Plain(ref plain) => plain.connected(),
+        }
     }
 }
 impl<T: AsyncRead + AsyncWrite + Unpin> AsyncRead for TlsStream<T> {
     #[inline]
     fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<Result<(), std::io::Error>> {
-        Pin::new(&mut self.get_mut().0).poll_read(cx, buf)
+        match self.get_mut().0 {
+            MaybeTls::Tls(ref mut tls) => Pin::new(tls).poll_read(cx, buf),
+            MaybeTls::Plain(ref mut plain) => Pin::new(plain).poll_read(cx, buf),
+        }
     }
 }
 
 impl<T: AsyncWrite + AsyncRead + Unpin> AsyncWrite for TlsStream<T> {
     #[inline]
     fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, std::io::Error>> {
-        Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+        match self.get_mut().0 {
+            MaybeTls::Tls(ref mut tls) => Pin::new(tls).poll_write(cx, buf),
+            MaybeTls::Plain(ref mut plain) => Pin::new(plain).poll_write(cx, buf),
+        }
     }
 
     #[inline]
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
-        Pin::new(&mut self.get_mut().0).poll_flush(cx)
+        match self.get_mut().0 {
+            MaybeTls::Tls(ref mut tls) => Pin::new(tls).poll_flush(cx),
+            MaybeTls::Plain(ref mut plain) => Pin::new(plain).poll_flush(cx),
+        }
     }
 
     #[inline]
     fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
-        Pin::new(&mut self.get_mut().0).poll_shutdown(cx)
+        match self.get_mut().0 {
+            MaybeTls::Tls(ref mut tls) => Pin::new(tls).poll_shutdown(cx),
+            MaybeTls::Plain(ref mut plain) => Pin::new(plain).poll_shutdown(cx),
+        }
     }
 
     #[inline]
     fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<Result<usize, std::io::Error>> {
-        Pin::new(&mut self.get_mut().0).poll_write_vectored(cx, bufs)
+        match self.get_mut().0 {
+            MaybeTls::Tls(ref mut tls) => Pin::new(tls).poll_write_vectored(cx, bufs),
+            MaybeTls::Plain(ref mut plain) => Pin::new(plain).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    #[inline]
+    fn is_write_vectored(&self) -> bool {
+        match self.0 {
+            MaybeTls::Tls(ref tls) => tls.is_write_vectored(),
+            MaybeTls::Plain(ref plain) => plain.is_write_vectored(),
+        }
     }
 }
 
@@ -78,18 +145,68 @@ impl<T: AsyncWrite + AsyncRead + Unpin> AsyncWrite for TlsStream<T> {
 pub struct HttpsConnector<T> {
     http: T,
     tls: TlsConnector,
+    connect_timeout: Duration,
+    allow_plaintext: bool,
+}
+
+impl<T> HttpsConnector<T> {
+    pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+    // How long connecting and the TLS handshake can take altogether before
+    // giving up with `Error::ConnectTimeout`
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+    // Whether to connect to http and ws urls without TLS rather than failing
+    // with `Error::InvalidArgument`. This is turned on by
+    // `Discord::connect_bot_with_tls` when `ConnectOptions::base_url` is an
+    // http url, and off otherwise
+    pub fn allow_plaintext(mut self, allow: bool) -> Self {
+        self.allow_plaintext = allow;
+        self
+    }
 }
 
 impl HttpsConnector<HttpConnector> {
-    pub fn new() -> Result<Self, native_tls::Error> {
-        native_tls::TlsConnector::new().map(|tls| HttpsConnector::new_(TlsConnector::from(tls)))
+    #[cfg(not(feature = "rustls"))]
+    pub fn new() -> Result<Self, TlsError> {
+        native_tls::TlsConnector::new().map(HttpsConnector::with_connector)
+    }
+    // Use a connector set up with e.g. extra root certificates, for when
+    // connecting through a proxy which uses its own certificates
+    #[cfg(not(feature = "rustls"))]
+    pub fn with_connector(tls: native_tls::TlsConnector) -> Self {
+        HttpsConnector::new_(TlsConnector::from(tls))
+    }
+    // rustls can't fail to set up, but this still gives a Result so the two
+    // backends can be swapped without changing any callers
+    #[cfg(feature = "rustls")]
+    pub fn new() -> Result<Self, TlsError> {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+            rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)
+        }));
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(HttpsConnector::with_connector(std::sync::Arc::new(config)))
+    }
+    // Use a config set up with e.g. extra root certificates, for when
+    // connecting through a proxy which uses its own certificates
+    #[cfg(feature = "rustls")]
+    pub fn with_connector(tls: std::sync::Arc<rustls::ClientConfig>) -> Self {
+        HttpsConnector::new_(TlsConnector::from(tls))
     }
     fn new_(tls: TlsConnector) -> Self {
         let mut http = HttpConnector::new();
         http.enforce_http(false);
         HttpsConnector {
             http,
-            tls
+            tls,
+            connect_timeout: Self::DEFAULT_CONNECT_TIMEOUT,
+            allow_plaintext: false,
         }
     }
 }
@@ -112,6 +229,23 @@ impl<T> Service<hyper::Uri> for HttpsConnector<T>
         }
     }
     fn call(&mut self, dst: hyper::Uri) -> Self::Future {
+        let connect_timeout = self.connect_timeout;
+        if matches!(dst.scheme_str(), Some("http") | Some("ws")) {
+            if !self.allow_plaintext {
+                return HttpsConnecting(Box::pin(async {
+                    Err(Error::InvalidArgument("plaintext urls aren't allowed unless the base url is one".into()))
+                }));
+            }
+            let connecting = self.http.call(dst);
+            return HttpsConnecting(Box::pin(async move {
+                match tokio::time::timeout(connect_timeout, connecting).await {
+                    Ok(Ok(plain)) => Ok(TlsStream(MaybeTls::Plain(plain))),
+                    Ok(Err(e)) => Err(<Error as From<_>>::from(e.into())),
+                    Err(_) => Err(Error::ConnectTimeout),
+                }
+            }));
+        }
+
         // This is a little annoying, there doesn't appear to be a way to easily
         // just change the port of a Uri. This is an issue because, the
         // underlying HttpConnector will just look at the scheme to determine
@@ -139,17 +273,42 @@ impl<T> Service<hyper::Uri> for HttpsConnector<T>
             match values {
                 Ok((host, connecting, tls)) => {
                     match connecting.await {
-                        Ok(tcp) => tls.connect(&host, tcp).await.map(TlsStream).map_err(Into::into),
+                        Ok(tcp) => connect_tls(&tls, &host, tcp).await,
                         Err(e) => Err(<Error as From<_>>::from(e.into())),
                     }
                 },
                 Err(e) => Err(<Error as From<http::Error>>::from(e)),
             }
         };
+        // Without a timeout an unreachable host would leave us waiting here
+        // forever rather than failing and letting the bot try again
+        let fut = async move {
+            tokio::time::timeout(connect_timeout, fut).await.unwrap_or(Err(Error::ConnectTimeout))
+        };
         HttpsConnecting(Box::pin(fut))
     }
 }
 
+#[cfg(not(feature = "rustls"))]
+async fn connect_tls<T: AsyncRead + AsyncWrite + Unpin>(tls: &TlsConnector, host: &str, tcp: T) -> Result<TlsStream<T>, Error> {
+    tls.connect(host, tcp).await.map(|tls| TlsStream(MaybeTls::Tls(tls))).map_err(Into::into)
+}
+#[cfg(feature = "rustls")]
+async fn connect_tls<T: AsyncRead + AsyncWrite + Unpin>(tls: &TlsConnector, host: &str, tcp: T) -> Result<TlsStream<T>, Error> {
+    use std::convert::TryFrom;
+
+    let server_name = rustls::ServerName::try_from(host)
+        .map_err(|_| Error::InvalidArgument("the host isn't a valid TLS server name".into()))?;
+    // tokio-rustls gives handshake failures back as io errors wrapping the
+    // rustls error, so unwrap them again to match what native-tls gives
+    tls.connect(server_name, tcp).await.map(|tls| TlsStream(MaybeTls::Tls(Box::new(tls)))).map_err(|e| {
+        match e.get_ref().and_then(|inner| inner.downcast_ref::<TlsError>()) {
+            Some(tls_error) => Error::Tls(tls_error.clone()),
+            None => Error::from(e),
+        }
+    })
+}
+
 type BoxedFut<T> =
     Pin<Box<dyn Future<Output = Result<TlsStream<T>, Error>> + Send>>;
 
@@ -169,3 +328,58 @@ impl<T> fmt::Debug for HttpsConnecting<T> {
         f.pad("HttpsConnecting")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A connector which never manages to connect
+    #[derive(Clone)]
+    struct Unreachable;
+    impl Service<hyper::Uri> for Unreachable {
+        type Response = tokio::io::DuplexStream;
+        type Future = futures::future::Pending<Result<Self::Response, std::io::Error>>;
+        type Error = std::io::Error;
+
+        fn poll_ready(&mut self, _cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+        fn call(&mut self, _dst: hyper::Uri) -> Self::Future {
+            futures::future::pending()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connect_times_out() {
+        let mut connector = HttpsConnector {
+            http: Unreachable,
+            tls: HttpsConnector::new().unwrap().tls,
+            connect_timeout: HttpsConnector::<Unreachable>::DEFAULT_CONNECT_TIMEOUT,
+            allow_plaintext: false,
+        }.connect_timeout(Duration::from_secs(5));
+
+        let start = tokio::time::Instant::now();
+        let result = connector.call(hyper::Uri::from_static("wss://gateway.discord.gg")).await;
+        assert!(matches!(result, Err(Error::ConnectTimeout)));
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn plaintext_only_when_allowed() {
+        let mut connector = HttpsConnector {
+            http: Unreachable,
+            tls: HttpsConnector::new().unwrap().tls,
+            connect_timeout: Duration::from_secs(5),
+            allow_plaintext: false,
+        };
+        for uri in ["http://localhost:8080/api", "ws://gateway.discord.gg"] {
+            let result = connector.call(hyper::Uri::from_static(uri)).await;
+            assert!(matches!(result, Err(Error::InvalidArgument(_))));
+        }
+
+        // Allowed through, as far as trying to connect
+        let mut connector = connector.allow_plaintext(true);
+        let result = connector.call(hyper::Uri::from_static("http://localhost:8080/api")).await;
+        assert!(matches!(result, Err(Error::ConnectTimeout)));
+    }
+}
@@ -19,6 +19,7 @@ use std::{
         FromStr,
     },
 };
+use unicase::UniCase;
 
 const MAX_REQUEST_KEY_LEN: usize = (16 / 3) * 4 + 4;
 const MAGIC_GUID_LEN: usize = 36;
@@ -26,11 +27,68 @@ const MAGIC_GUID: &[u8; MAGIC_GUID_LEN] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11
 const MAX_CONCAT_LEN: usize = MAX_REQUEST_KEY_LEN + MAGIC_GUID_LEN;
 const MAX_RESPONSE_KEY_LEN: usize = (20 / 3) * 4 + 4;
 
+mod extensions;
 mod header;
 pub mod message;
+mod prebuffered;
 
+pub use self::extensions::Extensions;
 #[doc(inline)]
-pub use self::message::Message;
+pub use self::message::{
+    Message,
+    Reader,
+};
+pub use self::prebuffered::Prebuffered;
+
+#[derive(Debug, thiserror::Error)]
+pub enum HandshakeError {
+    #[error("Request is not a websocket upgrade")]
+    NotUpgrade,
+    // The response to this should be a 426 with a Sec-WebSocket-Version
+    // header of 13, so the client knows which version to retry with
+    #[error("Unsupported websocket version")]
+    UnsupportedVersion,
+    #[error("Missing or invalid Sec-WebSocket-Key")]
+    InvalidKey,
+}
+
+// The server side of the handshake. Given the client's upgrade request this
+// checks it really is a websocket upgrade and gives back the 101 response to
+// send, after which the connection is a websocket, with messages written
+// using `message::Context::Server`
+pub fn accept_handshake<B>(request: &http::Request<B>) -> Result<http::Response<()>, HandshakeError> {
+    let headers = request.headers();
+    let header = |name| headers.get(name).and_then(|h| h.to_str().ok());
+    // Connection can have other options along with upgrade, e.g.
+    // "keep-alive, Upgrade"
+    let has_token = |name, token| header(name).is_some_and(|h: &str| {
+        h.split(',').any(|t| UniCase::new(t.trim()) == UniCase::new(token))
+    });
+
+    if request.method() != http::Method::GET
+        || !has_token(http::header::UPGRADE, "websocket")
+        || !has_token(http::header::CONNECTION, "upgrade")
+    {
+        return Err(HandshakeError::NotUpgrade);
+    }
+    if header(http::header::SEC_WEBSOCKET_VERSION).map(str::trim) != Some("13") {
+        return Err(HandshakeError::UnsupportedVersion);
+    }
+    // The key has to be 16 random bytes in base64
+    let key = header(http::header::SEC_WEBSOCKET_KEY)
+        .filter(|key| base64::decode(key.trim()).is_ok_and(|bytes| bytes.len() == 16))
+        .and_then(|key| RequestKey::from_str(key).ok())
+        .ok_or(HandshakeError::InvalidKey)?;
+
+    let accept = ResponseKey::from(key);
+    http::Response::builder()
+        .status(http::StatusCode::SWITCHING_PROTOCOLS)
+        .header(http::header::UPGRADE, "websocket")
+        .header(http::header::CONNECTION, "upgrade")
+        .header(http::header::SEC_WEBSOCKET_ACCEPT, accept.as_ref())
+        .body(())
+        .map_err(|_| HandshakeError::InvalidKey)
+}
 
 #[derive(Clone, Copy, Eq)]
 pub struct RequestKey {
@@ -158,3 +216,60 @@ impl FromStr for ResponseKey {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upgrade_request() -> http::request::Builder {
+        http::Request::get("/")
+            .header(http::header::UPGRADE, "websocket")
+            .header(http::header::CONNECTION, "keep-alive, Upgrade")
+            .header(http::header::SEC_WEBSOCKET_VERSION, "13")
+    }
+
+    #[test]
+    fn accept_handshake_example() {
+        // The example from RFC 6455
+        let request = upgrade_request()
+            .header(http::header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+            .body(())
+            .unwrap();
+        let response = accept_handshake(&request).unwrap();
+        assert_eq!(response.status(), http::StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(response.headers()[http::header::SEC_WEBSOCKET_ACCEPT], "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn accept_handshake_round_trip() {
+        let key = RequestKey::generate().unwrap();
+        let request = upgrade_request()
+            .header(http::header::SEC_WEBSOCKET_KEY, key.as_ref())
+            .body(())
+            .unwrap();
+        let response = accept_handshake(&request).unwrap();
+        let accept = ResponseKey::from_str(response.headers()[http::header::SEC_WEBSOCKET_ACCEPT].to_str().unwrap()).unwrap();
+        assert!(key.verify(accept));
+    }
+
+    #[test]
+    fn reject_bad_handshakes() {
+        let request = http::Request::get("/").body(()).unwrap();
+        assert!(matches!(accept_handshake(&request), Err(HandshakeError::NotUpgrade)));
+
+        let request = http::Request::get("/")
+            .header(http::header::UPGRADE, "websocket")
+            .header(http::header::CONNECTION, "Upgrade")
+            .header(http::header::SEC_WEBSOCKET_VERSION, "8")
+            .header(http::header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+            .body(())
+            .unwrap();
+        assert!(matches!(accept_handshake(&request), Err(HandshakeError::UnsupportedVersion)));
+
+        let request = upgrade_request()
+            .header(http::header::SEC_WEBSOCKET_KEY, "c2hvcnQ=")
+            .body(())
+            .unwrap();
+        assert!(matches!(accept_handshake(&request), Err(HandshakeError::InvalidKey)));
+    }
+}
@@ -0,0 +1,221 @@
+use bytes::{
+    Bytes,
+    BytesMut,
+};
+#[cfg(feature = "deflate")]
+use flate2::{
+    Decompress,
+    FlushDecompress,
+    Status,
+};
+
+use super::header;
+
+// The extensions agreed on in the handshake, along with any state they need
+// to keep between messages. Without the deflate feature there aren't any
+// extensions we support, so this is always empty
+#[derive(Debug, Default)]
+pub struct Extensions {
+    #[cfg(feature = "deflate")]
+    deflate: Option<Deflate>,
+}
+impl Extensions {
+    // The value of the Sec-WebSocket-Extensions header to send in the
+    // handshake, if there are any extensions to ask for
+    pub fn offer() -> Option<&'static str> {
+        if cfg!(feature = "deflate") {
+            // We never compress what we send, so we may as well let the
+            // server know it doesn't need to keep any context for us
+            Some("permessage-deflate; client_no_context_takeover")
+        } else {
+            None
+        }
+    }
+    // The extensions the server picked from our offer, given the
+    // Sec-WebSocket-Extensions header in its response. If the server didn't
+    // pick any then we just go without them, but if it picked something we
+    // didn't offer then the handshake has failed, and this gives None
+    pub fn accept(response: Option<&str>) -> Option<Self> {
+        let response = match response.map(str::trim) {
+            None | Some("") => return Some(Self::default()),
+            Some(response) => response,
+        };
+
+        #[cfg(feature = "deflate")]
+        {
+            Deflate::accept(response).map(|deflate| Self { deflate: Some(deflate) })
+        }
+        #[cfg(not(feature = "deflate"))]
+        {
+            let _ = response;
+            None
+        }
+    }
+
+    // Turn a message's payload back into its actual data, given whether the
+    // first frame of the message had RSV1 set. The data can be at most
+    // max_len bytes long
+    pub(super) fn decode(&mut self, rsv1: bool, payload: BytesMut, max_len: usize) -> Result<Bytes, header::Error> {
+        if !rsv1 {
+            return Ok(payload.freeze());
+        }
+        #[cfg(feature = "deflate")]
+        {
+            match self.deflate {
+                Some(ref mut deflate) => deflate.inflate(payload, max_len),
+                None => Err(header::Error::InvalidDataFrame),
+            }
+        }
+        #[cfg(not(feature = "deflate"))]
+        {
+            let _ = max_len;
+            Err(header::Error::InvalidDataFrame)
+        }
+    }
+}
+
+// permessage-deflate from RFC 7692, but only for messages we receive
+#[cfg(feature = "deflate")]
+#[derive(Debug)]
+struct Deflate {
+    decompress: Decompress,
+    // Whether the server starts every message with a fresh window, rather
+    // than referring back to earlier messages
+    server_no_context_takeover: bool,
+}
+#[cfg(feature = "deflate")]
+impl Deflate {
+    fn accept(response: &str) -> Option<Self> {
+        let mut params = response.split(';').map(str::trim);
+        if params.next() != Some("permessage-deflate") {
+            return None;
+        }
+
+        let mut server_no_context_takeover = false;
+        for param in params {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None),
+            };
+            match (name, value) {
+                ("server_no_context_takeover", None) => server_no_context_takeover = true,
+                ("client_no_context_takeover", None) => (),
+                // A smaller window than the maximum still inflates fine with
+                // the maximum window, so the sizes only need checking
+                ("server_max_window_bits", Some(bits))
+                | ("client_max_window_bits", Some(bits)) => match bits.parse::<u8>() {
+                    Ok(8..=15) => (),
+                    _ => return None,
+                },
+                _ => return None,
+            }
+        }
+
+        Some(Self {
+            decompress: Decompress::new(false),
+            server_no_context_takeover,
+        })
+    }
+
+    fn inflate(&mut self, mut payload: BytesMut, max_len: usize) -> Result<Bytes, header::Error> {
+        // The sender strips the empty block off the end of every message, so
+        // it has to be put back before inflating
+        payload.extend_from_slice(&[0x00, 0x00, 0xff, 0xff]);
+
+        let mut input = &payload[..];
+        let mut output = Vec::with_capacity(payload.len() * 4);
+        loop {
+            let (before_in, before_out) = (self.decompress.total_in(), self.decompress.total_out());
+            let status = self.decompress.decompress_vec(input, &mut output, FlushDecompress::Sync)
+                .map_err(|_| header::Error::InvalidCompression)?;
+            let consumed = (self.decompress.total_in() - before_in) as usize;
+            let produced = self.decompress.total_out() - before_out;
+            input = &input[consumed..];
+
+            if status == Status::StreamEnd {
+                // The server finished the deflate stream, anything after
+                // this starts a new one
+                self.decompress.reset(false);
+            }
+            if input.is_empty() && output.len() < output.capacity() {
+                break;
+            }
+            if consumed == 0 && produced == 0 && output.len() < output.capacity() {
+                return Err(header::Error::InvalidCompression);
+            }
+            // A tiny payload can inflate to something huge, so the limit has
+            // to be checked on what comes out as well as what goes in
+            if output.len() > max_len {
+                // The rest of the message never gets inflated, so whatever
+                // context the server is keeping no longer matches ours
+                self.decompress.reset(false);
+                return Err(header::Error::MessageTooLarge);
+            }
+            output.reserve(output.capacity());
+        }
+
+        if self.server_no_context_takeover {
+            self.decompress.reset(false);
+        }
+        Ok(Bytes::from(output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_extensions() {
+        assert!(Extensions::accept(None).is_some());
+        assert!(Extensions::accept(Some("")).is_some());
+        assert!(Extensions::accept(Some("x-unknown")).is_none());
+    }
+
+    #[test]
+    fn uncompressed_passes_through() {
+        let mut extensions = Extensions::default();
+        assert_eq!(extensions.decode(false, BytesMut::from(&b"abc"[..]), 3).unwrap(), &b"abc"[..]);
+        assert!(extensions.decode(true, BytesMut::from(&b"abc"[..]), 3).is_err());
+    }
+
+    #[cfg(feature = "deflate")]
+    #[test]
+    fn accept_deflate_params() {
+        assert!(Extensions::accept(Some("permessage-deflate")).unwrap().deflate.is_some());
+        let deflate = Extensions::accept(Some("permessage-deflate; server_no_context_takeover; client_max_window_bits=15"))
+            .unwrap()
+            .deflate
+            .unwrap();
+        assert!(deflate.server_no_context_takeover);
+        assert!(Extensions::accept(Some("permessage-deflate; server_max_window_bits=16")).is_none());
+        assert!(Extensions::accept(Some("permessage-deflate; unknown")).is_none());
+    }
+
+    #[cfg(feature = "deflate")]
+    #[test]
+    fn inflate_with_context() {
+        use flate2::{
+            Compress,
+            Compression,
+            FlushCompress,
+        };
+
+        // Compress the way a server would, keeping the context between
+        // messages and stripping the trailing empty block
+        let mut compress = Compress::new(Compression::default(), false);
+        let mut deflate = |data: &[u8]| {
+            let mut out = Vec::with_capacity(data.len() + 64);
+            compress.compress_vec(data, &mut out, FlushCompress::Sync).unwrap();
+            assert!(out.ends_with(&[0x00, 0x00, 0xff, 0xff]));
+            out.truncate(out.len() - 4);
+            BytesMut::from(&out[..])
+        };
+        let first = deflate(b"hello hello hello hello");
+        let second = deflate(b"hello hello hello hello");
+
+        let mut extensions = Extensions::accept(Some("permessage-deflate")).unwrap();
+        assert_eq!(extensions.decode(true, first, 100).unwrap(), &b"hello hello hello hello"[..]);
+        assert_eq!(extensions.decode(true, second, 100).unwrap(), &b"hello hello hello hello"[..]);
+    }
+}
@@ -25,6 +25,10 @@ pub enum Error {
     ReservedOpcode,
     #[error("Text field is not utf8")]
     NonUtf8Text,
+    #[error("Message is longer than the maximum allowed length")]
+    MessageTooLarge,
+    #[error("Compressed message couldn't be inflated")]
+    InvalidCompression,
     #[error("Input stream ended prematurely")]
     PrematureFinish,
     #[error("An IO Error occured")]
@@ -65,8 +69,28 @@ impl MaskingKey {
         Ok(MaskingKey { key })
     }
     pub fn apply(&self, payload: &mut [u8]) {
-        for (ct, item) in payload.iter_mut().enumerate() {
-            *item ^= self.key[ct % 4];
+        // XORing a whole u32 at a time is a lot faster than going byte by
+        // byte, so only the unaligned bytes at either end go byte by byte.
+        // Any bit pattern is a valid u32 so this is fine for any payload
+        let (head, body, tail) = unsafe { payload.align_to_mut::<u32>() };
+        self.apply_bytes(head, 0);
+
+        // The body starts head.len() bytes into the payload, so the key has
+        // to be rotated to line up with it
+        let mut key = self.key;
+        key.rotate_left(head.len() % 4);
+        let key = u32::from_ne_bytes(key);
+        for word in body.iter_mut() {
+            *word ^= key;
+        }
+
+        // The body is a whole number of words, so it doesn't change where
+        // the tail is in the key
+        self.apply_bytes(tail, head.len());
+    }
+    fn apply_bytes(&self, bytes: &mut [u8], offset: usize) {
+        for (ct, item) in bytes.iter_mut().enumerate() {
+            *item ^= self.key[(offset + ct) % 4];
         }
     }
 }
@@ -132,12 +156,12 @@ impl Header {
 
         match payload_len {
             0..=125 => (),
-            126     => bytes.extend(iter::repeat(0).take(2)),
-            127     => bytes.extend(iter::repeat(0).take(8)),
+            126     => bytes.extend(iter::repeat_n(0, 2)),
+            127     => bytes.extend(iter::repeat_n(0, 8)),
             _       => unreachable!()
         }
         if has_mask {
-            bytes.extend(iter::repeat(0).take(4));
+            bytes.extend(iter::repeat_n(0, 4));
         }
         let mut header = Header {
             is_final,
@@ -208,12 +232,12 @@ impl Header {
         bytes[1] = {
             let mut item = 0;
             if self.masking_key.is_some() { item |= 0b1000_0000; }
-            if self.payload_len > u16::max_value() as u64 { item |= 127 }
+            if self.payload_len > u16::MAX as u64 { item |= 127 }
             else if self.payload_len > 125 { item |= 126 }
             else { item |= self.payload_len as u8 }
             item
         };
-        len += if self.payload_len > u16::max_value() as u64 {
+        len += if self.payload_len > u16::MAX as u64 {
             bytes[2] = (self.payload_len >> 56 & 0xFF) as u8;
             bytes[3] = (self.payload_len >> 48 & 0xFF) as u8;
             bytes[4] = (self.payload_len >> 40 & 0xFF) as u8;
@@ -280,6 +304,33 @@ mod tests {
         assert_eq!(header, nheader)
     }
 
+    #[test]
+    fn masking_matches_byte_by_byte() {
+        use rand::{
+            rngs::StdRng,
+            Rng,
+            SeedableRng,
+        };
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..1000 {
+            let key = MaskingKey { key: rng.gen() };
+            let mut buf = [0u8; 80];
+            rng.fill(&mut buf[..]);
+
+            // Starting at different offsets into the buffer means the
+            // payload lines up differently with u32 alignment each time
+            let start = rng.gen_range(0..8);
+            let len = rng.gen_range(0..buf.len() - start);
+            let payload = &mut buf[start..start + len];
+
+            let mut expected = payload.to_vec();
+            key.apply_bytes(&mut expected, 0);
+            key.apply(payload);
+            assert_eq!(payload, &expected[..]);
+        }
+    }
+
     #[tokio::test]
     async fn test2() {
         let input = b"\x81\xfe\0\xeb8\xda\x018C\xf8uWS\xbfo\x1a\x02\xf8LBy\xadOB[\xadO|q\xeaOBy\xebLB_\xeaO|i\xee/`l\xbeeoy\xf4KaN\xb8nMz\x9fmW\x01\x83Qnw\xaed]I\xed,i\x08\xe3mA\0\xf8-\x1aH\xa8nH]\xa8uQ]\xa9#\x02C\xf8%WK\xf8;\x1aT\xb3oM@\xf8-\x1a\x1c\xb8sWO\xa9dJ\x1a\xe0#LW\xb1hW\x1a\xf6#\x1c\\\xbfwQ[\xbf#\x02\x1a\xa9dJN\xbfs\x1aE\xf6#[W\xb7qJ]\xa9r\x1a\x02\xbc`TK\xbf-\x1aT\xbbs_]\x85uPJ\xbfrPW\xb6e\x1a\x02\xb4tTT\xf6#KP\xbbs\\\x1a\xe0oMT\xb6-\x1aH\xa8dK]\xb4b]\x1a\xe0oMT\xb6-\x1a_\xafhT\\\x85rMZ\xa9bJQ\xaauQW\xb4r\x1a\x02\xbc`TK\xbf|";
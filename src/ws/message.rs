@@ -2,11 +2,22 @@ use bytes::{
     Bytes,
     BytesMut,
 };
+use futures::{
+    future::BoxFuture,
+    FutureExt,
+    Stream,
+};
 use smallvec::SmallVec;
 use std::{
     io,
     marker::Unpin,
-    str
+    mem,
+    pin::Pin,
+    str,
+    task::{
+        Context as TaskContext,
+        Poll,
+    },
 };
 use tokio::io::{
     AsyncRead,
@@ -15,11 +26,14 @@ use tokio::io::{
     AsyncWriteExt,
 };
 
-use super::header::{
-    self,
-    Header,
-    Kind as HeaderKind,
-    MaskingKey
+use super::{
+    header::{
+        self,
+        Header,
+        Kind as HeaderKind,
+        MaskingKey
+    },
+    Extensions,
 };
 
 #[derive(Debug, thiserror::Error)]
@@ -28,6 +42,20 @@ pub struct Error {
     #[from]
     kind: header::Error
 }
+impl Error {
+    // Whether this error came from the underlying connection being reset or
+    // closed mid-read, rather than from the peer sending something invalid
+    pub fn is_connection_reset(&self) -> bool {
+        match self.kind {
+            header::Error::Io(ref e) => matches!(e.kind(),
+                io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::UnexpectedEof),
+            header::Error::PrematureFinish => true,
+            _ => false,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Owned {
@@ -42,6 +70,12 @@ impl Owned {
                 Err(_) => return Err(header::Error::NonUtf8Text.into())
             },
             HeaderKind::Close => {
+                // A close frame's body is either empty, or a 2 byte close
+                // code followed by an optional UTF-8 reason, so a single byte
+                // body can't be valid
+                if data.len() == 1 {
+                    return Err(header::Error::InvalidDataFrame.into());
+                }
                 if data.len() > 2 {
                     match str::from_utf8(&data[2..]) {
                         Ok(_) => (),
@@ -55,55 +89,16 @@ impl Owned {
 
         Ok(Self { kind, data, })
     }
+    // Read a single message. Any fragments of a message which come after a
+    // control frame sent in the middle of it are lost, so a connection should
+    // be read through a `Reader` instead
     pub async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self, Error> {
-        let mut header = Header::read(reader).await?;
-        let message_kind = header.kind;
-
-        let mut payload = BytesMut::with_capacity(0);
-        loop {
-            payload.reserve(header.payload_len as usize);
-
-            let start = payload.len();
-            let mut remaining = header.payload_len as usize;
-            while remaining > 0 {
-                let read = reader.read_buf(&mut payload).await.map_err(header::Error::Io)?;
-                if read == 0 {
-                    Err(header::Error::PrematureFinish)?;
-                }
-                remaining -= read;
-            }
-
-            if let Some(ref key) = header.masking_key {
-                key.apply(&mut payload[start..]);
-            }
-
-            match header.kind {
-                HeaderKind::Continuation => if header.is_final {
-                    break;
-                }
-                HeaderKind::Binary | HeaderKind::Text => {
-                    if payload.len() != header.payload_len as usize {
-                        return Err(header::Error::InvalidDataFrame.into())
-                    } else if header.is_final {
-                        break;
-                    } else {
-                        header = Header::read(reader).await?;
-                    }
-                }
-                HeaderKind::Close | HeaderKind::Ping | HeaderKind::Pong => {
-                    if !header.is_final || payload.len() != header.payload_len as usize {
-                        return Err(header::Error::InvalidDataFrame.into())
-                    }
-                    break;
-                }
-            }
-        }
-        Self::new(message_kind, payload.freeze())
+        Reader::new(reader).read().await
     }
     pub fn buf(&self) -> &Bytes {
         &self.data
     }
-    pub fn message(&self) -> Message {
+    pub fn message(&self) -> Message<'_> {
         match self.kind {
             header::Kind::Continuation => unreachable!(),
             header::Kind::Text => unsafe {
@@ -111,7 +106,8 @@ impl Owned {
             },
             header::Kind::Binary => Message::Binary(&self.data),
             header::Kind::Close => {
-                if self.data.len() < 2 {
+                // Owned::new has already rejected single byte bodies
+                if self.data.is_empty() {
                     Message::Close(None)
                 } else {
                     let code = ((self.data[0] as u16) << 8) | self.data[1] as u16;
@@ -130,6 +126,229 @@ impl Owned {
     }
 }
 
+// Reads messages from one connection, keeping track of the extensions agreed
+// on for it and of any fragmented message that's only partly been read
+#[derive(Debug)]
+pub struct Reader<R> {
+    inner: R,
+    extensions: Extensions,
+    partial: Option<Partial>,
+    max_message_len: usize,
+}
+// A fragmented message which still has fragments to come
+#[derive(Debug)]
+struct Partial {
+    kind: HeaderKind,
+    compressed: bool,
+    payload: BytesMut,
+}
+impl<R: AsyncRead + Unpin> Reader<R> {
+    pub const DEFAULT_MAX_MESSAGE_LEN: usize = 8 * 1024 * 1024;
+
+    pub fn new(inner: R) -> Self {
+        Self::with_extensions(inner, Extensions::default())
+    }
+    pub fn with_extensions(inner: R, extensions: Extensions) -> Self {
+        Self {
+            inner,
+            extensions,
+            partial: None,
+            max_message_len: Self::DEFAULT_MAX_MESSAGE_LEN,
+        }
+    }
+    // The longest message (after reassembling fragments and inflating) we'll
+    // accept. Frame lengths come straight from the peer, so without a limit
+    // a bad one could have us allocate as much memory as it likes
+    pub fn max_message_len(mut self, max_message_len: usize) -> Self {
+        self.max_message_len = max_message_len;
+        self
+    }
+    pub async fn read(&mut self) -> Result<Owned, Error> {
+        loop {
+            let header = Header::read(&mut self.inner).await?;
+            // Only the first frame of a message says whether it's compressed,
+            // continuation and control frames never are
+            let compressed = header.extensions[0];
+            if compressed && !matches!(header.kind, HeaderKind::Text | HeaderKind::Binary) {
+                return Err(header::Error::InvalidDataFrame.into());
+            }
+
+            match header.kind {
+                // Control frames can come in between the fragments of
+                // another message, so they're handed out straight away and
+                // whatever we have of the fragmented message is kept for
+                // the next read
+                HeaderKind::Close | HeaderKind::Ping | HeaderKind::Pong => {
+                    let mut payload = BytesMut::new();
+                    read_payload(&mut self.inner, &header, &mut payload, self.max_message_len).await?;
+                    return Owned::new(header.kind, payload.freeze());
+                }
+                HeaderKind::Text | HeaderKind::Binary => {
+                    // The previous message has to be finished before the
+                    // next one starts
+                    if self.partial.is_some() {
+                        return Err(header::Error::InvalidDataFrame.into());
+                    }
+                    let mut payload = BytesMut::new();
+                    read_payload(&mut self.inner, &header, &mut payload, self.max_message_len).await?;
+                    if header.is_final {
+                        return Owned::new(header.kind, self.extensions.decode(compressed, payload, self.max_message_len)?);
+                    }
+                    self.partial = Some(Partial { kind: header.kind, compressed, payload });
+                }
+                HeaderKind::Continuation => {
+                    let mut partial = self.partial.take().ok_or(header::Error::InvalidDataFrame)?;
+                    read_payload(&mut self.inner, &header, &mut partial.payload, self.max_message_len).await?;
+                    if header.is_final {
+                        return Owned::new(partial.kind, self.extensions.decode(partial.compressed, partial.payload, self.max_message_len)?);
+                    }
+                    self.partial = Some(partial);
+                }
+            }
+        }
+    }
+}
+
+// Read one frame's payload onto the end of payload, unmasking it with the
+// frame's own key. The payload can be at most max_len bytes long altogether
+async fn read_payload<R: AsyncRead + Unpin>(reader: &mut R, header: &Header, payload: &mut BytesMut, max_len: usize) -> Result<(), Error> {
+    let start = payload.len();
+    if header.payload_len > max_len.saturating_sub(start) as u64 {
+        return Err(header::Error::MessageTooLarge.into());
+    }
+    let len = header.payload_len as usize;
+    payload.reserve(len);
+
+    // Reading through take makes sure we never read past the end of the
+    // frame into the next one's header
+    let mut frame = reader.take(header.payload_len);
+    while payload.len() - start < len {
+        let read = frame.read_buf(payload).await.map_err(header::Error::Io)?;
+        if read == 0 {
+            return Err(header::Error::PrematureFinish.into());
+        }
+    }
+
+    if let Some(ref key) = header.masking_key {
+        key.apply(&mut payload[start..]);
+    }
+    Ok(())
+}
+
+// The fragments of the next data message as they arrive, rather than
+// waiting for the whole message like `Reader` does, so large messages can be
+// handled a piece at a time. Text messages aren't checked to be UTF-8 since
+// a character can be split across fragments.
+//
+// Any control frames which come before or in between the fragments are kept
+// to be taken with `take_control_frames`, so pings can still be answered.
+// Compressed messages can't be split up like this so they're an error
+pub struct FrameStream<'a, R> {
+    state: FrameState<'a, R>,
+    started: bool,
+    len: usize,
+    max_message_len: usize,
+    control_frames: Vec<Owned>,
+}
+// Reading a frame needs the reader for a while, so the future hands it back
+// along with the frame once it's done
+type ReadingFrame<'a, R> = BoxFuture<'a, (&'a mut R, Result<(Header, BytesMut), Error>)>;
+enum FrameState<'a, R> {
+    Idle(&'a mut R),
+    Reading(ReadingFrame<'a, R>),
+    Done,
+}
+impl<'a, R: AsyncRead + Send + Unpin> FrameStream<'a, R> {
+    pub fn new(reader: &'a mut R) -> Self {
+        Self {
+            state: FrameState::Idle(reader),
+            started: false,
+            len: 0,
+            max_message_len: Reader::<R>::DEFAULT_MAX_MESSAGE_LEN,
+            control_frames: Vec::new(),
+        }
+    }
+    // The most bytes all of the fragments can add up to, see
+    // `Reader::max_message_len`
+    pub fn max_message_len(mut self, max_message_len: usize) -> Self {
+        self.max_message_len = max_message_len;
+        self
+    }
+    pub fn take_control_frames(&mut self) -> Vec<Owned> {
+        mem::take(&mut self.control_frames)
+    }
+
+    // Handle a frame we've read, giving back the fragment if it was part of
+    // the data message
+    fn frame(&mut self, header: Header, payload: BytesMut) -> Result<Option<Bytes>, Error> {
+        if header.extensions[0] {
+            return Err(header::Error::InvalidDataFrame.into());
+        }
+        match header.kind {
+            HeaderKind::Close | HeaderKind::Ping | HeaderKind::Pong => {
+                self.control_frames.push(Owned::new(header.kind, payload.freeze())?);
+                Ok(None)
+            }
+            HeaderKind::Text | HeaderKind::Binary if !self.started => {
+                self.started = true;
+                self.len += payload.len();
+                Ok(Some(payload.freeze()))
+            }
+            HeaderKind::Continuation if self.started => {
+                self.len += payload.len();
+                Ok(Some(payload.freeze()))
+            }
+            _ => Err(header::Error::InvalidDataFrame.into()),
+        }
+    }
+}
+impl<'a, R: AsyncRead + Send + Unpin> Stream for FrameStream<'a, R> {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            match mem::replace(&mut this.state, FrameState::Done) {
+                FrameState::Done => return Poll::Ready(None),
+                FrameState::Idle(reader) => {
+                    let max_len = this.max_message_len - this.len;
+                    this.state = FrameState::Reading(async move {
+                        let frame = async {
+                            let header = Header::read(reader).await?;
+                            let mut payload = BytesMut::new();
+                            read_payload(reader, &header, &mut payload, max_len).await?;
+                            Ok((header, payload))
+                        }.await;
+                        (reader, frame)
+                    }.boxed());
+                }
+                FrameState::Reading(mut reading) => match reading.poll_unpin(cx) {
+                    Poll::Pending => {
+                        this.state = FrameState::Reading(reading);
+                        return Poll::Pending;
+                    }
+                    // Once something's gone wrong the stream is out of
+                    // step with the frames, so the stream stays finished
+                    Poll::Ready((_, Err(e))) => return Poll::Ready(Some(Err(e))),
+                    Poll::Ready((reader, Ok((header, payload)))) => {
+                        let is_final = header.is_final;
+                        match this.frame(header, payload) {
+                            Ok(Some(fragment)) => {
+                                if !is_final {
+                                    this.state = FrameState::Idle(reader);
+                                }
+                                return Poll::Ready(Some(Ok(fragment)));
+                            }
+                            Ok(None) => this.state = FrameState::Idle(reader),
+                            Err(e) => return Poll::Ready(Some(Err(e))),
+                        }
+                    }
+                },
+            }
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Context {
     Client, Server
@@ -218,3 +437,124 @@ impl<'a> Message<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read_close(body: &[u8]) -> Result<Owned, Error> {
+        let mut frame = vec![0b1000_1000, body.len() as u8];
+        frame.extend_from_slice(body);
+        Owned::read(&mut &frame[..]).await
+    }
+
+    #[tokio::test]
+    async fn close_empty() {
+        let owned = read_close(b"").await.unwrap();
+        assert_eq!(owned.message(), Message::Close(None));
+    }
+
+    #[tokio::test]
+    async fn close_one_byte() {
+        assert!(read_close(b"\x03").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn close_code_only() {
+        let owned = read_close(b"\x03\xe9").await.unwrap();
+        assert_eq!(owned.message(), Message::Close(Some((1001, ""))));
+    }
+
+    #[tokio::test]
+    async fn close_code_and_reason() {
+        let owned = read_close(b"\x03\xe8a").await.unwrap();
+        assert_eq!(owned.message(), Message::Close(Some((1000, "a"))));
+    }
+
+    #[tokio::test]
+    async fn close_non_utf8_reason() {
+        assert!(read_close(b"\x03\xe8\xff").await.is_err());
+    }
+
+    // A frame as a client would send it, masked with its own key
+    fn frame(first: u8, payload: &[u8], key: [u8; 4]) -> Vec<u8> {
+        let mut frame = vec![first, 0b1000_0000 | payload.len() as u8];
+        frame.extend_from_slice(&key);
+        frame.extend(payload.iter().enumerate().map(|(idx, b)| b ^ key[idx % 4]));
+        frame
+    }
+
+    #[tokio::test]
+    async fn fragments_with_ping_between() {
+        let mut input = Vec::new();
+        input.extend(frame(0b0000_0001, b"hel", [1, 2, 3, 4]));
+        input.extend(frame(0b1000_1001, b"ping", [5, 6, 7, 8]));
+        input.extend(frame(0b0000_0000, b"lo ", [9, 10, 11, 12]));
+        input.extend(frame(0b1000_0000, b"there", [13, 14, 15, 16]));
+        input.extend(frame(0b1000_0001, b"next", [17, 18, 19, 20]));
+
+        let mut reader = Reader::new(&input[..]);
+        assert_eq!(reader.read().await.unwrap().message(), Message::Ping(b"ping"));
+        assert_eq!(reader.read().await.unwrap().message(), Message::Text("hello there"));
+        assert_eq!(reader.read().await.unwrap().message(), Message::Text("next"));
+    }
+
+    #[tokio::test]
+    async fn frame_too_large() {
+        // A frame claiming to be u64::MAX bytes long
+        let input = [0b1000_0010, 127, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        let err = Owned::read(&mut &input[..]).await.unwrap_err();
+        assert!(matches!(err.kind, header::Error::MessageTooLarge));
+    }
+
+    #[tokio::test]
+    async fn fragments_too_large() {
+        let mut input = frame(0b0000_0001, b"abc", [1, 2, 3, 4]);
+        input.extend(frame(0b1000_0000, b"def", [1, 2, 3, 4]));
+
+        let mut reader = Reader::new(&input[..]).max_message_len(6);
+        assert_eq!(reader.read().await.unwrap().message(), Message::Text("abcdef"));
+
+        let mut reader = Reader::new(&input[..]).max_message_len(5);
+        let err = reader.read().await.unwrap_err();
+        assert!(matches!(err.kind, header::Error::MessageTooLarge));
+    }
+
+    #[tokio::test]
+    async fn stream_fragments() {
+        use futures::StreamExt;
+
+        let mut input = Vec::new();
+        input.extend(frame(0b0000_0010, b"abc", [1, 2, 3, 4]));
+        input.extend(frame(0b1000_1001, b"ping", [5, 6, 7, 8]));
+        input.extend(frame(0b1000_0000, b"def", [9, 10, 11, 12]));
+        input.extend(frame(0b1000_0010, b"next", [13, 14, 15, 16]));
+
+        let mut reader = &input[..];
+        let mut frames = FrameStream::new(&mut reader);
+        assert_eq!(frames.next().await.unwrap().unwrap(), &b"abc"[..]);
+        assert_eq!(frames.next().await.unwrap().unwrap(), &b"def"[..]);
+        assert!(frames.next().await.is_none());
+
+        let control_frames = frames.take_control_frames();
+        assert_eq!(control_frames.len(), 1);
+        assert_eq!(control_frames[0].message(), Message::Ping(b"ping"));
+
+        // The stream stops at the end of the message, leaving the next one
+        drop(frames);
+        assert_eq!(Owned::read(&mut reader).await.unwrap().message(), Message::Binary(b"next"));
+    }
+
+    #[tokio::test]
+    async fn continuation_without_start() {
+        let input = frame(0b1000_0000, b"abc", [1, 2, 3, 4]);
+        assert!(Owned::read(&mut &input[..]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn new_message_before_last_finished() {
+        let mut input = frame(0b0000_0001, b"abc", [1, 2, 3, 4]);
+        input.extend(frame(0b1000_0001, b"def", [1, 2, 3, 4]));
+        assert!(Owned::read(&mut &input[..]).await.is_err());
+    }
+}
@@ -0,0 +1,105 @@
+use bytes::{
+    Buf,
+    Bytes,
+};
+use std::{
+    cmp,
+    io::{
+        self,
+        IoSlice,
+    },
+    marker::Unpin,
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+};
+use tokio::io::{
+    AsyncRead,
+    AsyncWrite,
+    ReadBuf,
+};
+
+// A stream where some of the data has already been read out of it, e.g. by
+// hyper reading past the end of the HTTP upgrade response. Reads give back
+// the already read bytes first and then carry on reading from the stream
+#[derive(Debug)]
+pub struct Prebuffered<S> {
+    prebuf: Bytes,
+    inner: S,
+}
+impl<S> Prebuffered<S> {
+    pub fn new(prebuf: Bytes, inner: S) -> Self {
+        Self { prebuf, inner }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Prebuffered<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.prebuf.is_empty() {
+            Pin::new(&mut this.inner).poll_read(cx, buf)
+        } else {
+            let len = cmp::min(this.prebuf.len(), buf.remaining());
+            buf.put_slice(&this.prebuf[..len]);
+            this.prebuf.advance(len);
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Prebuffered<S> {
+    #[inline]
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+
+    #[inline]
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    #[inline]
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ws::{
+        message::Owned,
+        Message,
+    };
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn reads_prebuf_first() {
+        let mut stream = Prebuffered::new(Bytes::from_static(b"hello "), &b"world"[..]);
+        let mut read = Vec::new();
+        stream.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn frame_split_across_prebuf() {
+        // An unmasked text frame containing "hi", with the first half of it
+        // already read out of the stream
+        let frame: &[u8] = &[0x81, 0x02, b'h', b'i'];
+        let mut stream = Prebuffered::new(Bytes::from_static(&frame[..3]), &frame[3..]);
+        let owned = Owned::read(&mut stream).await.unwrap();
+        assert_eq!(owned.message(), Message::Text("hi"));
+    }
+}